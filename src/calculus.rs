@@ -0,0 +1,355 @@
+use crate::executor::*;
+use crate::lexer::Token;
+use crate::parser::{Value, Variable};
+use std::ops::RangeInclusive;
+
+// The MATH menu functions that take an expression and a variable, like
+// nDeriv(X^2,X,3). The expression is kept unevaluated and evaluated over and
+// over with the variable temporarily bound to different values. Whatever the
+// variable held before is put back afterwards.
+
+pub struct Calculus {
+    pub token: Token,
+    pub expr: ValRef,
+    pub var: Variable,
+    pub args: Vec<ValRef>,
+}
+
+pub fn arity(token: &Token) -> Option<RangeInclusive<usize>> {
+    // how many arguments come after the expression and the variable
+    match token {
+        Token::Solve => Some(1..=2),
+        Token::NDeriv => Some(1..=2),
+        Token::FnInt => Some(2..=3),
+        Token::FMin | Token::FMax => Some(2..=3),
+        Token::Sigma => Some(2..=2),
+        _ => None,
+    }
+}
+
+// Golden section search shrinks the interval by about 0.618 each step, so
+// 200 steps is a factor of 1e-42, more than any f64 interval ever needs
+const MAX_EXTREMUM_STEPS: u32 = 200;
+
+// Σ( adds one term per step. A calculator would still be at it next week
+const MAX_SIGMA_TERMS: f64 = 1e7;
+
+struct Function<'a> {
+    expr: &'a ValRef,
+    var: &'a Variable,
+}
+
+impl<'a> Function<'a> {
+    fn at(&self, ctx: &mut Context, x: f64) -> Result<f64, ExecError> {
+        // every loop in here goes through this, so Ctrl+C and --timeout are
+        // checked here
+        ctx.check_break()?;
+        ctx.check_time()?;
+        ctx.set(self.var, Value::NumValue(x))?;
+        match self.expr.eval(ctx)? {
+            Value::NumValue(y) => Ok(y),
+            _ => Err(ExecError::TypeMismatch),
+        }
+    }
+}
+
+fn number(val: Value) -> Result<f64, ExecError> {
+    match val {
+        Value::NumValue(n) => Ok(n),
+        _ => Err(ExecError::TypeMismatch),
+    }
+}
+
+impl Eval for Calculus {
+    fn eval(&self, ctx: &mut Context) -> EvalResult {
+        let mut args: Vec<Value> = Vec::new();
+        for arg in self.args.iter() {
+            args.push(arg.eval(ctx)?);
+        }
+
+        let name = match self.var {
+            Variable::RealVar(name) => name,
+            _ => return Err(ExecError::SyntaxError),
+        };
+        let saved = ctx.reals.get(&name).cloned();
+
+        let f = Function {
+            expr: &self.expr,
+            var: &self.var,
+        };
+        let result = match self.token {
+            Token::Solve => solve(ctx, &f, args),
+            Token::NDeriv => n_deriv(ctx, &f, args),
+            Token::FnInt => fn_int(ctx, &f, args),
+            Token::FMin => f_extremum(ctx, &f, args, 1.0),
+            Token::FMax => f_extremum(ctx, &f, args, -1.0),
+            Token::Sigma => sigma(ctx, &f, args),
+            _ => Err(ExecError::NotYetImplemented),
+        };
+
+        match saved {
+            Some(val) => {
+                ctx.reals.insert(name, val);
+            }
+            None => {
+                ctx.reals.remove(&name);
+            }
+        }
+        result
+    }
+
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(
+            f,
+            "{:?}({:?}, {:?}, {:?})",
+            self.token, self.expr, self.var, self.args
+        )
+    }
+
+    fn clone_expr(&self) -> Box<dyn Eval> {
+        Box::new(Calculus {
+            token: self.token.clone(),
+            expr: self.expr.clone(),
+            var: self.var.clone(),
+            args: self.args.clone(),
+        })
+    }
+}
+
+fn solve(ctx: &mut Context, f: &Function, args: Vec<Value>) -> EvalResult {
+    // solve(expr,var,guess[,{lower,upper}]), finds a root of expr near guess
+    let mut args = args.into_iter();
+    let (guess, mut lower, mut upper) = match args.next() {
+        // a list guess is a starting interval instead of a point
+        Some(Value::ValueList(l)) if l.len() == 2 => {
+            let a = number(l[0].clone())?;
+            let b = number(l[1].clone())?;
+            ((a + b) / 2.0, -1e99, 1e99)
+        }
+        Some(val) => (number(val)?, -1e99, 1e99),
+        None => return Err(ExecError::SyntaxError),
+    };
+    if let Some(Value::ValueList(bounds)) = args.next() {
+        if bounds.len() != 2 {
+            return Err(ExecError::DimensionMismatch);
+        }
+        lower = number(bounds[0].clone())?;
+        upper = number(bounds[1].clone())?;
+    }
+    if lower >= upper || guess < lower || guess > upper {
+        return Err(ExecError::Domain);
+    }
+
+    // Secant method first, it is fast when the guess is any good
+    let mut x0 = guess;
+    let mut x1 = if guess == 0.0 {
+        1e-4
+    } else {
+        guess * (1.0 + 1e-4)
+    };
+    let mut y0 = f.at(ctx, x0)?;
+    for _ in 0..100 {
+        if y0 == 0.0 {
+            return Ok(Value::NumValue(x0));
+        }
+        let y1 = f.at(ctx, x1)?;
+        if y1 == 0.0 || (x1 - x0).abs() <= 1e-13 * x1.abs().max(1e-100) {
+            if y1.abs() < 1e-9 {
+                return Ok(Value::NumValue(x1));
+            }
+            break;
+        }
+        if y1 == y0 {
+            break;
+        }
+        let x2 = x1 - y1 * (x1 - x0) / (y1 - y0);
+        if !x2.is_finite() || x2 < lower || x2 > upper {
+            break;
+        }
+        x0 = x1;
+        y0 = y1;
+        x1 = x2;
+    }
+
+    // That didn't converge, so walk outwards from the guess looking for a sign
+    // change and bisect it
+    let y_guess = f.at(ctx, guess)?;
+    let mut step = guess.abs().max(1.0) * 1e-2;
+    while step < 1e100 {
+        for x in &[guess - step, guess + step] {
+            if *x < lower || *x > upper {
+                continue;
+            }
+            let y = f.at(ctx, *x)?;
+            if y.signum() != y_guess.signum() {
+                let (a, b) = if *x < guess { (*x, guess) } else { (guess, *x) };
+                return Ok(Value::NumValue(bisect(ctx, f, a, b)?));
+            }
+        }
+        step *= 2.0;
+    }
+
+    Err(ExecError::NoSignChange)
+}
+
+fn bisect(ctx: &mut Context, f: &Function, mut a: f64, mut b: f64) -> Result<f64, ExecError> {
+    let mut ya = f.at(ctx, a)?;
+    for _ in 0..200 {
+        let mid = (a + b) / 2.0;
+        if mid == a || mid == b {
+            break;
+        }
+        let y = f.at(ctx, mid)?;
+        if y == 0.0 {
+            return Ok(mid);
+        }
+        if y.signum() == ya.signum() {
+            a = mid;
+            ya = y;
+        } else {
+            b = mid;
+        }
+    }
+    Ok((a + b) / 2.0)
+}
+
+fn n_deriv(ctx: &mut Context, f: &Function, args: Vec<Value>) -> EvalResult {
+    // nDeriv(expr,var,value[,ε]), a symmetric difference quotient like the TI's
+    let x = number(args[0].clone())?;
+    let epsilon = match args.get(1) {
+        Some(val) => number(val.clone())?,
+        None => 1e-3,
+    };
+    if epsilon == 0.0 {
+        return Err(ExecError::Domain);
+    }
+    let ahead = f.at(ctx, x + epsilon)?;
+    let behind = f.at(ctx, x - epsilon)?;
+    Ok(Value::NumValue((ahead - behind) / (2.0 * epsilon)))
+}
+
+fn fn_int(ctx: &mut Context, f: &Function, args: Vec<Value>) -> EvalResult {
+    // fnInt(expr,var,lower,upper[,tol]), by adaptive Simpson's rule
+    let a = number(args[0].clone())?;
+    let b = number(args[1].clone())?;
+    let tolerance = match args.get(2) {
+        Some(val) => number(val.clone())?,
+        None => 1e-5,
+    };
+    if tolerance <= 0.0 {
+        return Err(ExecError::Domain);
+    }
+    if a == b {
+        return Ok(Value::NumValue(0.0));
+    }
+
+    let fa = f.at(ctx, a)?;
+    let fb = f.at(ctx, b)?;
+    let fm = f.at(ctx, (a + b) / 2.0)?;
+    let whole = Panel::new(a, b, fa, fm, fb);
+    Ok(Value::NumValue(simpson(ctx, f, whole, tolerance, 20)?))
+}
+
+struct Panel {
+    // one interval of Simpson's rule, and the function at its ends and middle
+    a: f64,
+    b: f64,
+    fa: f64,
+    fm: f64,
+    fb: f64,
+    area: f64,
+}
+
+impl Panel {
+    fn new(a: f64, b: f64, fa: f64, fm: f64, fb: f64) -> Panel {
+        Panel {
+            a,
+            b,
+            fa,
+            fm,
+            fb,
+            area: (b - a) / 6.0 * (fa + 4.0 * fm + fb),
+        }
+    }
+}
+
+fn simpson(
+    ctx: &mut Context,
+    f: &Function,
+    panel: Panel,
+    tolerance: f64,
+    depth: u32,
+) -> Result<f64, ExecError> {
+    let mid = (panel.a + panel.b) / 2.0;
+    let flm = f.at(ctx, (panel.a + mid) / 2.0)?;
+    let frm = f.at(ctx, (mid + panel.b) / 2.0)?;
+    let left = Panel::new(panel.a, mid, panel.fa, flm, panel.fm);
+    let right = Panel::new(mid, panel.b, panel.fm, frm, panel.fb);
+    let delta = left.area + right.area - panel.area;
+
+    if depth == 0 || delta.abs() <= 15.0 * tolerance {
+        return Ok(left.area + right.area + delta / 15.0);
+    }
+    Ok(simpson(ctx, f, left, tolerance / 2.0, depth - 1)?
+        + simpson(ctx, f, right, tolerance / 2.0, depth - 1)?)
+}
+
+fn f_extremum(ctx: &mut Context, f: &Function, args: Vec<Value>, sign: f64) -> EvalResult {
+    // fMin(expr,var,lower,upper[,tol]) and fMax(, by golden section search.
+    // fMax is just fMin of the negated function, which is what sign is for
+    let mut a = number(args[0].clone())?;
+    let mut b = number(args[1].clone())?;
+    let tolerance = match args.get(2) {
+        Some(val) => number(val.clone())?,
+        None => 1e-5,
+    };
+    if a >= b || tolerance <= 0.0 {
+        return Err(ExecError::Domain);
+    }
+
+    let ratio = (5f64.sqrt() - 1.0) / 2.0;
+    let mut c = b - ratio * (b - a);
+    let mut d = a + ratio * (b - a);
+    let mut fc = sign * f.at(ctx, c)?;
+    let mut fd = sign * f.at(ctx, d)?;
+    let mut steps = 0;
+    while (b - a).abs() > tolerance * 1e-3 {
+        // far from zero the interval stops shrinking long before it gets
+        // under the tolerance, f64 just can't tell a and b apart any closer
+        steps += 1;
+        if steps > MAX_EXTREMUM_STEPS {
+            return Err(ExecError::ToleranceNotMet);
+        }
+        if fc < fd {
+            b = d;
+            d = c;
+            fd = fc;
+            c = b - ratio * (b - a);
+            fc = sign * f.at(ctx, c)?;
+        } else {
+            a = c;
+            c = d;
+            fc = fd;
+            d = a + ratio * (b - a);
+            fd = sign * f.at(ctx, d)?;
+        }
+    }
+    Ok(Value::NumValue((a + b) / 2.0))
+}
+
+fn sigma(ctx: &mut Context, f: &Function, args: Vec<Value>) -> EvalResult {
+    // Σ(expr,var,start,end), steps by 1 from start to end inclusive
+    let start = number(args[0].clone())?;
+    let end = number(args[1].clone())?;
+    // past 2^53 adding 1 doesn't change x anymore, which would loop forever
+    if end - start >= MAX_SIGMA_TERMS || start + 1.0 == start {
+        return Err(ExecError::Domain);
+    }
+    let mut total = 0.0;
+    let mut x = start;
+    while x <= end {
+        total += f.at(ctx, x)?;
+        x += 1.0;
+    }
+    Ok(Value::NumValue(total))
+}
@@ -0,0 +1,206 @@
+use std::fmt;
+use std::ops;
+
+// Complex numbers, for when the a+bi or re^θi modes are on. Angles are always
+// in radians
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Complex {
+    pub re: f64,
+    pub im: f64,
+}
+
+impl Complex {
+    pub fn new(re: f64, im: f64) -> Complex {
+        Complex { re, im }
+    }
+
+    pub fn i() -> Complex {
+        Complex::new(0.0, 1.0)
+    }
+
+    pub fn from_polar(r: f64, theta: f64) -> Complex {
+        Complex::new(r * theta.cos(), r * theta.sin())
+    }
+
+    pub fn abs(&self) -> f64 {
+        self.re.hypot(self.im)
+    }
+
+    pub fn angle(&self) -> f64 {
+        self.im.atan2(self.re)
+    }
+
+    pub fn conj(&self) -> Complex {
+        Complex::new(self.re, -self.im)
+    }
+
+    pub fn checked_div(self, other: Complex) -> Option<Complex> {
+        let denominator = other.re * other.re + other.im * other.im;
+        if denominator == 0.0 {
+            return None;
+        }
+        Some(Complex::new(
+            (self.re * other.re + self.im * other.im) / denominator,
+            (self.im * other.re - self.re * other.im) / denominator,
+        ))
+    }
+
+    pub fn sqrt(self) -> Complex {
+        // the half-angle formulas are exact for things like √(-4), where going
+        // through polar form would leave a tiny real part behind
+        let r = self.abs();
+        let re = ((r + self.re) / 2.0).sqrt();
+        let im = ((r - self.re) / 2.0).sqrt();
+        Complex::new(re, if self.im < 0.0 { -im } else { im })
+    }
+
+    fn ln(self) -> Complex {
+        Complex::new(self.abs().ln(), self.angle())
+    }
+
+    fn exp(self) -> Complex {
+        Complex::from_polar(self.re.exp(), self.im)
+    }
+
+    pub fn pow(self, other: Complex) -> Option<Complex> {
+        if other.im == 0.0 && other.re.fract() == 0.0 && other.re.abs() <= 1024.0 {
+            // integer powers by repeated multiplication so i^2 is exactly -1
+            let mut result = Complex::new(1.0, 0.0);
+            for _ in 0..(other.re.abs() as u32) {
+                result = result * self;
+            }
+            return if other.re < 0.0 {
+                Complex::new(1.0, 0.0).checked_div(result)
+            } else {
+                Some(result)
+            };
+        }
+        if self.re == 0.0 && self.im == 0.0 {
+            return if other.re > 0.0 {
+                Some(Complex::new(0.0, 0.0))
+            } else {
+                None
+            };
+        }
+        Some((other * self.ln()).exp())
+    }
+
+    pub fn is_real(&self) -> bool {
+        self.im == 0.0
+    }
+
+    pub fn clean(self) -> Complex {
+        // The calculator only keeps 14 digits, so residue like the 1.2e-16 in
+        // e^(πi) never shows up there. Don't let it show up here either
+        let scale = self.re.abs().max(self.im.abs());
+        let tiny = |n: f64| n.abs() < scale * 1e-13;
+        Complex::new(
+            if tiny(self.re) { 0.0 } else { self.re },
+            if tiny(self.im) { 0.0 } else { self.im },
+        )
+    }
+
+    pub fn polar_string(&self) -> String {
+        format!("{}e^({}i)", self.abs(), self.angle())
+    }
+}
+
+impl ops::Add for Complex {
+    type Output = Complex;
+
+    fn add(self, other: Complex) -> Complex {
+        Complex::new(self.re + other.re, self.im + other.im)
+    }
+}
+
+impl ops::Sub for Complex {
+    type Output = Complex;
+
+    fn sub(self, other: Complex) -> Complex {
+        Complex::new(self.re - other.re, self.im - other.im)
+    }
+}
+
+impl ops::Mul for Complex {
+    type Output = Complex;
+
+    fn mul(self, other: Complex) -> Complex {
+        Complex::new(
+            self.re * other.re - self.im * other.im,
+            self.re * other.im + self.im * other.re,
+        )
+    }
+}
+
+impl ops::Neg for Complex {
+    type Output = Complex;
+
+    fn neg(self) -> Complex {
+        Complex::new(-self.re, -self.im)
+    }
+}
+
+impl fmt::Display for Complex {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        let imaginary = if self.im == 1.0 {
+            String::from("i")
+        } else if self.im == -1.0 {
+            String::from("-i")
+        } else {
+            format!("{}i", self.im)
+        };
+
+        if self.re == 0.0 {
+            write!(f, "{}", imaginary)
+        } else if self.im < 0.0 {
+            write!(f, "{}{}", self.re, imaginary)
+        } else {
+            write!(f, "{}+{}", self.re, imaginary)
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_arithmetic() {
+        let a = Complex::new(1.0, 2.0);
+        let b = Complex::new(3.0, -1.0);
+        assert_eq!(a + b, Complex::new(4.0, 1.0));
+        assert_eq!(a - b, Complex::new(-2.0, 3.0));
+        assert_eq!(a * b, Complex::new(5.0, 5.0));
+        assert_eq!((a * b).checked_div(b), Some(a));
+        assert_eq!(a.checked_div(Complex::new(0.0, 0.0)), None);
+    }
+
+    #[test]
+    fn test_sqrt_and_pow() {
+        assert_eq!(Complex::new(-1.0, 0.0).sqrt(), Complex::i());
+        assert_eq!(Complex::new(-4.0, 0.0).sqrt(), Complex::new(0.0, 2.0));
+        assert_eq!(
+            Complex::i().pow(Complex::new(2.0, 0.0)),
+            Some(Complex::new(-1.0, 0.0))
+        );
+        let e_to_pi_i = Complex::new(std::f64::consts::E, 0.0)
+            .pow(Complex::new(0.0, std::f64::consts::PI))
+            .unwrap()
+            .clean();
+        assert_eq!(e_to_pi_i, Complex::new(-1.0, 0.0));
+    }
+
+    #[test]
+    fn test_display() {
+        assert_eq!(format!("{}", Complex::new(3.0, 2.0)), "3+2i");
+        assert_eq!(format!("{}", Complex::new(3.0, -2.0)), "3-2i");
+        assert_eq!(format!("{}", Complex::new(0.0, 1.0)), "i");
+        assert_eq!(format!("{}", Complex::new(0.0, -1.0)), "-i");
+        assert_eq!(format!("{}", Complex::new(0.0, 2.5)), "2.5i");
+        assert_eq!(
+            Complex::new(0.0, 2.0).polar_string(),
+            "2e^(1.5707963267948966i)"
+        );
+    }
+}
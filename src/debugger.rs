@@ -0,0 +1,430 @@
+use std::io;
+use std::io::{BufRead, Write};
+
+use crate::executor::*;
+use crate::lexer;
+use crate::parser::*;
+
+// tib --debug: run a program a statement at a time. Always on the tree-walker,
+// which is the reference for what a program does.
+//
+// tib has no prgm yet, so `next` steps over blocks instead: on a For, While,
+// Repeat or If-Then it runs until that block is done.
+
+const HELP: &str = "\
+step, s [N]     run the next statement, or the next N
+next, n         like step, but run a whole block if it starts here
+continue, c     run until a breakpoint or the end
+break, b LINE   stop before the first statement on a line
+break, b LABEL  stop at a Lbl, `break Lbl 1` for numbered labels
+delete, d N     remove breakpoint N
+watch, w EXPR   show EXPR every time the program stops
+unwatch N       remove watch N
+print, p EXPR   evaluate EXPR once
+where           the current statement and the blockstack
+vars            every variable that has been set
+info            list breakpoints and watches
+quit, q         leave the debugger
+
+Watches and print use the program's own variables, so storing to one or
+using rand in them changes what the program does.";
+
+#[derive(Debug, Clone, PartialEq)]
+pub enum Breakpoint {
+    Line(usize),
+    Label(String),
+}
+
+pub struct Debugger {
+    pub program: Program,
+    source: Vec<String>,
+    breakpoints: Vec<Breakpoint>,
+    watches: Vec<(String, ValRef)>,
+    // the statement the last step started on
+    last_pc: Option<usize>,
+    // how the program ended, once it has
+    finished: Option<Result<(), ExecError>>,
+}
+
+impl Debugger {
+    pub fn new(program: Program, source: &str) -> Debugger {
+        let mut debugger = Debugger {
+            program,
+            source: source.lines().map(String::from).collect(),
+            breakpoints: Vec::new(),
+            watches: Vec::new(),
+            last_pc: None,
+            finished: None,
+        };
+        debugger.check_finished();
+        debugger
+    }
+
+    fn check_finished(&mut self) {
+        if self.finished.is_none() && self.program.over() {
+            self.finished = Some(Ok(()));
+        }
+    }
+
+    fn line(&self, pc: usize) -> usize {
+        self.program.lines.get(pc).copied().unwrap_or(0)
+    }
+
+    fn at_breakpoint(&self) -> bool {
+        let pc = self.program.pc;
+        if self.program.over() {
+            return false;
+        }
+        self.breakpoints.iter().any(|bp| match bp {
+            // only when coming onto the line, not between its statements
+            Breakpoint::Line(line) => {
+                self.line(pc) == *line && (pc == 0 || self.line(pc - 1) != *line)
+            }
+            // Goto lands just past its Lbl
+            Breakpoint::Label(label) => match self.program.label_cache.get(label) {
+                Some(loc) => pc == *loc || (pc == loc + 1 && self.last_pc != Some(*loc)),
+                None => false,
+            },
+        })
+    }
+
+    fn step(&mut self) {
+        if self.finished.is_some() {
+            return;
+        }
+        self.last_pc = Some(self.program.pc);
+        if let Err(err) = self.program.step() {
+            self.finished = Some(Err(err));
+        }
+        self.check_finished();
+    }
+
+    fn step_over(&mut self) {
+        let depth = self.program.blockstack.len();
+        self.step();
+        while self.finished.is_none()
+            && self.program.blockstack.len() > depth
+            && !self.at_breakpoint()
+        {
+            self.step();
+        }
+    }
+
+    fn resume(&mut self) {
+        self.step();
+        while self.finished.is_none() && !self.at_breakpoint() {
+            self.step();
+        }
+    }
+
+    fn parse_expr(text: &str) -> Result<ValRef, String> {
+        // an expression, parsed on its own so the program isn't touched
+        let tokens = lexer::lex(&format!("{}\n", text)).map_err(|err| format!("{:?}", err))?;
+        let mut scratch = Program::with_options(&Options::new());
+        parse(&tokens, &mut scratch).map_err(|err| format!("{:?}", err))?;
+        match scratch.statements.as_slice() {
+            [Statement::Expression(expr)] => Ok(expr.clone()),
+            _ => Err(String::from("not an expression")),
+        }
+    }
+
+    fn eval(&mut self, expr: &ValRef) -> String {
+        match expr.eval(&mut self.program.ctx) {
+            Ok(val) => self.program.ctx.format(&val),
+            Err(err) => format!("ERR:{:?}", err),
+        }
+    }
+
+    fn show_stop(&mut self, out: &mut dyn Write) -> io::Result<()> {
+        match &self.finished {
+            Some(Ok(())) | Some(Err(ExecError::UnexpectedEof)) => {
+                writeln!(out, "the program has finished")?
+            }
+            Some(Err(err)) => {
+                writeln!(out, "ERR:{:?} on line {}", err, self.line(self.program.pc))?
+            }
+            None => {
+                let line = self.line(self.program.pc);
+                let text = match line {
+                    0 => String::new(),
+                    line => self.source.get(line - 1).cloned().unwrap_or_default(),
+                };
+                writeln!(out, "{:>4}: {}", line, text)?;
+            }
+        }
+        let mut watches = Vec::new();
+        for (text, expr) in self.watches.clone().iter() {
+            watches.push((text.clone(), self.eval(expr)));
+        }
+        for (text, val) in watches.iter() {
+            writeln!(out, "  {} = {}", text, val)?;
+        }
+        Ok(())
+    }
+
+    fn show_where(&self, out: &mut dyn Write) -> io::Result<()> {
+        let pc = self.program.pc;
+        match self.program.statements.get(pc) {
+            Some(statement) => writeln!(
+                out,
+                "statement {} on line {}: {:?}",
+                pc,
+                self.line(pc),
+                statement
+            )?,
+            None => writeln!(out, "statement {}, past the end", pc)?,
+        }
+        if self.program.blockstack.is_empty() {
+            writeln!(out, "no open blocks")?;
+        }
+        // innermost first
+        for block in self.program.blockstack.iter().rev() {
+            writeln!(out, "  {:?} from line {}", block, self.line(block.start()))?;
+        }
+        Ok(())
+    }
+
+    fn show_info(&self, out: &mut dyn Write) -> io::Result<()> {
+        for (i, bp) in self.breakpoints.iter().enumerate() {
+            match bp {
+                Breakpoint::Line(line) => writeln!(out, "breakpoint {}: line {}", i + 1, line)?,
+                Breakpoint::Label(label) => writeln!(out, "breakpoint {}: Lbl {}", i + 1, label)?,
+            }
+        }
+        for (i, (text, _)) in self.watches.iter().enumerate() {
+            writeln!(out, "watch {}: {}", i + 1, text)?;
+        }
+        Ok(())
+    }
+
+    fn add_breakpoint(&mut self, arg: &str, out: &mut dyn Write) -> io::Result<()> {
+        let bp = match arg.strip_prefix("Lbl ") {
+            Some(label) => Breakpoint::Label(String::from(label.trim())),
+            None => match arg.parse::<usize>() {
+                Ok(line) => Breakpoint::Line(line),
+                Err(_) => Breakpoint::Label(String::from(arg)),
+            },
+        };
+        let known = match &bp {
+            Breakpoint::Line(line) => self.program.lines.contains(line),
+            Breakpoint::Label(label) => self.program.label_cache.contains_key(label),
+        };
+        if arg.is_empty() || !known {
+            return writeln!(out, "nothing to stop at for {}", arg);
+        }
+        self.breakpoints.push(bp);
+        writeln!(out, "breakpoint {} set", self.breakpoints.len())
+    }
+
+    fn index(arg: &str, len: usize) -> Option<usize> {
+        // 1-based, the way breakpoints and watches are listed
+        match arg.parse::<usize>() {
+            Ok(n) if n >= 1 && n <= len => Some(n - 1),
+            _ => None,
+        }
+    }
+
+    pub fn command(&mut self, line: &str, out: &mut dyn Write) -> io::Result<bool> {
+        // Run one debugger command, false once it is time to quit
+        let line = line.trim();
+        let (cmd, arg) = match line.split_once(' ') {
+            Some((cmd, arg)) => (cmd, arg.trim()),
+            None => (line, ""),
+        };
+        match cmd {
+            "" | "s" | "step" => {
+                let count = if arg.is_empty() {
+                    Ok(1)
+                } else {
+                    arg.parse::<usize>()
+                };
+                match count {
+                    Ok(count) => {
+                        for _ in 0..count {
+                            self.step();
+                        }
+                        self.show_stop(out)?;
+                    }
+                    Err(_) => writeln!(out, "step takes a number of statements")?,
+                }
+            }
+            "n" | "next" => {
+                self.step_over();
+                self.show_stop(out)?;
+            }
+            "c" | "continue" => {
+                self.resume();
+                self.show_stop(out)?;
+            }
+            "b" | "break" => self.add_breakpoint(arg, out)?,
+            "d" | "delete" => match Debugger::index(arg, self.breakpoints.len()) {
+                Some(i) => {
+                    self.breakpoints.remove(i);
+                }
+                None => writeln!(out, "no breakpoint {}", arg)?,
+            },
+            "w" | "watch" => match Debugger::parse_expr(arg) {
+                Ok(expr) => {
+                    let val = self.eval(&expr);
+                    self.watches.push((String::from(arg), expr));
+                    writeln!(out, "watch {}: {} = {}", self.watches.len(), arg, val)?;
+                }
+                Err(err) => writeln!(out, "can't watch {}: {}", arg, err)?,
+            },
+            "unwatch" => match Debugger::index(arg, self.watches.len()) {
+                Some(i) => {
+                    self.watches.remove(i);
+                }
+                None => writeln!(out, "no watch {}", arg)?,
+            },
+            "p" | "print" => match Debugger::parse_expr(arg) {
+                Ok(expr) => {
+                    let val = self.eval(&expr);
+                    writeln!(out, "{}", val)?;
+                }
+                Err(err) => writeln!(out, "can't print {}: {}", arg, err)?,
+            },
+            "where" => self.show_where(out)?,
+            "vars" => show_vars(&self.program.ctx, out)?,
+            "info" => self.show_info(out)?,
+            "h" | "help" => writeln!(out, "{}", HELP)?,
+            "q" | "quit" => return Ok(false),
+            _ => writeln!(out, "unknown command {}, try help", cmd)?,
+        }
+        Ok(true)
+    }
+}
+
+pub fn show_vars(ctx: &Context, out: &mut dyn Write) -> io::Result<()> {
+    // every variable that has been set, the way the calculator shows it. The
+    // REPL's :vars prints the same
+    writeln!(out, "Ans = {}", ctx.format(&ctx.ans))?;
+    for (var, val) in ctx.variables().iter() {
+        let archived = if ctx.archived.contains(var) {
+            " (archived)"
+        } else {
+            ""
+        };
+        writeln!(out, "{} = {}{}", var, ctx.format(val), archived)?;
+    }
+    Ok(())
+}
+
+pub fn debug(program: Program, source: &str) -> Context {
+    // hands back the variables once you're done, for --state
+    let mut debugger = Debugger::new(program, source);
+    let stdin = io::stdin();
+    let mut stdout = io::stdout();
+    debugger.show_stop(&mut stdout).unwrap();
+    loop {
+        print!("(tib) ");
+        stdout.flush().unwrap();
+        let mut line = String::new();
+        // ^D quits too
+        if stdin.lock().read_line(&mut line).unwrap_or(0) == 0 {
+            break;
+        }
+        if !debugger.command(&line, &mut stdout).unwrap() {
+            break;
+        }
+    }
+    debugger.program.ctx
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const PROGRAM: &str = "0->A\nFor(I,1,3)\nA+I->A\nEnd\nLbl B\nDisp A\nA+1->A\n";
+
+    fn debugger(source: &str) -> Debugger {
+        let (tokens, lines) = lexer::lex_lines(&String::from(source)).unwrap();
+        let mut program = Program::with_options(&Options::new());
+        parse_lines(&tokens, &lines, &mut program).unwrap();
+        Debugger::new(program, source)
+    }
+
+    fn run(debugger: &mut Debugger, command: &str) -> String {
+        let mut out: Vec<u8> = Vec::new();
+        debugger.command(command, &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_step() {
+        let mut dbg = debugger(PROGRAM);
+        assert_eq!(run(&mut dbg, "step"), "   2: For(I,1,3)\n");
+        assert_eq!(run(&mut dbg, "s"), "   3: A+I->A\n");
+        assert_eq!(dbg.program.blockstack.len(), 1);
+        assert_eq!(run(&mut dbg, "s 2"), "   3: A+I->A\n");
+        assert_eq!(run(&mut dbg, "c"), "the program has finished\n");
+        assert_eq!(dbg.program.ctx.get(&Variable::RealVar('A')).unwrap(), 7.0);
+        // nothing left to run
+        assert_eq!(run(&mut dbg, "s"), "the program has finished\n");
+        assert!(!dbg.command("quit", &mut Vec::new()).unwrap());
+    }
+
+    #[test]
+    fn test_next() {
+        let mut dbg = debugger(PROGRAM);
+        run(&mut dbg, "s");
+        // the whole For loop
+        assert_eq!(run(&mut dbg, "n"), "   5: Lbl B\n");
+        assert_eq!(dbg.program.ctx.get(&Variable::RealVar('A')).unwrap(), 6.0);
+        assert!(dbg.program.blockstack.is_empty());
+
+        // but still stops at breakpoints inside
+        let mut dbg = debugger(PROGRAM);
+        run(&mut dbg, "s");
+        run(&mut dbg, "break 4");
+        assert_eq!(run(&mut dbg, "n"), "   4: End\n");
+    }
+
+    #[test]
+    fn test_breakpoints() {
+        let mut dbg = debugger(PROGRAM);
+        assert_eq!(run(&mut dbg, "break 3"), "breakpoint 1 set\n");
+        assert_eq!(run(&mut dbg, "b B"), "breakpoint 2 set\n");
+        assert_eq!(run(&mut dbg, "b 99"), "nothing to stop at for 99\n");
+        assert_eq!(run(&mut dbg, "b C"), "nothing to stop at for C\n");
+        assert_eq!(
+            run(&mut dbg, "info"),
+            "breakpoint 1: line 3\nbreakpoint 2: Lbl B\n"
+        );
+        run(&mut dbg, "s");
+        assert_eq!(run(&mut dbg, "watch A*10"), "watch 1: A*10 = 0\n");
+
+        // once per time round the loop
+        assert_eq!(run(&mut dbg, "c"), "   3: A+I->A\n  A*10 = 0\n");
+        assert_eq!(run(&mut dbg, "c"), "   3: A+I->A\n  A*10 = 10\n");
+        run(&mut dbg, "delete 1");
+        assert_eq!(run(&mut dbg, "c"), "   5: Lbl B\n  A*10 = 60\n");
+        run(&mut dbg, "unwatch 1");
+        assert_eq!(run(&mut dbg, "c"), "the program has finished\n");
+        assert_eq!(run(&mut dbg, "delete 5"), "no breakpoint 5\n");
+
+        let mut dbg = debugger("Lbl 1\n1->A\nGoto 1\n");
+        assert_eq!(run(&mut dbg, "b Lbl 1"), "breakpoint 1 set\n");
+        run(&mut dbg, "s");
+        assert_eq!(run(&mut dbg, "c"), "   2: 1->A\n");
+    }
+
+    #[test]
+    fn test_inspect() {
+        let mut dbg = debugger("5->A\n\"HI\"->Str1\nWhile 1\nIf A\nThen\n1/0\nEnd\nEnd\n");
+        run(&mut dbg, "s 5");
+        assert_eq!(
+            run(&mut dbg, "where"),
+            "statement 5 on line 6: Expression(Divide(1.0, 0.0))\n  IfBlock(3, true) from line 4\n  WhileBlock(2) from line 3\n"
+        );
+        assert_eq!(run(&mut dbg, "vars"), "Ans = HI\nA = 5\nStr1 = HI\n");
+        assert_eq!(run(&mut dbg, "p A+1"), "6\n");
+        assert_eq!(
+            run(&mut dbg, "p A+"),
+            "can't print A+: UnexpectedToken(EndOfLine)\n"
+        );
+        assert_eq!(run(&mut dbg, "p 1->A"), "1\n");
+        assert_eq!(run(&mut dbg, "s"), "ERR:DivideByZero on line 6\n");
+        assert_eq!(run(&mut dbg, "frob"), "unknown command frob, try help\n");
+    }
+}
@@ -1,9 +1,21 @@
-use crate::lexer::Token;
+use crate::complex::Complex;
+use crate::functions;
+use crate::lexer::{self, Token};
+use crate::optimize;
 use crate::parser::Statement;
 use crate::parser::*;
+use crate::profiler::Profile;
+use crate::sources::{Sources, Uninit};
+#[cfg(feature = "ti-float")]
+use crate::tifloat;
+use crate::vm::Op;
 use core::fmt::Debug;
-use rand::Rng;
-use std::collections::HashMap;
+use std::collections::{HashMap, HashSet};
+use std::io;
+use std::io::Write;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
+use std::time::{Duration, Instant};
 
 #[derive(Debug, PartialEq)]
 pub enum ExecError {
@@ -21,15 +33,188 @@ pub enum ExecError {
     UnknownLabel,
     NonNumericTypeInList,
     DimensionMismatch,
+    NonRealAnswer,
+    Overflow,
+    Domain,
+    NoSignChange,
+    // fMin( and fMax( gave up before narrowing down to the tolerance
+    ToleranceNotMet,
+    Undefined,
+    InvalidDim,
+    Archived,
+    Memory,
+    // the limits in Limits, with the limit that was hit
+    StepLimit(u64),
+    TimeLimit(Duration),
+    OutputLimit(usize),
+    SizeLimit(usize),
+    // Ctrl+C, the ON key on a calculator
+    Break,
 }
 
+impl ExecError {
+    pub fn describe(&self) -> String {
+        match self {
+            ExecError::StepLimit(max) => format!("ran more than {} statements (--max-steps)", max),
+            ExecError::TimeLimit(max) => {
+                format!("ran longer than {}s (--timeout)", max.as_secs_f64())
+            }
+            ExecError::OutputLimit(max) => {
+                format!("printed more than {} bytes (--max-output)", max)
+            }
+            ExecError::SizeLimit(max) => format!(
+                "made a list, matrix or string bigger than {} (--max-size)",
+                max
+            ),
+            ExecError::Break => String::from("ERR:BREAK"),
+            ExecError::Memory => String::from("ERR:MEMORY, blocks or strings nested too deep"),
+            err => format!("{:?}", err),
+        }
+    }
+
+    pub fn exit_code(&self) -> i32 {
+        // 2 is taken by bad arguments
+        match self {
+            ExecError::StepLimit(_) => 3,
+            ExecError::TimeLimit(_) => 4,
+            ExecError::OutputLimit(_) => 5,
+            ExecError::SizeLimit(_) => 6,
+            // tib's stand-in for running out of stack, which would otherwise
+            // take the whole process down
+            ExecError::Memory => 7,
+            // what a shell expects after Ctrl+C
+            ExecError::Break => 130,
+            _ => 1,
+        }
+    }
+}
+
+// Limits for running programs nobody has looked at, like in an autograder.
+// None is no limit, which is the default. Statements and time are counted
+// from each call to execute, so per line typed in the REPL
+#[derive(Debug, Clone, Default)]
+pub struct Limits {
+    pub steps: Option<u64>,
+    pub time: Option<Duration>,
+    // bytes printed by Disp
+    pub output: Option<usize>,
+    // elements in a list or matrix, characters in a string
+    pub size: Option<usize>,
+}
+
+// How many blocks can be open at once before ERR:MEMORY. A real calculator
+// runs out somewhere around here depending on how much RAM is free, no honest
+// program nests anywhere near this deep so it only trips on leaks
+pub const DEFAULT_BLOCK_LIMIT: usize = 1000;
+
+// How many strings can be evaluated inside each other (expr( of a string
+// that calls expr(, a Y= variable that uses itself) before ERR:MEMORY
+pub const MAX_EVAL_DEPTH: usize = 100;
+
 #[derive(Debug)]
 pub struct Context {
     pub ans: Value,
     pub reals: HashMap<char, Value>,
+    pub strings: HashMap<u8, String>,
+    // Y0-Y9 are kept as text and parsed every time they are evaluated
+    pub equations: HashMap<u8, String>,
+    pub lists: HashMap<String, Vec<Value>>,
+    pub matrices: HashMap<char, Vec<Vec<f64>>>,
+    // archived variables keep their value but can't be read or written until
+    // they are UnArchived
+    pub archived: HashSet<Variable>,
+    // reals that were DelVar'd. They read as 0 instead of whatever happened
+    // to be in uninitialized memory
+    pub deleted: HashSet<char>,
+    pub sources: Sources,
+    pub mode: NumberMode,
+    pub backend: Backend,
+    pub limits: Limits,
+    // bytes printed so far, for Limits.output
+    output: usize,
+    // when Limits.time runs out. This and interrupt live here rather than in
+    // Program so the builtins that loop for a long time, like Σ( and solve(,
+    // can check them
+    deadline: Option<Instant>,
+    // set from outside (the Ctrl+C handler) to stop the program with ERR:BREAK
+    pub interrupt: Arc<AtomicBool>,
+    // how many strings are being evaluated inside each other right now
+    eval_depth: usize,
     // rest of the variables/state will go here
 }
 
+#[derive(Debug, Clone, PartialEq)]
+pub enum NumberMode {
+    // Real, a+bi and re^θi on the MODE screen. In Real mode, things like √(-1)
+    // are an error instead of a complex number
+    Real,
+    Rect,
+    Polar,
+}
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Backend {
+    // How real numbers are computed. f64 is the default, the calculator's own
+    // decimal floats are opt-in since they are slower
+    Float,
+    #[cfg(feature = "ti-float")]
+    TiFloat,
+}
+
+#[derive(Debug, Clone)]
+pub struct Options {
+    // take every source of nondeterminism (uninitialized variables, rand, the
+    // clock, getKey) from `seed` and `keys` instead of the outside world
+    pub deterministic: bool,
+    pub seed: u64,
+    pub uninit: Uninit,
+    // scripted getKey presses, consumed in order. Once they run out getKey
+    // returns 0 (no key pressed)
+    pub keys: Vec<f64>,
+    // scripted lines for Input and Prompt, read before stdin is
+    pub inputs: Vec<String>,
+    pub backend: Backend,
+    // variables to set before the program starts, eg from --load
+    pub preload: Vec<(Variable, Value)>,
+    // which of the preloaded variables start out archived
+    pub archived: Vec<Variable>,
+    // open blocks allowed before ERR:MEMORY, None for no limit
+    pub block_limit: Option<usize>,
+    // print a warning whenever a Goto jumps out of a block, leaking it
+    pub warn_leaks: bool,
+    // run on the bytecode vm instead of walking the tree
+    pub vm: bool,
+    // fold constants and such before a program starts, see optimize.rs
+    pub optimize: bool,
+    // print every statement as it runs, with the Ans it leaves behind
+    pub trace: bool,
+    // count and time every statement, see profiler.rs
+    pub profile: bool,
+    pub limits: Limits,
+}
+
+impl Options {
+    pub fn new() -> Options {
+        Options {
+            deterministic: false,
+            seed: 0,
+            uninit: Uninit::Random,
+            keys: Vec::new(),
+            inputs: Vec::new(),
+            backend: Backend::Float,
+            preload: Vec::new(),
+            archived: Vec::new(),
+            block_limit: Some(DEFAULT_BLOCK_LIMIT),
+            warn_leaks: false,
+            vm: false,
+            optimize: false,
+            trace: false,
+            profile: false,
+            limits: Limits::default(),
+        }
+    }
+}
+
 impl Value {
     pub fn to_bool(&self) -> Result<bool, ExecError> {
         match self {
@@ -40,33 +225,399 @@ impl Value {
 }
 
 impl Context {
-    fn set(&mut self, var: &Variable, val: Value) -> Result<Value, ExecError> {
+    pub fn set(&mut self, var: &Variable, val: Value) -> Result<Value, ExecError> {
+        if self.archived.contains(var) {
+            return Err(ExecError::Archived);
+        }
+        self.check_size(&val)?;
         match var {
             Variable::RealVar(name) => {
                 self.reals.insert(name.clone(), val.clone());
                 Ok(val)
             }
+            Variable::StrVar(n) => match val {
+                Value::StringValue(ref string) => {
+                    self.strings.insert(*n, string.clone());
+                    Ok(val)
+                }
+                _ => Err(ExecError::TypeMismatch),
+            },
+            Variable::EquVar(n) => match val {
+                Value::StringValue(ref string) => {
+                    self.equations.insert(*n, string.clone());
+                    Ok(val)
+                }
+                _ => Err(ExecError::TypeMismatch),
+            },
+            Variable::ListVar(name) => match val {
+                Value::ValueList(ref list) => {
+                    self.lists.insert(name.clone(), list.clone());
+                    Ok(val)
+                }
+                _ => Err(ExecError::TypeMismatch),
+            },
+            Variable::MatrixVar(name) => match val {
+                Value::MatrixValue(ref rows) => {
+                    self.matrices.insert(*name, rows.clone());
+                    Ok(val)
+                }
+                _ => Err(ExecError::TypeMismatch),
+            },
+            Variable::Rand => {
+                // storing to rand seeds the generator
+                match val {
+                    Value::NumValue(n) => {
+                        self.sources.seed(n);
+                        Ok(val)
+                    }
+                    _ => Err(ExecError::TypeMismatch),
+                }
+            }
             Variable::Ans => Err(ExecError::ImmutableVariable),
         }
     }
 
-    fn get(&mut self, var: &Variable) -> Result<Value, ExecError> {
+    pub fn get(&mut self, var: &Variable) -> Result<Value, ExecError> {
+        if self.archived.contains(var) {
+            return Err(ExecError::Archived);
+        }
         match var {
             Variable::RealVar(name) => {
                 // check if is in hashmap
                 match self.reals.get(&name) {
                     Some(val) => Ok(val.clone()),
+                    None if self.deleted.contains(name) => Ok(Value::NumValue(0.0)),
                     // tried to access an uninitialized variable! Punish them for their
-                    // insolence
-                    None => {
-                        let mut rng = rand::thread_rng();
-                        Ok(Value::NumValue(rng.gen_range(-10e20..10e20)))
-                    }
+                    // insolence (unless we are pretending the RAM was just cleared)
+                    None => Ok(Value::NumValue(self.sources.uninit_real())),
                 }
             }
+            Variable::StrVar(n) => match self.strings.get(n) {
+                Some(string) => Ok(Value::StringValue(string.clone())),
+                None => Err(ExecError::Undefined),
+            },
+            Variable::EquVar(n) => match self.equations.get(n) {
+                Some(text) => {
+                    let text = text.clone();
+                    self.eval_text(&text)
+                }
+                None => Err(ExecError::Undefined),
+            },
+            Variable::ListVar(name) => match self.lists.get(name) {
+                Some(list) => Ok(Value::ValueList(list.clone())),
+                None => Err(ExecError::Undefined),
+            },
+            Variable::MatrixVar(name) => match self.matrices.get(name) {
+                Some(rows) => Ok(Value::MatrixValue(rows.clone())),
+                None => Err(ExecError::Undefined),
+            },
+            Variable::Rand => Ok(Value::NumValue(self.sources.rand())),
             Variable::Ans => Ok(self.ans.clone()),
         }
     }
+
+    fn exists(&self, var: &Variable) -> bool {
+        match var {
+            Variable::RealVar(name) => self.reals.contains_key(name),
+            Variable::StrVar(n) => self.strings.contains_key(n),
+            Variable::EquVar(n) => self.equations.contains_key(n),
+            Variable::ListVar(name) => self.lists.contains_key(name),
+            Variable::MatrixVar(name) => self.matrices.contains_key(name),
+            Variable::Rand | Variable::Ans => false,
+        }
+    }
+
+    pub fn archive(&mut self, var: &Variable) -> Result<(), ExecError> {
+        // archiving something that isn't there is an error, archiving it twice
+        // is not
+        if !self.exists(var) {
+            return Err(ExecError::Undefined);
+        }
+        self.archived.insert(var.clone());
+        Ok(())
+    }
+
+    pub fn unarchive(&mut self, var: &Variable) -> Result<(), ExecError> {
+        if !self.exists(var) {
+            return Err(ExecError::Undefined);
+        }
+        self.archived.remove(var);
+        Ok(())
+    }
+
+    pub fn delete(&mut self, var: &Variable) {
+        // DelVar works on archived variables too
+        self.archived.remove(var);
+        match var {
+            Variable::RealVar(name) => {
+                self.reals.remove(name);
+                self.deleted.insert(*name);
+            }
+            Variable::StrVar(n) => {
+                self.strings.remove(n);
+            }
+            Variable::EquVar(n) => {
+                self.equations.remove(n);
+            }
+            Variable::ListVar(name) => {
+                self.lists.remove(name);
+            }
+            Variable::MatrixVar(name) => {
+                self.matrices.remove(name);
+            }
+            Variable::Rand | Variable::Ans => (),
+        }
+    }
+
+    fn check_size(&self, val: &Value) -> Result<(), ExecError> {
+        let max = match self.limits.size {
+            Some(max) => max,
+            None => return Ok(()),
+        };
+        let size = match val {
+            Value::StringValue(string) => string.chars().count(),
+            Value::ValueList(list) => list.len(),
+            Value::MatrixValue(rows) => rows.iter().map(|row| row.len()).sum(),
+            _ => 0,
+        };
+        if size > max {
+            Err(ExecError::SizeLimit(max))
+        } else {
+            Ok(())
+        }
+    }
+
+    pub fn set_ans(&mut self, val: Value) -> Result<(), ExecError> {
+        // Ans can grow just like a variable, "A":While 1:Ans+Ans:End
+        self.check_size(&val)?;
+        self.ans = val;
+        Ok(())
+    }
+
+    fn count_output(&mut self, bytes: usize) -> Result<(), ExecError> {
+        // Everything a program prints is counted here first
+        self.output += bytes;
+        if let Some(max) = self.limits.output {
+            if self.output > max {
+                return Err(ExecError::OutputLimit(max));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn check_break(&self) -> Result<(), ExecError> {
+        // has someone pressed Ctrl+C since we last looked
+        if self.interrupt.swap(false, Ordering::Relaxed) {
+            return Err(ExecError::Break);
+        }
+        Ok(())
+    }
+
+    pub fn check_time(&self) -> Result<(), ExecError> {
+        // Between statements, and inside anything that evaluates an
+        // expression over and over
+        if let (Some(deadline), Some(time)) = (self.deadline, self.limits.time) {
+            if Instant::now() >= deadline {
+                return Err(ExecError::TimeLimit(time));
+            }
+        }
+        Ok(())
+    }
+
+    pub fn disp(&mut self, text: &str) -> Result<(), ExecError> {
+        self.count_output(text.len() + 1)?;
+        println!("{}", text);
+        Ok(())
+    }
+
+    fn input(&mut self, prompt: &str, var: &Variable) -> Result<(), ExecError> {
+        // Input and Prompt. What is typed is evaluated, except for strings
+        // which take it as it is. Running out of input is like pressing ON
+        self.count_output(prompt.len())?;
+        print!("{}", prompt);
+        io::stdout().flush().ok();
+        let (line, scripted) = self.sources.input().ok_or(ExecError::Break)?;
+        if scripted {
+            // shown as if it had been typed
+            self.disp(&line)?;
+        }
+        let val = match var {
+            Variable::StrVar(_) | Variable::EquVar(_) => Value::StringValue(line),
+            _ => self.eval_text(&line)?,
+        };
+        self.set(var, val)?;
+        Ok(())
+    }
+
+    pub fn exec_command(&mut self, cmd: &Command) -> Result<(), ExecError> {
+        // Commands that only touch variables and settings, not control flow
+        match cmd {
+            Command::Lbl(_) => (),
+            Command::Mode(mode) => self.mode = mode.clone(),
+            Command::EquToString(equ, string) => {
+                let text = match equ {
+                    Variable::EquVar(n) => self.equations.get(n).cloned(),
+                    _ => None,
+                };
+                match text {
+                    Some(text) => self.set(string, Value::StringValue(text))?,
+                    None => return Err(ExecError::Undefined),
+                };
+            }
+            Command::StringToEqu(equ, string) => {
+                let text = self.get(string)?;
+                self.set(equ, text)?;
+            }
+            Command::DelVar(var) => self.delete(var),
+            Command::Output(row, col, val) => {
+                // the home screen is 8 rows of 16. tib has no screen to put
+                // it on, so it comes out on its own line like Disp
+                let row = functions::index(row.eval(self)?)?;
+                let col = functions::index(col.eval(self)?)?;
+                if row > 8 || col > 16 {
+                    return Err(ExecError::Domain);
+                }
+                let val = val.eval(self)?;
+                let text = self.format(&val);
+                self.disp(&text)?;
+            }
+            Command::Input(prompt, var) => {
+                let prompt = match prompt {
+                    Some(prompt) => {
+                        let prompt = prompt.eval(self)?;
+                        self.format(&prompt)
+                    }
+                    None => String::from("?"),
+                };
+                self.input(&prompt, var)?;
+            }
+            Command::Prompt(vars) => {
+                for var in vars.iter() {
+                    self.input(&format!("{}=?", var), var)?;
+                }
+            }
+            Command::ClrList(lists) => {
+                for name in lists.iter() {
+                    let list = Variable::ListVar(name.clone());
+                    self.set(&list, Value::ValueList(Vec::new()))?;
+                }
+            }
+            Command::ClrAllLists => {
+                // archived lists are left alone
+                for (name, list) in self.lists.iter_mut() {
+                    if !self.archived.contains(&Variable::ListVar(name.clone())) {
+                        list.clear();
+                    }
+                }
+            }
+            Command::Archive(var) => self.archive(var)?,
+            Command::UnArchive(var) => self.unarchive(var)?,
+            _ => return Err(ExecError::NotYetImplemented),
+        }
+        Ok(())
+    }
+
+    pub fn variables(&self) -> Vec<(Variable, Value)> {
+        // Every variable that has been set, archived or not, in a stable
+        // order so saving the same state twice gives the same file
+        let mut vars: Vec<(Variable, Value)> = Vec::new();
+        for (name, val) in self.reals.iter() {
+            vars.push((Variable::RealVar(*name), val.clone()));
+        }
+        for (n, string) in self.strings.iter() {
+            vars.push((Variable::StrVar(*n), Value::StringValue(string.clone())));
+        }
+        for (n, text) in self.equations.iter() {
+            vars.push((Variable::EquVar(*n), Value::StringValue(text.clone())));
+        }
+        for (name, list) in self.lists.iter() {
+            vars.push((
+                Variable::ListVar(name.clone()),
+                Value::ValueList(list.clone()),
+            ));
+        }
+        for (name, rows) in self.matrices.iter() {
+            vars.push((Variable::MatrixVar(*name), Value::MatrixValue(rows.clone())));
+        }
+        vars.sort_by_key(|(var, _)| format!("{:?}", var));
+        vars
+    }
+}
+
+impl Target {
+    pub fn get(&self, ctx: &mut Context) -> EvalResult {
+        match self {
+            Target::Var(var) => ctx.get(var),
+            Target::ListElement(name, i) => {
+                let i = functions::index(i.eval(ctx)?)?;
+                match ctx.get(&Variable::ListVar(name.clone()))? {
+                    Value::ValueList(list) => list.get(i - 1).cloned().ok_or(ExecError::InvalidDim),
+                    _ => Err(ExecError::TypeMismatch),
+                }
+            }
+            Target::MatrixElement(name, row, col) => {
+                let row = functions::index(row.eval(ctx)?)?;
+                let col = functions::index(col.eval(ctx)?)?;
+                match ctx.get(&Variable::MatrixVar(*name))? {
+                    Value::MatrixValue(rows) => rows
+                        .get(row - 1)
+                        .and_then(|r| r.get(col - 1))
+                        .map(|n| Value::NumValue(*n))
+                        .ok_or(ExecError::InvalidDim),
+                    _ => Err(ExecError::TypeMismatch),
+                }
+            }
+        }
+    }
+
+    pub fn set(&self, ctx: &mut Context, val: Value) -> EvalResult {
+        match self {
+            Target::Var(var) => ctx.set(var, val),
+            Target::ListElement(name, i) => {
+                let i = functions::index(i.eval(ctx)?)?;
+                if val.as_complex().is_none() {
+                    return Err(ExecError::TypeMismatch);
+                }
+                let var = Variable::ListVar(name.clone());
+                // storing one past the end makes the list longer, which is
+                // also how a list that doesn't exist yet gets made
+                let mut list = match ctx.get(&var) {
+                    Ok(Value::ValueList(list)) => list,
+                    Ok(_) => return Err(ExecError::TypeMismatch),
+                    Err(ExecError::Undefined) => Vec::new(),
+                    Err(err) => return Err(err),
+                };
+                if i <= list.len() {
+                    list[i - 1] = val.clone();
+                } else if i == list.len() + 1 {
+                    list.push(val.clone());
+                } else {
+                    return Err(ExecError::InvalidDim);
+                }
+                ctx.set(&var, Value::ValueList(list))?;
+                Ok(val)
+            }
+            Target::MatrixElement(name, row, col) => {
+                let row = functions::index(row.eval(ctx)?)?;
+                let col = functions::index(col.eval(ctx)?)?;
+                let n = match val {
+                    Value::NumValue(n) => n,
+                    _ => return Err(ExecError::TypeMismatch),
+                };
+                let var = Variable::MatrixVar(*name);
+                let mut rows = match ctx.get(&var)? {
+                    Value::MatrixValue(rows) => rows,
+                    _ => return Err(ExecError::TypeMismatch),
+                };
+                match rows.get_mut(row - 1).and_then(|r| r.get_mut(col - 1)) {
+                    Some(element) => *element = n,
+                    None => return Err(ExecError::InvalidDim),
+                }
+                ctx.set(&var, Value::MatrixValue(rows))?;
+                Ok(val)
+            }
+        }
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -79,13 +630,79 @@ pub enum Block {
     RepeatBlock(usize),
 }
 
+impl Block {
+    pub fn start(&self) -> usize {
+        match self {
+            Block::IfBlock(loc, _) => *loc,
+            Block::WhileBlock(loc) => *loc,
+            Block::ForBlock(loc) => *loc,
+            Block::RepeatBlock(loc) => *loc,
+        }
+    }
+}
+
 impl Context {
-    fn new() -> Context {
-        // todo: init vars to random values to punish people using
-        // uninitialized variables
-        Context {
+    fn new(options: &Options) -> Context {
+        let mut ctx = Context {
             ans: Value::NumValue(0.0),
             reals: HashMap::new(),
+            strings: HashMap::new(),
+            equations: HashMap::new(),
+            lists: HashMap::new(),
+            matrices: HashMap::new(),
+            archived: HashSet::new(),
+            deleted: HashSet::new(),
+            sources: Sources::new(options),
+            mode: NumberMode::Real,
+            backend: options.backend,
+            limits: Limits::default(),
+            output: 0,
+            deadline: None,
+            interrupt: Arc::new(AtomicBool::new(false)),
+            eval_depth: 0,
+        };
+        for (var, val) in options.preload.iter() {
+            // tivars::read_vars only hands out values that fit their variable
+            ctx.set(var, val.clone()).unwrap();
+        }
+        // what was loaded isn't the program's doing
+        ctx.limits = options.limits.clone();
+        for var in options.archived.iter() {
+            ctx.archived.insert(var.clone());
+        }
+        ctx
+    }
+
+    pub fn eval_text(&mut self, text: &str) -> EvalResult {
+        // Lex, parse and evaluate an expression from a string, for expr( and
+        // the Y= variables. A string can end up evaluating itself, like
+        // "Y1+1"->Y1, which would otherwise recurse until the stack runs out
+        if self.eval_depth >= MAX_EVAL_DEPTH {
+            return Err(ExecError::Memory);
+        }
+        let tokens = match lexer::lex(&String::from(text)) {
+            Ok(tokens) => tokens,
+            Err(_) => return Err(ExecError::SyntaxError),
+        };
+        let expr = match parse_expression(&tokens) {
+            Ok(expr) => expr,
+            Err(_) => return Err(ExecError::SyntaxError),
+        };
+        self.eval_depth += 1;
+        let result = expr.eval(self);
+        self.eval_depth -= 1;
+        result
+    }
+
+    pub fn format(&self, val: &Value) -> String {
+        // Format a value for display, respecting the complex number mode
+        match val {
+            Value::ComplexValue(c) if self.mode == NumberMode::Polar => c.polar_string(),
+            Value::ValueList(l) if self.mode == NumberMode::Polar => {
+                let items: Vec<String> = l.iter().map(|v| self.format(v)).collect();
+                format!("{{{}}}", items.join(" "))
+            }
+            _ => format!("{}", val),
         }
     }
 }
@@ -97,20 +714,60 @@ pub struct Program {
     pub pc: usize,
     pub blockstack: Vec<Block>,
     pub label_cache: HashMap<String, usize>,
+    // source line of each statement, 0 when we don't know it
+    pub lines: Vec<usize>,
+    // the text of those lines, for --trace to show
+    pub source: Vec<String>,
+    pub block_limit: Option<usize>,
+    pub warn_leaks: bool,
+    pub trace: bool,
+    pub profile: Option<Profile>,
+    // where scan_and_advance ended up, keyed by (pc, expect_else, skip_else)
+    jump_cache: HashMap<(usize, bool, bool), usize>,
+    // how many statements there were when the cache was filled
+    jump_cache_len: usize,
+    // statements run since execute was called
+    steps: u64,
+    // the last statement run was a command rather than an expression, so the
+    // home screen shows Done instead of Ans
+    pub done: bool,
 }
 
 impl Program {
-    pub fn new() -> Program {
+    pub fn with_options(options: &Options) -> Program {
         Program {
-            ctx: Context::new(),
+            ctx: Context::new(options),
             statements: Vec::new(),
             pc: 0,
             blockstack: Vec::new(),
             label_cache: HashMap::new(),
+            lines: Vec::new(),
+            source: Vec::new(),
+            block_limit: options.block_limit,
+            warn_leaks: options.warn_leaks,
+            trace: options.trace,
+            profile: if options.profile {
+                Some(Profile::new())
+            } else {
+                None
+            },
+            jump_cache: HashMap::new(),
+            jump_cache_len: 0,
+            steps: 0,
+            done: true,
         }
     }
 
-    fn over(&self) -> bool {
+    pub fn result(&self) -> String {
+        // what the home screen shows once a program is done
+        if self.done {
+            String::from("Done")
+        } else {
+            self.ctx.format(&self.ctx.ans)
+        }
+    }
+
+    pub fn over(&self) -> bool {
         return self.pc >= self.statements.len();
     }
 
@@ -139,13 +796,7 @@ impl Program {
 
         // make sure that we are popping off the same one we put on
         let removed = self.blockstack.pop().ok_or(ExecError::SyntaxError)?;
-        let removed_loc: usize;
-        match removed {
-            Block::IfBlock(loc, _) => removed_loc = loc,
-            Block::WhileBlock(loc) => removed_loc = loc,
-            Block::ForBlock(loc) => removed_loc = loc,
-            Block::RepeatBlock(loc) => removed_loc = loc,
-        }
+        let removed_loc = removed.start();
 
         if removed_loc != inserted_loc {
             Err(ExecError::SyntaxError)
@@ -154,15 +805,39 @@ impl Program {
         }
     }
 
-    fn scan_and_advance(&mut self, expect_else: bool, skip_else: bool) -> Result<(), ExecError> {
+    pub fn scan_and_advance(
+        &mut self,
+        expect_else: bool,
+        skip_else: bool,
+    ) -> Result<(), ExecError> {
+        // The scan only ever looks at the statements, never at what is on the
+        // blockstack (End being turned off happens when the End runs, not
+        // here), so where it lands can be remembered. The only thing that
+        // changes the answer is more statements showing up, like in the REPL
+        if self.jump_cache_len != self.statements.len() {
+            self.jump_cache.clear();
+            self.jump_cache_len = self.statements.len();
+        }
+        let key = (self.pc, expect_else, skip_else);
+        if let Some(target) = self.jump_cache.get(&key) {
+            self.pc = *target;
+            return Ok(());
+        }
+        // errors aren't cached, they're rare and usually end the program
+        self.scan(expect_else, skip_else)?;
+        self.jump_cache.insert(key, self.pc);
+        Ok(())
+    }
+
+    fn scan(&mut self, expect_else: bool, skip_else: bool) -> Result<(), ExecError> {
         // Used when we have determined we need to skip some code, becuase
         // we are not going to execute a loop body or we are skipping an arm
         // of an else statement.
         // Scan for an End command (or an Else command too if we are scanning
         // because of an If command). As we go along, we need to respect and
         // keep track of other block commands on the block stack to make sure
-        // we don't steal someone else's End or Else. We can't do any caching
-        // or parsing beforehand because in tib End can be "turned off", eg
+        // we don't steal someone else's End or Else. In tib End can be
+        // "turned off", eg
         //
         // 1 -> A
         // If 1
@@ -172,7 +847,10 @@ impl Program {
         //     End
         // 0 -> A
         // Goto A
-        // is a valid program (loops forever)
+        // is a valid program (loops forever). That happens at run time though,
+        // when the End runs against whatever is on the blockstack. Where a
+        // scan from a given statement lands only depends on the statements,
+        // which is why scan_and_advance can cache it
         self.advance();
         loop {
             match self.next_statement()? {
@@ -225,7 +903,7 @@ impl Program {
         self.pc += 1;
     }
 
-    fn peek_next(&mut self) -> Result<&Statement, ExecError> {
+    pub fn peek_next(&mut self) -> Result<&Statement, ExecError> {
         if self.pc + 1 >= self.statements.len() {
             Err(ExecError::UnexpectedEof)
         } else {
@@ -233,43 +911,106 @@ impl Program {
         }
     }
 
+    pub fn start_limits(&mut self) {
+        self.steps = 0;
+        self.ctx.deadline = self.ctx.limits.time.map(|time| Instant::now() + time);
+    }
+
+    pub fn checkpoint(&mut self) -> Result<(), ExecError> {
+        // Before every statement, has someone pressed Ctrl+C or is the program
+        // over one of its limits
+        self.ctx.check_break()?;
+        self.steps += 1;
+        if let Some(max) = self.ctx.limits.steps {
+            if self.steps > max {
+                return Err(ExecError::StepLimit(max));
+            }
+        }
+        self.ctx.check_time()
+    }
+
     fn execute(&mut self) -> Result<(), ExecError> {
+        self.start_limits();
         while !self.over() {
-            // println!("{:?}", self.blockstack);
-            // wart of me battling the borrow checker VVV
-            match self.next_statement()?.clone() {
-                Statement::Expression(expr) => {
-                    self.ctx.ans = expr.eval(&mut self.ctx)?;
-                }
-                Statement::Command(statement) => match statement {
-                    Command::If(expr) => self.exec_if(expr)?,
-                    Command::Then => self.exec_then()?,
-                    Command::Else => self.exec_else()?,
-                    Command::End => self.exec_end()?,
-                    Command::Disp(val) => self.exec_disp(val)?,
-                    Command::For(cmd) => self.exec_for(&cmd)?,
-                    Command::While(expr) => self.exec_while(expr)?,
-                    Command::Repeat(_cmd) => self.exec_repeat()?,
-                    Command::Lbl(_) => (),
-                    Command::Goto(label) => match self.label_cache.get(&label) {
-                        Some(loc) => {
-                            self.pc = *loc;
-                        }
-                        None => return Err(ExecError::UnknownLabel),
-                    },
-                    Command::DecrementSkip(var, val) => self.exec_ds_rs(&var, &val, true)?,
-                    Command::IncrementSkip(var, val) => self.exec_ds_rs(&var, &val, false)?,
-
-                    _ => return Err(ExecError::NotYetImplemented),
-                },
+            self.checkpoint()?;
+            if self.trace || self.profile.is_some() {
+                self.step_traced()?;
+            } else {
+                self.step()?;
             }
-            self.advance();
         }
 
         Ok(())
     }
 
-    fn next_then(&mut self) -> Result<bool, ExecError> {
+    fn step_traced(&mut self) -> Result<(), ExecError> {
+        // step, and let --trace and --profile know about it
+        let pc = self.pc;
+        let start = Instant::now();
+        let result = self.step();
+        if let Some(profile) = &mut self.profile {
+            profile.record(pc, start.elapsed());
+        }
+        if self.trace {
+            let after = match &result {
+                Ok(()) => format!("Ans = {}", self.ctx.format(&self.ctx.ans)),
+                Err(err) => format!("ERR:{:?}", err),
+            };
+            eprintln!("{}", self.trace_line(pc, &after));
+        }
+        result
+    }
+
+    fn trace_line(&self, pc: usize, after: &str) -> String {
+        // the line the statement came from as it was written, or the parsed
+        // statement when we don't have the source
+        let line = self.lines.get(pc).copied().unwrap_or(0);
+        let text = match line.checked_sub(1).and_then(|i| self.source.get(i)) {
+            Some(text) => String::from(text.trim()),
+            None => format!("{:?}", self.statements[pc]),
+        };
+        format!("trace: line {}: {}  {}", line, text, after)
+    }
+
+    pub fn step(&mut self) -> Result<(), ExecError> {
+        // Run the statement at pc and move on, one at a time for the debugger
+        // println!("{:?}", self.blockstack);
+        // wart of me battling the borrow checker VVV
+        let statement = self.next_statement()?.clone();
+        self.done = matches!(statement, Statement::Command(_));
+        match statement {
+            Statement::Expression(expr) => {
+                let ans = expr.eval(&mut self.ctx)?;
+                self.ctx.set_ans(ans)?;
+            }
+            Statement::Command(statement) => match statement {
+                Command::If(expr) => self.exec_if(expr)?,
+                Command::Then => self.exec_then()?,
+                Command::Else => self.exec_else()?,
+                Command::End => self.exec_end()?,
+                Command::Disp(val) => self.exec_disp(val)?,
+                Command::For(cmd) => self.exec_for(&cmd)?,
+                Command::While(expr) => self.exec_while(expr)?,
+                Command::Repeat(_cmd) => self.exec_repeat()?,
+                Command::Goto(label) => match self.label_cache.get(&label) {
+                    Some(loc) => {
+                        let loc = *loc;
+                        self.jump(&label, loc);
+                    }
+                    None => return Err(ExecError::UnknownLabel),
+                },
+                Command::Jump(label, loc) => self.jump(&label, loc),
+                Command::Stop => self.stop(),
+                Command::DecrementSkip(var, val) => self.exec_ds_rs(&var, &val, true)?,
+                Command::IncrementSkip(var, val) => self.exec_ds_rs(&var, &val, false)?,
+                cmd => self.ctx.exec_command(&cmd)?,
+            },
+        }
+        self.advance();
+        Ok(())
+    }
+
+    pub fn next_then(&mut self) -> Result<bool, ExecError> {
         match self.peek_next()? {
             Statement::Command(cmd) => match cmd {
                 Command::Then => Ok(true),
@@ -281,20 +1022,24 @@ impl Program {
 
     fn exec_ds_rs(
         &mut self,
-        var: &Variable,
+        target: &Target,
         value: &ValRef,
         decrement: bool,
     ) -> Result<(), ExecError> {
+        // there has to be a statement after this to (maybe) skip
+        if self.pc + 1 >= self.statements.len() {
+            return Err(ExecError::SyntaxError);
+        }
         let delta = if decrement { -1.0 } else { 1.0 };
         let new_value = BinaryOp::add(
-            Box::new(self.ctx.get(&var)?),
+            Box::new(target.get(&mut self.ctx)?),
             Box::new(Value::NumValue(delta)),
         )
         .eval(&mut self.ctx)?;
-        self.ctx.set(&var, new_value)?;
+        target.set(&mut self.ctx, new_value.clone())?;
 
         let mut skip = false;
-        let lhs = Box::new(self.ctx.get(var)?.clone());
+        let lhs = Box::new(new_value);
         let rhs = value.clone();
         if decrement {
             if BinaryOp::less(lhs, rhs).eval(&mut self.ctx)?.to_bool()? {
@@ -313,12 +1058,99 @@ impl Program {
         Ok(())
     }
 
+    pub fn push_block(&mut self, block: Block) -> Result<(), ExecError> {
+        // Every block that is jumped out of with Goto stays on the blockstack
+        // forever, just like on the calculator, which eventually runs out of
+        // memory
+        if self
+            .block_limit
+            .is_some_and(|limit| self.blockstack.len() >= limit)
+        {
+            return Err(ExecError::Memory);
+        }
+        self.blockstack.push(block);
+        Ok(())
+    }
+
+    fn block_end(&self, start: usize) -> usize {
+        // index of the End that closes the block opened at start, or the end
+        // of the program if it is never closed
+        let mut depth = 0;
+        for (i, statement) in self.statements.iter().enumerate().skip(start) {
+            match statement {
+                Statement::Command(Command::If(_)) => {
+                    if let Some(Statement::Command(Command::Then)) = self.statements.get(i + 1) {
+                        depth += 1;
+                    }
+                }
+                Statement::Command(Command::For(_))
+                | Statement::Command(Command::While(_))
+                | Statement::Command(Command::Repeat(_)) => depth += 1,
+                Statement::Command(Command::End) => depth -= 1,
+                _ => (),
+            }
+            if depth == 0 {
+                return i;
+            }
+        }
+        self.statements.len()
+    }
+
+    pub fn open_blocks(&self) -> usize {
+        // how many blocks are still waiting for their End, counted the same
+        // way as block_end. A stray End doesn't close anything
+        let mut depth: usize = 0;
+        for (i, statement) in self.statements.iter().enumerate() {
+            match statement {
+                Statement::Command(Command::If(_)) => {
+                    if let Some(Statement::Command(Command::Then)) = self.statements.get(i + 1) {
+                        depth += 1;
+                    }
+                }
+                Statement::Command(Command::For(_))
+                | Statement::Command(Command::While(_))
+                | Statement::Command(Command::Repeat(_)) => depth += 1,
+                Statement::Command(Command::End) => depth = depth.saturating_sub(1),
+                _ => (),
+            }
+        }
+        depth
+    }
+
+    pub fn warn_leak(&self, label: &str, target: usize) {
+        // count the open blocks that the jump lands outside of, innermost
+        // first. Those will never see their End
+        let leaked = self
+            .blockstack
+            .iter()
+            .rev()
+            .take_while(|block| {
+                let start = block.start();
+                target <= start || target > self.block_end(start)
+            })
+            .count();
+        if leaked == 0 {
+            return;
+        }
+        let line = match self.lines.get(self.pc) {
+            Some(line) if *line > 0 => format!(" on line {}", line),
+            _ => String::new(),
+        };
+        eprintln!(
+            "warning: Goto {}{} jumps out of a block without reaching its End, {} leaked block{} now",
+            label,
+            line,
+            leaked,
+            if leaked == 1 { "" } else { "s" }
+        );
+    }
+
     fn exec_if(&mut self, condition: ValRef) -> Result<(), ExecError> {
         let result = condition.eval(&mut self.ctx)?;
 
         if result.to_bool()? {
             if self.next_then()? {
-                self.blockstack.push(Block::IfBlock(self.pc, true));
+                self.push_block(Block::IfBlock(self.pc, true))?;
                 // point the pc at the then statement so that next iteration of main loop advances
                 // past it (we don't want to execute a then ever)
                 self.advance();
@@ -328,7 +1160,7 @@ impl Program {
         } else {
             if self.next_then()? {
                 // We need to skip to the End associated(?) with this If node, or go to the Else
-                self.blockstack.push(Block::IfBlock(self.pc, false));
+                self.push_block(Block::IfBlock(self.pc, false))?;
                 self.scan_and_advance(true, false)?;
                 // If the next thing is an else, advance since we don't want to execute the
                 // else since we did not take the true branch.
@@ -348,11 +1180,49 @@ impl Program {
         Ok(())
     }
 
+    pub fn retain_statements(&mut self, keep: &[bool]) {
+        // Drop statements, keeping labels and source lines lined up with the
+        // ones that are left
+        let mut i = 0;
+        self.statements.retain(|_| {
+            i += 1;
+            keep[i - 1]
+        });
+        if self.lines.len() == keep.len() {
+            let mut i = 0;
+            self.lines.retain(|_| {
+                i += 1;
+                keep[i - 1]
+            });
+        }
+        self.label_cache.clear();
+        for (i, statement) in self.statements.iter().enumerate() {
+            if let Statement::Command(Command::Lbl(name)) = statement {
+                self.label_cache.entry(name.clone()).or_insert(i);
+            }
+        }
+        self.jump_cache.clear();
+    }
+
+    pub fn jump(&mut self, label: &str, loc: usize) {
+        if self.warn_leaks {
+            self.warn_leak(label, loc);
+        }
+        self.pc = loc;
+    }
+
+    pub fn stop(&mut self) {
+        // the program is over, open blocks and all. Land on the last statement
+        // so the pc moves past it like any other
+        self.blockstack.clear();
+        self.pc = self.statements.len() - 1;
+    }
+
     fn exec_then(&mut self) -> Result<(), ExecError> {
         Err(ExecError::UnexpectedThen)
     }
 
-    fn exec_else(&mut self) -> Result<(), ExecError> {
+    pub fn exec_else(&mut self) -> Result<(), ExecError> {
         // An else should only be executed if we took the true branch of an if statement
         // the signal is to skip to the end.
         // If there is an Else, we need to skip to the End associated with this
@@ -443,7 +1313,7 @@ impl Program {
     }
 
     fn exec_while(&mut self, condition: ValRef) -> Result<(), ExecError> {
-        self.blockstack.push(Block::WhileBlock(self.pc));
+        self.push_block(Block::WhileBlock(self.pc))?;
         // evaluate the condition
         let result = condition.eval(&mut self.ctx)?;
 
@@ -458,14 +1328,14 @@ impl Program {
 
     fn exec_repeat(&mut self) -> Result<(), ExecError> {
         // only thing to do is put an entry on the blockstack
-        self.blockstack.push(Block::RepeatBlock(self.pc));
+        self.push_block(Block::RepeatBlock(self.pc))?;
         Ok(())
     }
 
     fn exec_disp(&mut self, val: ValRef) -> Result<(), ExecError> {
         let result = val.eval(&mut self.ctx)?;
-        println!("{}", result);
-        Ok(())
+        let text = self.ctx.format(&result);
+        self.ctx.disp(&text)
     }
 
     fn for_should_execute_loop(&mut self, cmd: &For) -> Result<bool, ExecError> {
@@ -520,7 +1390,7 @@ impl Program {
         let start = cmd.start.eval(&mut self.ctx)?;
         // Set it
         self.ctx.set(&cmd.var, start)?;
-        self.blockstack.push(Block::ForBlock(self.pc));
+        self.push_block(Block::ForBlock(self.pc))?;
 
         // Check to see if we need to skip to the end
         if !self.for_should_execute_loop(cmd)? {
@@ -538,6 +1408,16 @@ pub trait Eval {
     fn eval(&self, ctx: &mut Context) -> EvalResult;
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result;
     fn clone_expr(&self) -> Box<dyn Eval>;
+    // Turn the expression into stack code for the VM. Anything that doesn't
+    // know how is handed to the tree-walker as is
+    fn compile(&self, code: &mut Vec<Op>) {
+        code.push(Op::Tree(self.clone_expr()));
+    }
+    // Fold whatever is constant under this node in place, and hand back the
+    // value if the whole node is constant. See optimize.rs
+    fn fold(&mut self, _ctx: &Context) -> Option<Value> {
+        None
+    }
 }
 
 impl Debug for dyn Eval {
@@ -569,25 +1449,57 @@ pub struct Or {
 
 pub struct StoreNode {
     pub val: ValRef,
-    pub var: Variable,
+    pub target: Target,
 }
 
 impl Eval for StoreNode {
     fn eval(&self, ctx: &mut Context) -> EvalResult {
         let val = self.val.eval(ctx)?;
-        ctx.set(&self.var, val)
+        self.target.set(ctx, val)
     }
 
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
-        write!(f, "Store({:?}->{:?})", self.val, self.var)
+        write!(f, "Store({:?}->{:?})", self.val, self.target)
     }
 
     fn clone_expr(&self) -> Box<dyn Eval> {
         Box::new(StoreNode {
-            var: self.var.clone(),
+            target: self.target.clone(),
             val: self.val.clone(),
         })
     }
+
+    fn compile(&self, code: &mut Vec<Op>) {
+        self.val.compile(code);
+        code.push(Op::Store(self.target.clone()));
+    }
+
+    fn fold(&mut self, ctx: &Context) -> Option<Value> {
+        // storing is never constant, but what gets stored can be
+        optimize::fold(&mut self.val, ctx);
+        None
+    }
+}
+
+pub struct ElementRef {
+    // L1(2) or [A](1,2) in an expression
+    pub target: Target,
+}
+
+impl Eval for ElementRef {
+    fn eval(&self, ctx: &mut Context) -> EvalResult {
+        self.target.get(ctx)
+    }
+
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "Element({:?})", self.target)
+    }
+
+    fn clone_expr(&self) -> Box<dyn Eval> {
+        Box::new(ElementRef {
+            target: self.target.clone(),
+        })
+    }
 }
 
 pub struct VarRef {
@@ -608,12 +1520,25 @@ impl Eval for VarRef {
             var: self.var.clone(),
         })
     }
+
+    fn compile(&self, code: &mut Vec<Op>) {
+        code.push(Op::Load(self.var.clone()));
+    }
 }
 
 pub struct BinaryOp {
     pub lhs: ValRef,
     pub rhs: ValRef,
     pub num_num: fn(f64, f64) -> EvalResult,
+    pub cplx_cplx: fn(Complex, Complex) -> EvalResult,
+    pub token: Token,
+}
+
+// The operation a BinaryOp does, without its operands
+#[derive(Debug, Clone)]
+pub struct Operator {
+    pub num_num: fn(f64, f64) -> EvalResult,
+    pub cplx_cplx: fn(Complex, Complex) -> EvalResult,
     pub token: Token,
 }
 
@@ -621,20 +1546,7 @@ impl Eval for BinaryOp {
     fn eval(&self, ctx: &mut Context) -> EvalResult {
         let vleft = self.lhs.eval(ctx)?;
         let vright = self.rhs.eval(ctx)?;
-
-        match vleft {
-            Value::NumValue(nl) => match vright {
-                Value::NumValue(nr) => return (self.num_num)(nl, nr),
-                Value::ValueList(list) => return self.num_list(nl, list, false),
-                _ => Err(ExecError::TypeMismatch),
-            },
-            Value::ValueList(list) => match vright {
-                Value::NumValue(nr) => return self.num_list(nr, list, true),
-                Value::ValueList(listr) => return self.list_list(list, listr),
-                _ => Err(ExecError::TypeMismatch),
-            },
-            _ => Err(ExecError::TypeMismatch),
-        }
+        self.operator().apply(vleft, vright, ctx)
     }
 
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
@@ -646,22 +1558,95 @@ impl Eval for BinaryOp {
             lhs: self.lhs.clone(),
             rhs: self.rhs.clone(),
             num_num: self.num_num,
+            cplx_cplx: self.cplx_cplx,
             token: self.token.clone(),
         })
     }
+
+    fn compile(&self, code: &mut Vec<Op>) {
+        self.lhs.compile(code);
+        self.rhs.compile(code);
+        code.push(Op::Binary(self.operator()));
+    }
+
+    fn fold(&mut self, ctx: &Context) -> Option<Value> {
+        let lhs = optimize::fold(&mut self.lhs, ctx);
+        let rhs = optimize::fold(&mut self.rhs, ctx);
+        optimize::constant(self.operator().apply(lhs?, rhs?, ctx))
+    }
 }
 
-impl BinaryOp {
-    fn num_list(&self, lhs: f64, list: Vec<Value>, swap: bool) -> EvalResult {
+impl Operator {
+    pub fn apply(&self, vleft: Value, vright: Value, ctx: &Context) -> EvalResult {
+        match vleft {
+            Value::ValueList(list) => match vright {
+                Value::ValueList(listr) => self.list_list(list, listr, ctx),
+                _ => self.scalar_list(vright, list, true, ctx),
+            },
+            _ => match vright {
+                Value::ValueList(list) => self.scalar_list(vleft, list, false, ctx),
+                _ => self.scalar_scalar(vleft, vright, ctx),
+            },
+        }
+    }
+
+    fn scalar_scalar(&self, lhs: Value, rhs: Value, ctx: &Context) -> EvalResult {
+        // apply the operation to two numbers, going complex if either of them is
+        if let (Value::StringValue(sl), Value::StringValue(sr)) = (&lhs, &rhs) {
+            // strings can only be joined and compared
+            return match self.token {
+                Token::Plus => Ok(Value::StringValue(format!("{}{}", sl, sr))),
+                Token::Equal => Ok(Value::bool(sl == sr)),
+                Token::NotEqual => Ok(Value::bool(sl != sr)),
+                _ => Err(ExecError::TypeMismatch),
+            };
+        }
+        if let (Value::NumValue(nl), Value::NumValue(nr)) = (&lhs, &rhs) {
+            let result = self.real_real(*nl, *nr, ctx)?;
+            match result {
+                // a real operation on real numbers with no real answer, eg (-1)^.5
+                Value::NumValue(n) if n.is_nan() && !nl.is_nan() && !nr.is_nan() => {
+                    if ctx.mode == NumberMode::Real {
+                        return Err(ExecError::NonRealAnswer);
+                    }
+                }
+                _ => return Ok(result),
+            }
+        }
+
+        match (lhs.as_complex(), rhs.as_complex()) {
+            (Some(cl), Some(cr)) => (self.cplx_cplx)(cl, cr),
+            _ => Err(ExecError::TypeMismatch),
+        }
+    }
+
+    fn real_real(&self, lhs: f64, rhs: f64, ctx: &Context) -> EvalResult {
+        match ctx.backend {
+            Backend::Float => (self.num_num)(lhs, rhs),
+            #[cfg(feature = "ti-float")]
+            Backend::TiFloat => match tifloat::binary(&self.token, lhs, rhs) {
+                Some(result) => result,
+                None => (self.num_num)(lhs, rhs),
+            },
+        }
+    }
+
+    fn scalar_list(
+        &self,
+        scalar: Value,
+        list: Vec<Value>,
+        swap: bool,
+        ctx: &Context,
+    ) -> EvalResult {
         // helper method to apply a binary operation to each element of a list
         let mut result: Vec<Value> = Vec::new();
-        for val in list.iter() {
+        for val in list.into_iter() {
             match val {
-                Value::NumValue(rhs) => {
+                Value::NumValue(_) | Value::ComplexValue(_) => {
                     if swap {
-                        result.push((self.num_num)(*rhs, lhs)?)
+                        result.push(self.scalar_scalar(val, scalar.clone(), ctx)?)
                     } else {
-                        result.push((self.num_num)(lhs, *rhs)?)
+                        result.push(self.scalar_scalar(scalar.clone(), val, ctx)?)
                     }
                 }
                 _ => return Err(ExecError::TypeMismatch),
@@ -671,36 +1656,47 @@ impl BinaryOp {
         Ok(Value::ValueList(result))
     }
 
-    fn list_list(&self, lhs_list: Vec<Value>, rhs_list: Vec<Value>) -> EvalResult {
+    fn list_list(&self, lhs_list: Vec<Value>, rhs_list: Vec<Value>, ctx: &Context) -> EvalResult {
         if lhs_list.len() != rhs_list.len() {
             return Err(ExecError::DimensionMismatch);
         }
 
         let mut result: Vec<Value> = Vec::new();
 
-        for i in 0..lhs_list.len() {
-            match lhs_list[i] {
-                Value::NumValue(lhs) => match rhs_list[i] {
-                    Value::NumValue(rhs) => result.push((self.num_num)(lhs, rhs)?),
-                    _ => (),
-                },
-                _ => (),
+        for (lhs, rhs) in lhs_list.into_iter().zip(rhs_list) {
+            if lhs.as_complex().is_some() && rhs.as_complex().is_some() {
+                result.push(self.scalar_scalar(lhs, rhs, ctx)?);
             }
         }
 
         Ok(Value::ValueList(result))
     }
+}
+
+impl BinaryOp {
+    pub fn operator(&self) -> Operator {
+        Operator {
+            num_num: self.num_num,
+            cplx_cplx: self.cplx_cplx,
+            token: self.token.clone(),
+        }
+    }
 
     pub fn or(lhs: ValRef, rhs: ValRef) -> BinaryOp {
         fn or(lhs: f64, rhs: f64) -> EvalResult {
             Ok(Value::bool(fb(lhs) || fb(rhs)))
         }
 
+        fn or_c(_: Complex, _: Complex) -> EvalResult {
+            Err(ExecError::TypeMismatch)
+        }
+
         BinaryOp {
             lhs: lhs,
             rhs: rhs,
             token: Token::Or,
             num_num: or,
+            cplx_cplx: or_c,
         }
     }
 
@@ -709,11 +1705,16 @@ impl BinaryOp {
             Ok(Value::bool(fb(lhs) ^ fb(rhs)))
         }
 
+        fn xor_c(_: Complex, _: Complex) -> EvalResult {
+            Err(ExecError::TypeMismatch)
+        }
+
         BinaryOp {
             lhs: lhs,
             rhs: rhs,
             token: Token::Xor,
             num_num: xor,
+            cplx_cplx: xor_c,
         }
     }
 
@@ -722,11 +1723,16 @@ impl BinaryOp {
             Ok(Value::bool(fb(lhs) && fb(rhs)))
         }
 
+        fn and_c(_: Complex, _: Complex) -> EvalResult {
+            Err(ExecError::TypeMismatch)
+        }
+
         BinaryOp {
             lhs: lhs,
             rhs: rhs,
             token: Token::And,
             num_num: and,
+            cplx_cplx: and_c,
         }
     }
 
@@ -735,11 +1741,16 @@ impl BinaryOp {
             Ok(Value::bool(lhs == rhs))
         }
 
+        fn equal_c(lhs: Complex, rhs: Complex) -> EvalResult {
+            Ok(Value::bool(lhs == rhs))
+        }
+
         BinaryOp {
             lhs: lhs,
             rhs: rhs,
             token: Token::Equal,
             num_num: equal,
+            cplx_cplx: equal_c,
         }
     }
 
@@ -748,11 +1759,16 @@ impl BinaryOp {
             Ok(Value::bool(lhs != rhs))
         }
 
+        fn not_equal_c(lhs: Complex, rhs: Complex) -> EvalResult {
+            Ok(Value::bool(lhs != rhs))
+        }
+
         BinaryOp {
             lhs: lhs,
             rhs: rhs,
             token: Token::NotEqual,
             num_num: not_equal,
+            cplx_cplx: not_equal_c,
         }
     }
 
@@ -761,11 +1777,17 @@ impl BinaryOp {
             Ok(Value::bool(lhs > rhs))
         }
 
+        // complex numbers have no ordering
+        fn greater_c(_: Complex, _: Complex) -> EvalResult {
+            Err(ExecError::TypeMismatch)
+        }
+
         BinaryOp {
             lhs: lhs,
             rhs: rhs,
             token: Token::Greater,
             num_num: greater,
+            cplx_cplx: greater_c,
         }
     }
 
@@ -774,11 +1796,16 @@ impl BinaryOp {
             Ok(Value::bool(lhs >= rhs))
         }
 
+        fn greater_equal_c(_: Complex, _: Complex) -> EvalResult {
+            Err(ExecError::TypeMismatch)
+        }
+
         BinaryOp {
             lhs: lhs,
             rhs: rhs,
             token: Token::GreaterEqual,
             num_num: greater_equal,
+            cplx_cplx: greater_equal_c,
         }
     }
 
@@ -787,11 +1814,16 @@ impl BinaryOp {
             Ok(Value::bool(lhs < rhs))
         }
 
+        fn less_c(_: Complex, _: Complex) -> EvalResult {
+            Err(ExecError::TypeMismatch)
+        }
+
         BinaryOp {
             lhs: lhs,
             rhs: rhs,
             token: Token::Less,
             num_num: less,
+            cplx_cplx: less_c,
         }
     }
 
@@ -800,11 +1832,16 @@ impl BinaryOp {
             Ok(Value::bool(lhs <= rhs))
         }
 
+        fn less_equal_c(_: Complex, _: Complex) -> EvalResult {
+            Err(ExecError::TypeMismatch)
+        }
+
         BinaryOp {
             lhs: lhs,
             rhs: rhs,
             token: Token::LessEqual,
             num_num: less_equal,
+            cplx_cplx: less_equal_c,
         }
     }
 
@@ -813,11 +1850,16 @@ impl BinaryOp {
             Ok(Value::NumValue(lhs + rhs))
         }
 
+        fn add_c(lhs: Complex, rhs: Complex) -> EvalResult {
+            Ok(Value::complex(lhs + rhs))
+        }
+
         BinaryOp {
             lhs: lhs,
             rhs: rhs,
             token: Token::Plus,
             num_num: add,
+            cplx_cplx: add_c,
         }
     }
 
@@ -826,11 +1868,16 @@ impl BinaryOp {
             Ok(Value::NumValue(lhs - rhs))
         }
 
+        fn minus_c(lhs: Complex, rhs: Complex) -> EvalResult {
+            Ok(Value::complex(lhs - rhs))
+        }
+
         BinaryOp {
             lhs: lhs,
             rhs: rhs,
             token: Token::Minus,
             num_num: minus,
+            cplx_cplx: minus_c,
         }
     }
 
@@ -839,11 +1886,16 @@ impl BinaryOp {
             Ok(Value::NumValue(lhs * rhs))
         }
 
+        fn mult_c(lhs: Complex, rhs: Complex) -> EvalResult {
+            Ok(Value::complex(lhs * rhs))
+        }
+
         BinaryOp {
             lhs: lhs,
             rhs: rhs,
             token: Token::Mult,
             num_num: mult,
+            cplx_cplx: mult_c,
         }
     }
 
@@ -856,11 +1908,19 @@ impl BinaryOp {
             }
         }
 
+        fn divide_c(lhs: Complex, rhs: Complex) -> EvalResult {
+            match lhs.checked_div(rhs) {
+                Some(c) => Ok(Value::complex(c)),
+                None => Err(ExecError::DivideByZero),
+            }
+        }
+
         BinaryOp {
             lhs: lhs,
             rhs: rhs,
             token: Token::Divide,
             num_num: divide,
+            cplx_cplx: divide_c,
         }
     }
 
@@ -869,11 +1929,19 @@ impl BinaryOp {
             Ok(Value::NumValue(lhs.powf(rhs)))
         }
 
+        fn power_c(lhs: Complex, rhs: Complex) -> EvalResult {
+            match lhs.pow(rhs) {
+                Some(c) => Ok(Value::complex(c)),
+                None => Err(ExecError::DivideByZero),
+            }
+        }
+
         BinaryOp {
             lhs: lhs,
             rhs: rhs,
             token: Token::Power,
             num_num: power,
+            cplx_cplx: power_c,
         }
     }
 }
@@ -899,7 +1967,7 @@ impl Eval for ExprList {
             let result = expr.eval(ctx)?;
             // make sure no one snuck in a non-numeric value in this
             match result {
-                Value::NumValue(_) => (),
+                Value::NumValue(_) | Value::ComplexValue(_) => (),
                 _ => return Err(ExecError::NonNumericTypeInList),
             }
             vals.push(result);
@@ -918,6 +1986,13 @@ impl Eval for ExprList {
             exprs: self.exprs.clone(),
         })
     }
+
+    fn fold(&mut self, ctx: &Context) -> Option<Value> {
+        for expr in self.exprs.iter_mut() {
+            optimize::fold(expr, ctx);
+        }
+        None
+    }
 }
 
 fn varidicify(func: fn(f64) -> EvalResult, vals: Vec<Value>) -> EvalResult {
@@ -961,25 +2036,43 @@ impl Eval for Not {
             val: self.val.clone(),
         })
     }
+
+    fn fold(&mut self, ctx: &Context) -> Option<Value> {
+        match optimize::fold(&mut self.val, ctx)? {
+            Value::NumValue(n) => Some(Value::bool(!fb(n))),
+            _ => None,
+        }
+    }
 }
 pub struct Negate {
     pub val: ValRef,
 }
 
-impl Eval for Negate {
-    fn eval(&self, ctx: &mut Context) -> EvalResult {
+impl Negate {
+    pub fn apply(val: Value) -> EvalResult {
         fn negate(n: f64) -> EvalResult {
             Ok(Value::NumValue(-1.0 * n))
         }
 
-        let val = self.val.eval(ctx)?;
-
         match val {
             Value::NumValue(n) => negate(n),
+            Value::ComplexValue(c) => Ok(Value::ComplexValue(-c)),
             Value::ValueList(l) => varidicify(negate, l),
             _ => Err(ExecError::TypeMismatch),
         }
     }
+}
+
+impl Eval for Negate {
+    fn eval(&self, ctx: &mut Context) -> EvalResult {
+        let val = self.val.eval(ctx)?;
+        Negate::apply(val)
+    }
+
+    fn compile(&self, code: &mut Vec<Op>) {
+        self.val.compile(code);
+        code.push(Op::Unary(Negate::apply));
+    }
 
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         write!(f, "Neg({:?})", self.val)
@@ -990,6 +2083,59 @@ impl Eval for Negate {
             val: self.val.clone(),
         })
     }
+
+    fn fold(&mut self, ctx: &Context) -> Option<Value> {
+        let val = optimize::fold(&mut self.val, ctx)?;
+        optimize::constant(Negate::apply(val))
+    }
+}
+
+pub struct SourceRead {
+    // getKey and startTmr, which read the keypad and the clock
+    pub token: Token,
+}
+
+impl Eval for SourceRead {
+    fn eval(&self, ctx: &mut Context) -> EvalResult {
+        match self.token {
+            Token::GetKey => Ok(Value::NumValue(ctx.sources.get_key())),
+            Token::StartTmr => Ok(Value::NumValue(ctx.sources.seconds())),
+            _ => Err(ExecError::NotYetImplemented),
+        }
+    }
+
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{:?}", self.token)
+    }
+
+    fn clone_expr(&self) -> Box<dyn Eval> {
+        Box::new(SourceRead {
+            token: self.token.clone(),
+        })
+    }
+}
+
+pub struct CheckTmr {
+    pub val: ValRef,
+}
+
+impl Eval for CheckTmr {
+    fn eval(&self, ctx: &mut Context) -> EvalResult {
+        match self.val.eval(ctx)? {
+            Value::NumValue(start) => Ok(Value::NumValue(ctx.sources.seconds() - start)),
+            _ => Err(ExecError::TypeMismatch),
+        }
+    }
+
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "CheckTmr({:?})", self.val)
+    }
+
+    fn clone_expr(&self) -> Box<dyn Eval> {
+        Box::new(CheckTmr {
+            val: self.val.clone(),
+        })
+    }
 }
 
 pub fn execute(program: &mut Program) -> Result<(), ExecError> {
@@ -1002,7 +2148,11 @@ mod tests {
     use crate::lexer::*;
 
     fn exec_str(input: String) -> Value {
-        let mut program = Program::new();
+        exec_with(input, &Options::new())
+    }
+
+    fn exec_with(input: String, options: &Options) -> Value {
+        let mut program = Program::with_options(options);
         match parse(&lex_str(&input), &mut program) {
             Result::Ok(_) => (),
             Result::Err(err) => panic!("{:?} oops", err),
@@ -1030,6 +2180,16 @@ mod tests {
         assert_eq!(exec("2/4\n"), 0.5);
         assert_eq!(exec("--2\n"), -2.0);
         assert_eq!(exec("2^4\n"), 16.0);
+        // implied multiplication
+        assert_eq!(exec("2(3)4\n"), 24.0);
+        assert_eq!(exec("6/2(3)\n"), 9.0);
+        assert_eq!(exec("3->A\n2A+A(A+1)\n"), 18.0);
+        assert_eq!(exec("2ᴇ3\n"), 2000.0);
+        // ASCII - is negation where a value starts
+        assert_eq!(exec("-2^2\n"), -4.0);
+        assert_eq!(exec("3->A\nA*-2\n"), -6.0);
+        assert_eq!(exec("1-(-1)\n"), 2.0);
+        assert_eq!(exec("2^-1\n"), 0.5);
         // todo: validate the statements in program
     }
 
@@ -1802,7 +2962,7 @@ mod tests {
             exec(
                 "
         7 -> A
-        IS<(A, 6
+        IS>(A, 6
         0 -> A
         A
     "
@@ -1814,7 +2974,7 @@ mod tests {
             exec(
                 "
         1 -> B
-        IS<(B, 2)
+        IS>(B, 2)
         0 -> B
         B
     "
@@ -1823,7 +2983,660 @@ mod tests {
         );
     }
 
-    // Testing Todo:
-    // - Ans is going to have a huge testing burden
-    // - Test Ans interactions with IS< and DS<
+    #[test]
+    fn test_skip_elements() {
+        assert_eq!(exec("{1,5}->L1\nIS>(L1(2),5)\n0->L1(2)\nL1(2)\n"), 6.0);
+        assert_eq!(exec("{1,5}->L1\nDS<(L1(1),1)\n7->L1(1)\nL1(1)\n"), 0.0);
+        let mut options = Options::new();
+        options.preload = vec![(
+            Variable::MatrixVar('A'),
+            Value::MatrixValue(vec![vec![1.0, 2.0], vec![3.0, 4.0]]),
+        )];
+        assert_eq!(
+            exec_with(String::from("IS>([A](2,1),9\n0->[A](1,1)\n[A]\n"), &options),
+            Value::MatrixValue(vec![vec![0.0, 2.0], vec![4.0, 4.0]])
+        );
+        assert_eq!(exec_with(String::from("[A](2,1\n"), &options), 3.0);
+        // nothing to skip
+        assert_eq!(exec_err("1->A\nIS>(A,0)\n"), ExecError::SyntaxError);
+        // the old spelling still works
+        assert_eq!(exec("1->A\nIS<(A,0)\n5->A\nA\n"), 2.0);
+    }
+
+    #[test]
+    fn test_elements() {
+        assert_eq!(exec("{4,5,6}->L1\nL1(2)+L1(3\n"), 11.0);
+        // one past the end grows the list
+        assert_eq!(
+            exec("{4}->L1\n7->L1(2)\nL1\n"),
+            Value::ValueList(vec![Value::NumValue(4.0), Value::NumValue(7.0)])
+        );
+        assert_eq!(
+            exec("3->|LNEW(1)\n|LNEW\n"),
+            Value::ValueList(vec![Value::NumValue(3.0)])
+        );
+        assert_eq!(exec_err("{4}->L1\n7->L1(3)\n"), ExecError::InvalidDim);
+        assert_eq!(exec_err("{4}->L1\nL1(0)\n"), ExecError::Domain);
+        assert_eq!(exec_err("{4}->L1\n\"A\"->L1(1)\n"), ExecError::TypeMismatch);
+        assert_eq!(exec_err("[A](1,1)\n"), ExecError::Undefined);
+    }
+
+    #[test]
+    fn test_block_leaks() {
+        let leaky = "0->I\nLbl A\nI+1->I\nWhile 1\nIf I<50\nGoto A\nGoto B\nEnd\nLbl B\nI\n";
+        assert_eq!(exec(leaky), 50.0);
+        let mut options = Options::new();
+        options.block_limit = Some(10);
+        let mut program = Program::with_options(&options);
+        parse(&lex_str(leaky), &mut program).unwrap();
+        assert_eq!(execute(&mut program).unwrap_err(), ExecError::Memory);
+        assert_eq!(program.blockstack.len(), 10);
+
+        // jumping around inside a block doesn't leak
+        let fine = "0->I\nFor(J,1,100)\nLbl A\nI+1->I\nIf I<5\nGoto A\nEnd\nI\n";
+        assert_eq!(exec_with(String::from(fine), &options), 104.0);
+    }
+
+    #[test]
+    fn test_block_end() {
+        let mut program = Program::with_options(&Options::new());
+        parse(
+            &lex_str("While 1\nIf 1\nDisp 1\nIf 1\nThen\nGoto B\nEnd\nEnd\nLbl B\n"),
+            &mut program,
+        )
+        .unwrap();
+        // an If without a Then isn't a block
+        assert_eq!(program.block_end(0), 7);
+        assert_eq!(program.block_end(3), 6);
+    }
+
+    #[test]
+    fn test_jump_cache() {
+        // skipping the same block over and over lands in the same place
+        assert_eq!(
+            exec("0->S\nFor(I,1,10)\nIf I>5\nThen\nFor(J,1,2)\nS+1->S\nEnd\nEnd\nEnd\nS\n"),
+            10.0
+        );
+        // End can still be turned off
+        assert_eq!(
+            exec("1->A\n0->B\nIf 1\nThen\nLbl A\nB+1->B\nIf A\nEnd\n0->A\nIf B<3\nGoto A\nB\n"),
+            3.0
+        );
+
+        // a scan that runs off the end isn't remembered, more statements can
+        // still show up (like in the REPL)
+        let mut program = Program::with_options(&Options::new());
+        parse(&lex_str("If 0\nThen\n1->A\n"), &mut program).unwrap();
+        assert_eq!(execute(&mut program).unwrap_err(), ExecError::UnexpectedEof);
+        parse(&lex_str("End\n2->A\nA\n"), &mut program).unwrap();
+        program.pc = 0;
+        program.blockstack.clear();
+        execute(&mut program).unwrap();
+        assert_eq!(program.ctx.ans, 2.0);
+    }
+
+    fn exec_limited(input: &str, limits: Limits) -> (Program, ExecError) {
+        let mut options = Options::new();
+        options.limits = limits;
+        let mut program = Program::with_options(&options);
+        parse(&lex_str(input), &mut program).unwrap();
+        let err = execute(&mut program).unwrap_err();
+        (program, err)
+    }
+
+    #[test]
+    fn test_limits() {
+        let limits = Limits {
+            steps: Some(10),
+            ..Limits::default()
+        };
+        let (mut program, err) = exec_limited("0->A\nLbl A\nA+1->A\nGoto A\n", limits.clone());
+        assert_eq!(err, ExecError::StepLimit(10));
+        assert_eq!(program.ctx.get(&Variable::RealVar('A')).unwrap(), 4.0);
+        // counted again every time execute is called
+        let (mut program, _) = exec_limited("1\n2\n3\n4\n5\n6\n7\n8\n9\n10\n11\n", limits);
+        execute(&mut program).unwrap();
+        assert_eq!(program.ctx.ans, 11.0);
+
+        let limits = Limits {
+            time: Some(Duration::from_millis(50)),
+            ..Limits::default()
+        };
+        let (_, err) = exec_limited("While 1\nEnd\n", limits.clone());
+        assert_eq!(err, ExecError::TimeLimit(Duration::from_millis(50)));
+        // also in the middle of one long statement
+        let (_, err) = exec_limited("Σ(X,X,1,9999999)\n", limits);
+        assert_eq!(err, ExecError::TimeLimit(Duration::from_millis(50)));
+
+        let limits = Limits {
+            output: Some(6),
+            ..Limits::default()
+        };
+        let (program, err) = exec_limited("Disp 1\nDisp 22\nDisp 333\n", limits);
+        assert_eq!(err, ExecError::OutputLimit(6));
+        assert_eq!(program.pc, 2);
+
+        let limits = Limits {
+            size: Some(3),
+            ..Limits::default()
+        };
+        let (_, err) = exec_limited("{1,2,3}->L1\n4->L1(4)\n", limits.clone());
+        assert_eq!(err, ExecError::SizeLimit(3));
+        let (_, err) = exec_limited("\"AB\"->Str1\nStr1+Str1->Str1\n", limits.clone());
+        assert_eq!(err, ExecError::SizeLimit(3));
+        let (_, err) = exec_limited("\"AB\"\nAns+Ans\n", limits);
+        assert_eq!(err, ExecError::SizeLimit(3));
+
+        // recursion isn't counted in steps or caught by the clock, it has to
+        // stop on its own before the stack runs out
+        let limits = Limits {
+            steps: Some(1000),
+            time: Some(Duration::from_secs(2)),
+            ..Limits::default()
+        };
+        let (_, err) = exec_limited("\"Y1+1\"->Y1\nY1\n", limits);
+        assert_eq!(err, ExecError::Memory);
+        assert_eq!(err.exit_code(), 7);
+
+        assert_eq!(ExecError::StepLimit(10).exit_code(), 3);
+        assert_eq!(ExecError::SizeLimit(3).exit_code(), 6);
+        assert_eq!(ExecError::DivideByZero.exit_code(), 1);
+        assert_eq!(
+            ExecError::OutputLimit(6).describe(),
+            "printed more than 6 bytes (--max-output)"
+        );
+    }
+
+    #[test]
+    fn test_trace_line() {
+        let source = "1->A\nIf A:  Disp A\n";
+        let (tokens, lines) = lex_lines(&String::from(source)).unwrap();
+        let mut program = Program::with_options(&Options::new());
+        parse_lines(&tokens, &lines, &mut program).unwrap();
+        // no source, so the parsed statement
+        assert!(program
+            .trace_line(0, "Ans = 1")
+            .starts_with("trace: line 1: Expression"));
+        program.source = source.lines().map(String::from).collect();
+        assert_eq!(
+            program.trace_line(0, "Ans = 1"),
+            "trace: line 1: 1->A  Ans = 1"
+        );
+        // the whole line, even for the second statement on it
+        assert_eq!(program.trace_line(2, ""), "trace: line 2: If A:  Disp A  ");
+    }
+
+    #[test]
+    fn test_break() {
+        let mut program = Program::with_options(&Options::new());
+        parse(&lex_str("5->A\nWhile 1\nEnd\n"), &mut program).unwrap();
+        program.ctx.interrupt.store(true, Ordering::Relaxed);
+        assert_eq!(execute(&mut program).unwrap_err(), ExecError::Break);
+        assert_eq!(program.pc, 0);
+
+        // from another thread while the program is stuck in a loop
+        let interrupt = program.ctx.interrupt.clone();
+        let presser = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(20));
+            interrupt.store(true, Ordering::Relaxed);
+        });
+        assert_eq!(execute(&mut program).unwrap_err(), ExecError::Break);
+        presser.join().unwrap();
+        // the variables are still there
+        assert_eq!(program.ctx.get(&Variable::RealVar('A')).unwrap(), 5.0);
+        assert_eq!(ExecError::Break.describe(), "ERR:BREAK");
+
+        // and in the middle of one long statement
+        let mut program = Program::with_options(&Options::new());
+        parse(&lex_str("Σ(X,X,1,9999999)\n"), &mut program).unwrap();
+        let interrupt = program.ctx.interrupt.clone();
+        let presser = std::thread::spawn(move || {
+            std::thread::sleep(Duration::from_millis(20));
+            interrupt.store(true, Ordering::Relaxed);
+        });
+        assert_eq!(execute(&mut program).unwrap_err(), ExecError::Break);
+        presser.join().unwrap();
+    }
+
+    fn deterministic(seed: u64) -> Options {
+        let mut options = Options::new();
+        options.deterministic = true;
+        options.seed = seed;
+        options
+    }
+
+    #[test]
+    fn test_deterministic_uninitialized_vars() {
+        let program = String::from("A+B\n");
+        let first = exec_with(program.clone(), &deterministic(7));
+        assert_eq!(exec_with(program.clone(), &deterministic(7)), first);
+        assert!(exec_with(program, &deterministic(8)) != first);
+    }
+
+    #[test]
+    fn test_deterministic_rand() {
+        let program = String::from("rand+rand\n");
+        let first = exec_with(program.clone(), &deterministic(1));
+        assert_eq!(exec_with(program, &deterministic(1)), first);
+
+        // seeding through rand resets the sequence
+        assert_eq!(
+            exec_with(
+                String::from("5->rand\nrand->A\n5->rand\nrand=A\n"),
+                &Options::new()
+            ),
+            true
+        );
+    }
+
+    #[test]
+    fn test_deterministic_clock_and_keys() {
+        let mut options = deterministic(0);
+        options.keys = vec![105.0];
+        assert_eq!(
+            exec_with(String::from("startTmr->T\ncheckTmr(T)\n"), &options),
+            1.0
+        );
+        assert_eq!(exec_with(String::from("getKey\n"), &options), 105.0);
+        assert_eq!(exec_with(String::from("getKey\ngetKey\n"), &options), 0.0);
+    }
+
+    #[test]
+    fn test_ram_clear_vars() {
+        let mut options = Options::new();
+        options.uninit = Uninit::Zero;
+        assert_eq!(exec_with(String::from("A+B\n"), &options), 0.0);
+    }
+
+    fn exec_err(input: &str) -> ExecError {
+        let mut program = Program::with_options(&Options::new());
+        parse(&lex_str(input), &mut program).unwrap();
+        execute(&mut program).unwrap_err()
+    }
+
+    fn complex(re: f64, im: f64) -> Value {
+        Value::ComplexValue(Complex::new(re, im))
+    }
+
+    #[test]
+    fn test_nonreal_answer_in_real_mode() {
+        assert_eq!(exec_err("sqrt(--1)\n"), ExecError::NonRealAnswer);
+        assert_eq!(exec_err("(--1)^.5\n"), ExecError::NonRealAnswer);
+        assert_eq!(exec("sqrt(4)\n"), 2.0);
+    }
+
+    #[test]
+    fn test_complex_mode() {
+        assert_eq!(exec("a+bi\nsqrt(--1)\n"), complex(0.0, 1.0));
+        assert_eq!(exec("a+bi\n(--4)^.5\n"), complex(0.0, 2.0));
+        assert_eq!(exec("re^θi\n√(--9)\n"), complex(0.0, 3.0));
+    }
+
+    #[test]
+    fn test_complex_arithmetic() {
+        assert_eq!(exec("i*i\n"), -1.0);
+        assert_eq!(exec("(2+3i)+(1-i)\n"), complex(3.0, 2.0));
+        assert_eq!(exec("(2+3i)-(2+i)\n"), complex(0.0, 2.0));
+        assert_eq!(exec("(1+i)*(1-i)\n"), 2.0);
+        assert_eq!(exec("(4+2i)/(1+i)\n"), complex(3.0, -1.0));
+        assert_eq!(exec("i^4\n"), 1.0);
+        assert_eq!(exec("--(1+i)\n"), complex(-1.0, -1.0));
+        assert_eq!(exec("1+i=1+i\n"), true);
+        assert_eq!(exec("1+i!=1\n"), true);
+        assert_eq!(exec_err("i>1\n"), ExecError::TypeMismatch);
+        assert_eq!(exec_err("i and 1\n"), ExecError::TypeMismatch);
+    }
+
+    #[test]
+    fn test_complex_functions() {
+        assert_eq!(exec("real(3+4i)\n"), 3.0);
+        assert_eq!(exec("imag(3+4i)\n"), 4.0);
+        assert_eq!(exec("conj(3+4i)\n"), complex(3.0, -4.0));
+        assert_eq!(exec("abs(3+4i)\n"), 5.0);
+        assert_eq!(exec("abs(--3)\n"), 3.0);
+        assert_eq!(exec("angle(i)\n"), std::f64::consts::FRAC_PI_2);
+        assert_eq!(exec("angle(--1)\n"), std::f64::consts::PI);
+    }
+
+    #[test]
+    fn test_complex_lists() {
+        assert_eq!(
+            format!("{}", exec("{1,i}*i\n")),
+            format!(
+                "{}",
+                Value::ValueList(vec![complex(0.0, 1.0), Value::NumValue(-1.0)])
+            )
+        );
+    }
+
+    #[test]
+    fn test_complex_format() {
+        let mut ctx = Context::new(&Options::new());
+        let val = complex(0.0, 2.0);
+        assert_eq!(ctx.format(&val), "2i");
+        ctx.mode = NumberMode::Polar;
+        assert_eq!(ctx.format(&val), "2e^(1.5707963267948966i)");
+        assert_eq!(ctx.format(&Value::NumValue(2.0)), "2");
+    }
+
+    #[test]
+    fn test_float_backend() {
+        // f64 artifacts are the default
+        assert_eq!(exec("0.1+0.2=0.3\n"), false);
+    }
+
+    #[cfg(feature = "ti-float")]
+    fn exec_ti(input: &str) -> Value {
+        let mut options = Options::new();
+        options.backend = Backend::TiFloat;
+        exec_with(String::from(input), &options)
+    }
+
+    #[cfg(feature = "ti-float")]
+    #[test]
+    fn test_ti_float_backend() {
+        assert_eq!(exec_ti("0.1+0.2=0.3\n"), true);
+        assert_eq!(exec_ti("0.1+0.2\n"), 0.3);
+        assert_eq!(exec_ti("(1/3)*3=1\n"), true);
+        assert_eq!(exec_ti("1/3\n"), 0.33333333333333);
+        assert_eq!(exec_ti("{0.1,0.2}+0.2\n").to_string(), "{0.3 0.4}");
+    }
+
+    #[cfg(feature = "ti-float")]
+    #[test]
+    fn test_ti_float_overflow() {
+        let mut options = Options::new();
+        options.backend = Backend::TiFloat;
+        let mut program = Program::with_options(&options);
+        parse(&lex_str("e99*10\n"), &mut program).unwrap();
+        assert_eq!(execute(&mut program), Err(ExecError::Overflow));
+    }
+
+    #[cfg(feature = "ti-float")]
+    #[test]
+    fn test_ti_float_non_real() {
+        let mut options = Options::new();
+        options.backend = Backend::TiFloat;
+        let mut program = Program::with_options(&options);
+        parse(&lex_str("(--1)^.5\n"), &mut program).unwrap();
+        assert_eq!(execute(&mut program), Err(ExecError::NonRealAnswer));
+        assert_eq!(exec_ti("a+bi\n(--4)^.5\n"), complex(0.0, 2.0));
+    }
+
+    fn assert_close(val: Value, expected: f64) {
+        match val {
+            Value::NumValue(n) => assert!(
+                (n - expected).abs() < 1e-6,
+                "{} is not close to {}",
+                n,
+                expected
+            ),
+            _ => panic!("{:?} is not a number", val),
+        }
+    }
+
+    #[test]
+    fn test_solve() {
+        assert_close(exec("solve(X^2-2,X,1)\n"), 2f64.sqrt());
+        assert_close(exec("solve(X^2-2,X,--1)\n"), -(2f64.sqrt()));
+        assert_close(exec("solve(X^3-(X+1),X,1.5,{1,2})\n"), 1.324717957244746);
+        assert_close(exec("solve(X^2-9,X,{0,10})\n"), 3.0);
+        assert_close(exec("3->A\nsolve(A*X-6,X,0)\n"), 2.0);
+        assert_eq!(exec_err("solve(X-5,X,0,{1,2})\n"), ExecError::Domain);
+        assert_eq!(exec_err("solve(X^2+1,X,0)\n"), ExecError::NoSignChange);
+    }
+
+    #[test]
+    fn test_n_deriv() {
+        assert_close(exec("nDeriv(X^2,X,3)\n"), 6.0);
+        assert_close(exec("nDeriv(X^3,X,2,0.0001)\n"), 12.0);
+    }
+
+    #[test]
+    fn test_fn_int() {
+        assert_close(exec("fnInt(X^2,X,0,3)\n"), 9.0);
+        assert_close(exec("fnInt(1/X,X,1,e1)\n"), 10f64.ln());
+        assert_close(exec("fnInt(X,X,2,2)\n"), 0.0);
+    }
+
+    #[test]
+    fn test_f_min_max() {
+        assert_close(exec("fMin((X-2)^2,X,0,5)\n"), 2.0);
+        assert_close(exec("fMax(--(X-1)^2+4,X,--5,5)\n"), 1.0);
+        assert_eq!(exec_err("fMin(X,X,5,0)\n"), ExecError::Domain);
+        assert_eq!(
+            exec_err("fMin(X^2,X,10^20,2*10^20)\n"),
+            ExecError::ToleranceNotMet
+        );
+    }
+
+    #[test]
+    fn test_sigma() {
+        assert_eq!(exec("Σ(X^2,X,1,4)\n"), 30.0);
+        assert_eq!(exec("Σ(1,X,1,0)\n"), 0.0);
+        assert_eq!(exec_err("Σ(X,X,1,10^15)\n"), ExecError::Domain);
+        assert_eq!(exec_err("Σ(X,X,10^20,10^20+5)\n"), ExecError::Domain);
+    }
+
+    #[test]
+    fn test_calculus_restores_variable() {
+        assert_eq!(exec("5->X\nnDeriv(X^2,X,3)\nX\n"), 5.0);
+        assert_eq!(exec("5->X\nΣ(X,X,1,3)+X\n"), 11.0);
+    }
+
+    fn string(s: &str) -> Value {
+        Value::StringValue(String::from(s))
+    }
+
+    #[test]
+    fn test_strings() {
+        assert_eq!(exec("\"HELLO\"->Str1\nStr1\n"), string("HELLO"));
+        assert_eq!(exec("\"AB\"+\"CD\"\n"), string("ABCD"));
+        assert_eq!(exec("\"AB\"=\"AB\"\n"), true);
+        assert_eq!(exec("\"AB\"!=\"AB\"\n"), false);
+        assert_eq!(exec_err("Str2\n"), ExecError::Undefined);
+        assert_eq!(exec_err("1->Str1\n"), ExecError::TypeMismatch);
+        assert_eq!(exec_err("\"A\"-\"B\"\n"), ExecError::TypeMismatch);
+    }
+
+    #[test]
+    fn test_sub_in_str_length() {
+        assert_eq!(exec("sub(\"HELLO\",2,3)\n"), string("ELL"));
+        assert_eq!(exec("sub(\"0123456789\",5+1,1)\n"), string("5"));
+        assert_eq!(exec("inStr(\"HELLO\",\"L\")\n"), 3.0);
+        assert_eq!(exec("inStr(\"HELLO\",\"L\",4)\n"), 4.0);
+        assert_eq!(exec("inStr(\"HELLO\",\"Z\")\n"), 0.0);
+        assert_eq!(exec("length(\"HELLO\")\n"), 5.0);
+        assert_eq!(exec("length(\"θ\")\n"), 1.0);
+        assert_eq!(exec_err("sub(\"HELLO\",4,3)\n"), ExecError::InvalidDim);
+        assert_eq!(exec_err("sub(\"HI\",10^20,10^20)\n"), ExecError::InvalidDim);
+        assert_eq!(exec_err("sub(\"HI\",1,10^20)\n"), ExecError::InvalidDim);
+        assert_eq!(exec_err("sub(\"HELLO\",0,1)\n"), ExecError::Domain);
+        assert_eq!(exec_err("sub(\"HELLO\",1.5,1)\n"), ExecError::Domain);
+        assert_eq!(exec_err("inStr(\"HI\",\"H\",4)\n"), ExecError::InvalidDim);
+    }
+
+    #[test]
+    fn test_expr_and_to_string() {
+        assert_eq!(exec("expr(\"2+3*4\")\n"), 14.0);
+        assert_eq!(exec("7->X\nexpr(\"X*2\")\n"), 14.0);
+        assert_eq!(exec("toString(1.5)\n"), string("1.5"));
+        assert_eq!(exec("expr(toString(42))\n"), 42.0);
+        assert_eq!(exec("eval(\"6/2\")\n"), 3.0);
+        assert_eq!(exec_err("expr(\"2+\")\n"), ExecError::SyntaxError);
+        assert_eq!(exec_err("expr(\"1,2\")\n"), ExecError::SyntaxError);
+        // a string that evaluates itself runs out of memory, not stack
+        assert_eq!(
+            exec_err("\"expr(Str1)\"->Str1\nexpr(Str1)\n"),
+            ExecError::Memory
+        );
+        // but nesting that stops is fine, and the count goes back down
+        assert_eq!(exec("\"expr(Str2)\"->Str1\n\"2\"->Str2\nexpr(Str1)\n"), 2.0);
+    }
+
+    #[test]
+    fn test_equations() {
+        assert_eq!(exec("\"X^2\"->Y1\n3->X\nY1\n"), 9.0);
+        assert_eq!(
+            exec("\"X+1\"->Y1\nEqu>String(Y1,Str1)\nStr1\n"),
+            string("X+1")
+        );
+        assert_eq!(exec("\"2*X\"->Str3\nString>Equ(Str3,Y2)\n4->X\nY2\n"), 8.0);
+        assert_eq!(exec_err("Equ>String(Y1,Str1)\n"), ExecError::Undefined);
+        assert_eq!(exec_err("\"Y1+1\"->Y1\nY1\n"), ExecError::Memory);
+    }
+
+    #[test]
+    fn test_list_and_matrix_vars() {
+        assert_eq!(
+            exec("{1,2}->L1\nL1*2\n"),
+            Value::ValueList(vec![Value::NumValue(2.0), Value::NumValue(4.0)])
+        );
+        assert_eq!(exec_err("L2\n"), ExecError::Undefined);
+        assert_eq!(exec_err("1->L1\n"), ExecError::TypeMismatch);
+        assert_eq!(exec_err("[A]\n"), ExecError::Undefined);
+    }
+
+    #[test]
+    fn test_preload() {
+        let mut options = Options::new();
+        options.preload = vec![
+            (Variable::RealVar('A'), Value::NumValue(3.0)),
+            (
+                Variable::MatrixVar('B'),
+                Value::MatrixValue(vec![vec![1.0, 2.0]]),
+            ),
+        ];
+        assert_eq!(exec_with(String::from("A+1\n"), &options), 4.0);
+        assert_eq!(
+            exec_with(String::from("[B]\n"), &options),
+            Value::MatrixValue(vec![vec![1.0, 2.0]])
+        );
+    }
+
+    #[test]
+    fn test_square_and_inverse() {
+        assert_eq!(exec("3²\n"), 9.0);
+        assert_eq!(exec("4⁻¹\n"), 0.25);
+        assert_eq!(exec("2²²\n"), 16.0);
+        assert_eq!(exec("1+2²*3\n"), 13.0);
+        assert_eq!(exec("⁻2²\n"), -4.0);
+    }
+
+    #[test]
+    fn test_archive() {
+        assert_eq!(exec("5->A\nArchive A\nUnArchive A\nA+1\n"), 6.0);
+        assert_eq!(exec_err("5->A\nArchive A\nA\n"), ExecError::Archived);
+        assert_eq!(exec_err("5->A\nArchive A\n1->A\n"), ExecError::Archived);
+        assert_eq!(exec_err("Archive Str1\n"), ExecError::Undefined);
+        assert_eq!(
+            exec_err("{1,2}->L1\nArchive L1\nDisp L1\n"),
+            ExecError::Archived
+        );
+        // DelVar gets rid of archived variables too
+        assert_eq!(exec("5->A\nArchive A\nDelVar A\n2->A\nA\n"), 2.0);
+        assert_eq!(
+            exec_err("\"HI\"->Str1\nDelVar Str1\nStr1\n"),
+            ExecError::Undefined
+        );
+
+        let mut options = Options::new();
+        options.preload = vec![(Variable::RealVar('H'), Value::NumValue(100.0))];
+        options.archived = vec![Variable::RealVar('H')];
+        assert_eq!(exec_with(String::from("UnArchive H\nH\n"), &options), 100.0);
+    }
+
+    #[test]
+    fn test_delvar() {
+        // deleted reals read as 0, not as uninitialized memory
+        let mut options = Options::new();
+        options.deterministic = true;
+        assert_eq!(
+            exec_with(String::from("5->A\nDelVar A\nA\n"), &options),
+            0.0
+        );
+        assert_eq!(exec_err("{1,2}->L1\nDelVar L1\nL1\n"), ExecError::Undefined);
+        // no colon needed after DelVar
+        assert_eq!(exec("5->A\n2->B\nDelVar ADelVar BA+B+1\n"), 1.0);
+        assert_eq!(exec("5->A\nDelVar A3->A\nA\n"), 3.0);
+    }
+
+    #[test]
+    fn test_clr_list() {
+        assert_eq!(
+            exec("{1,2}->L1\n{3}->L2\nClrList L1,L2\nL2\n"),
+            Value::ValueList(vec![])
+        );
+        assert_eq!(
+            exec("{1,2}->L1\n{3}->L2\nArchive L2\nClrAllLists\nUnArchive L2\nL2\n"),
+            Value::ValueList(vec![Value::NumValue(3.0)])
+        );
+        assert_eq!(
+            exec("{1,2}->L1\nClrAllLists\nL1\n"),
+            Value::ValueList(vec![])
+        );
+        assert_eq!(
+            exec_err("{1}->L1\nArchive L1\nClrList L1\n"),
+            ExecError::Archived
+        );
+    }
+
+    fn run_scripted(input: &str, inputs: &[&str]) -> Program {
+        let mut options = Options::new();
+        options.inputs = inputs.iter().map(|line| line.to_string()).collect();
+        let mut program = Program::with_options(&options);
+        parse(&lex_str(input), &mut program).unwrap();
+        execute(&mut program).unwrap();
+        program
+    }
+
+    #[test]
+    fn test_ans() {
+        // storing is an expression, so it sets Ans
+        assert_eq!(exec("5->A\nAns+1\n"), 6.0);
+        assert_eq!(exec("{1,2}*2->L1\nAns\n"), exec("{2,4}\n"));
+        assert_eq!(
+            exec("\"AB\"->Str1\nAns+\"C\"\n"),
+            Value::StringValue(String::from("ABC"))
+        );
+        // commands leave it alone
+        assert_eq!(exec("2\nDisp 3\nAns\n"), 2.0);
+        assert_eq!(exec("2\nOutput(1,1,3)\nAns\n"), 2.0);
+        assert_eq!(exec("2\nFor(I,1,3)\nEnd\nAns\n"), 2.0);
+        assert_eq!(exec("0->A\n2\nIS>(A,5)\nDisp 1\nAns\n"), 2.0);
+        assert_eq!(exec("0->A\n2\nDS<(A,5)\n7\nAns\n"), 2.0);
+        let program = run_scripted("2\nInput A\nPrompt B,Str1\n", &["3", "4", "HI"]);
+        assert_eq!(program.ctx.ans, 2.0);
+
+        // what the home screen shows at the end
+        assert_eq!(run_scripted("1+1\n", &[]).result(), "2");
+        assert_eq!(run_scripted("{1,2}->L1\n", &[]).result(), "{1 2}");
+        assert_eq!(run_scripted("1+1\nDisp 3\n", &[]).result(), "Done");
+        assert_eq!(run_scripted("1->A\nIf A\nStop\n2\n", &[]).result(), "Done");
+        assert_eq!(run_scripted("", &[]).result(), "Done");
+    }
+
+    #[test]
+    fn test_input_output() {
+        let mut program = run_scripted(
+            "Input A\nInput \"N=\",B\nPrompt C,Str1\n",
+            &["1+2", "4", "5", "1+2"],
+        );
+        assert_eq!(program.ctx.get(&Variable::RealVar('A')).unwrap(), 3.0);
+        assert_eq!(program.ctx.get(&Variable::RealVar('B')).unwrap(), 4.0);
+        assert_eq!(program.ctx.get(&Variable::RealVar('C')).unwrap(), 5.0);
+        // strings are taken as typed
+        assert_eq!(
+            program.ctx.get(&Variable::StrVar(1)).unwrap(),
+            Value::StringValue(String::from("1+2"))
+        );
+        assert_eq!(exec_err("Output(9,1,\"HI\")\n"), ExecError::Domain);
+        assert_eq!(exec_err("Output(1,17,\"HI\")\n"), ExecError::Domain);
+
+        // a bare Input is for the graph screen, and Ans can't be typed into
+        let mut program = Program::with_options(&Options::new());
+        assert!(parse(&lex_str("Input\n"), &mut program).is_err());
+        assert!(parse(&lex_str("Input Ans\n"), &mut program).is_err());
+        assert!(parse(&lex_str("Prompt A,\n"), &mut program).is_err());
+    }
 }
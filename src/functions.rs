@@ -0,0 +1,228 @@
+use crate::complex::Complex;
+use crate::executor::*;
+use crate::lexer::Token;
+use crate::optimize;
+use crate::parser::Value;
+use crate::vm::Op;
+use std::ops::RangeInclusive;
+
+// Built in functions that precede their arguments, like sqrt( and abs(. Each
+// one gets its arguments already evaluated, functions that need to see their
+// arguments unevaluated need their own node.
+
+pub type FuncImpl = fn(&mut Context, Vec<Value>) -> EvalResult;
+
+pub struct Func {
+    pub token: Token,
+    pub args: Vec<ValRef>,
+    pub func: FuncImpl,
+}
+
+impl Eval for Func {
+    fn eval(&self, ctx: &mut Context) -> EvalResult {
+        let mut args: Vec<Value> = Vec::new();
+        for arg in self.args.iter() {
+            args.push(arg.eval(ctx)?);
+        }
+        (self.func)(ctx, args)
+    }
+
+    fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
+        write!(f, "{:?}{:?}", self.token, self.args)
+    }
+
+    fn clone_expr(&self) -> Box<dyn Eval> {
+        Box::new(Func {
+            token: self.token.clone(),
+            args: self.args.clone(),
+            func: self.func,
+        })
+    }
+
+    fn compile(&self, code: &mut Vec<Op>) {
+        for arg in self.args.iter() {
+            arg.compile(code);
+        }
+        code.push(Op::Call(self.func, self.args.len()));
+    }
+
+    fn fold(&mut self, ctx: &Context) -> Option<Value> {
+        // functions can read variables, the clock and the mode, so only their
+        // arguments are folded
+        for arg in self.args.iter_mut() {
+            optimize::fold(arg, ctx);
+        }
+        None
+    }
+}
+
+pub fn lookup(token: &Token) -> Option<(FuncImpl, RangeInclusive<usize>)> {
+    // the implementation of a function, and how many arguments it takes
+    match token {
+        Token::Sqrt => Some((sqrt, 1..=1)),
+        Token::Abs => Some((abs, 1..=1)),
+        Token::RealPart => Some((real, 1..=1)),
+        Token::ImagPart => Some((imag, 1..=1)),
+        Token::Conj => Some((conj, 1..=1)),
+        Token::Angle => Some((angle, 1..=1)),
+        Token::Sub => Some((sub, 3..=3)),
+        Token::InStr => Some((in_str, 2..=3)),
+        Token::Length => Some((length, 1..=1)),
+        Token::Expr => Some((expr, 1..=1)),
+        Token::ToString => Some((to_string, 1..=1)),
+        Token::EvalFunc => Some((eval, 1..=1)),
+        _ => None,
+    }
+}
+
+fn elementwise(
+    ctx: &mut Context,
+    val: Value,
+    func: fn(&mut Context, Complex) -> EvalResult,
+) -> EvalResult {
+    // apply a function of one number to a number, or each number in a list
+    match val {
+        Value::ValueList(list) => {
+            let mut result: Vec<Value> = Vec::new();
+            for val in list.into_iter() {
+                result.push(elementwise(ctx, val, func)?);
+            }
+            Ok(Value::ValueList(result))
+        }
+        val => match val.as_complex() {
+            Some(c) => func(ctx, c),
+            None => Err(ExecError::TypeMismatch),
+        },
+    }
+}
+
+fn single(args: Vec<Value>) -> Value {
+    // the parser already checked the arity
+    args.into_iter().next().unwrap()
+}
+
+fn sqrt(ctx: &mut Context, args: Vec<Value>) -> EvalResult {
+    fn sqrt(ctx: &mut Context, c: Complex) -> EvalResult {
+        if c.is_real() && c.re < 0.0 && ctx.mode == NumberMode::Real {
+            return Err(ExecError::NonRealAnswer);
+        }
+        Ok(Value::complex(c.sqrt()))
+    }
+    elementwise(ctx, single(args), sqrt)
+}
+
+fn abs(ctx: &mut Context, args: Vec<Value>) -> EvalResult {
+    fn abs(_: &mut Context, c: Complex) -> EvalResult {
+        Ok(Value::NumValue(c.abs()))
+    }
+    elementwise(ctx, single(args), abs)
+}
+
+fn real(ctx: &mut Context, args: Vec<Value>) -> EvalResult {
+    fn real(_: &mut Context, c: Complex) -> EvalResult {
+        Ok(Value::NumValue(c.re))
+    }
+    elementwise(ctx, single(args), real)
+}
+
+fn imag(ctx: &mut Context, args: Vec<Value>) -> EvalResult {
+    fn imag(_: &mut Context, c: Complex) -> EvalResult {
+        Ok(Value::NumValue(c.im))
+    }
+    elementwise(ctx, single(args), imag)
+}
+
+fn conj(ctx: &mut Context, args: Vec<Value>) -> EvalResult {
+    fn conj(_: &mut Context, c: Complex) -> EvalResult {
+        Ok(Value::complex(c.conj()))
+    }
+    elementwise(ctx, single(args), conj)
+}
+
+fn angle(ctx: &mut Context, args: Vec<Value>) -> EvalResult {
+    fn angle(_: &mut Context, c: Complex) -> EvalResult {
+        Ok(Value::NumValue(c.angle()))
+    }
+    elementwise(ctx, single(args), angle)
+}
+
+fn string(val: Value) -> Result<Vec<char>, ExecError> {
+    // strings are indexed by character, not by byte, so θ counts as one
+    match val {
+        Value::StringValue(s) => Ok(s.chars().collect()),
+        _ => Err(ExecError::TypeMismatch),
+    }
+}
+
+pub fn index(val: Value) -> Result<usize, ExecError> {
+    // a 1-based position, anything that isn't a positive integer is a domain error
+    match val {
+        Value::NumValue(n) if n.fract() == 0.0 && n >= 1.0 => Ok(n as usize),
+        Value::NumValue(_) => Err(ExecError::Domain),
+        _ => Err(ExecError::TypeMismatch),
+    }
+}
+
+fn sub(_: &mut Context, args: Vec<Value>) -> EvalResult {
+    // sub(string,start,length)
+    let mut args = args.into_iter();
+    let chars = string(args.next().unwrap())?;
+    let start = index(args.next().unwrap())?;
+    let len = index(args.next().unwrap())?;
+    if start > chars.len() || len > chars.len() + 1 - start {
+        return Err(ExecError::InvalidDim);
+    }
+    Ok(Value::StringValue(
+        chars[start - 1..start - 1 + len].iter().collect(),
+    ))
+}
+
+fn in_str(_: &mut Context, args: Vec<Value>) -> EvalResult {
+    // inStr(haystack,needle[,start]), the position of needle or 0
+    let mut args = args.into_iter();
+    let haystack = string(args.next().unwrap())?;
+    let needle = string(args.next().unwrap())?;
+    let start = match args.next() {
+        Some(val) => index(val)?,
+        None => 1,
+    };
+    if start > haystack.len() + 1 {
+        return Err(ExecError::InvalidDim);
+    }
+    if needle.is_empty() {
+        return Ok(Value::NumValue(0.0));
+    }
+    let found = haystack[start - 1..]
+        .windows(needle.len())
+        .position(|window| window == needle.as_slice());
+    Ok(Value::NumValue(match found {
+        Some(i) => (start + i) as f64,
+        None => 0.0,
+    }))
+}
+
+fn length(_: &mut Context, args: Vec<Value>) -> EvalResult {
+    Ok(Value::NumValue(string(single(args))?.len() as f64))
+}
+
+fn expr(ctx: &mut Context, args: Vec<Value>) -> EvalResult {
+    // expr("2+3") runs the string as code
+    let text: String = string(single(args))?.into_iter().collect();
+    ctx.eval_text(&text)
+}
+
+fn to_string(ctx: &mut Context, args: Vec<Value>) -> EvalResult {
+    match single(args) {
+        Value::StringValue(s) => Ok(Value::StringValue(s)),
+        val => Ok(Value::StringValue(ctx.format(&val))),
+    }
+}
+
+fn eval(ctx: &mut Context, args: Vec<Value>) -> EvalResult {
+    // eval( from the newer OSes, the value of its argument. Strings are run as
+    // code like expr( does, anything else is already a value
+    match single(args) {
+        Value::StringValue(s) => ctx.eval_text(&s),
+        val => Ok(val),
+    }
+}
@@ -1,64 +1,116 @@
 use std::io;
-use std::io::Write;
+use std::process;
+use std::sync::atomic::{AtomicBool, Ordering};
+use std::sync::Arc;
 
 use crate::executor;
 use crate::lexer;
-use crate::lexer::Token;
+use crate::optimize;
 use crate::parser;
+use crate::repl;
+use crate::repl::Repl;
+use crate::vm;
 
-fn getline() -> String {
-    let mut guess = String::new();
-    print!(":");
-    io::stdout().flush().unwrap();
-    io::stdin()
-        .read_line(&mut guess)
-        .expect("Failed to read line");
+fn catch_break(interrupt: &Arc<AtomicBool>, running: &Arc<AtomicBool>) {
+    // Ctrl+C stops a running program with ERR:BREAK like the ON key does, and
+    // quits tib when nothing is running. A second Ctrl+C before the program
+    // noticed the first one quits too, in case it's stuck somewhere that
+    // never looks
+    let interrupt = interrupt.clone();
+    let running = running.clone();
+    let handler = ctrlc::set_handler(move || {
+        if !running.load(Ordering::Relaxed) || interrupt.swap(true, Ordering::Relaxed) {
+            process::exit(130);
+        }
+    });
+    if let Err(err) = handler {
+        eprintln!("warning: Ctrl+C won't be able to stop programs: {}", err);
+    }
+}
 
-    guess
+fn on_break(program: &mut executor::Program, repl: &mut Repl) {
+    // The calculator's ERR:BREAK menu. Both end the program, Goto shows where
+    // it was, and every variable it set is kept
+    let pc = program.pc;
+    println!("ERR:BREAK");
+    println!("1:Quit");
+    println!("2:Goto");
+    if repl.line(":").is_some_and(|choice| choice.trim() == "2") {
+        if let Some(line) = program.lines.get(pc) {
+            println!("line {}", line);
+        }
+    }
+    program.blockstack.clear();
+    program.pc = program.statements.len();
 }
 
-fn interpret(repl: bool, input_file: &String) {
+fn interpret(
+    mut editor: Option<&mut Repl>,
+    input_file: &String,
+    options: &executor::Options,
+) -> (executor::Context, Result<(), executor::ExecError>) {
     let mut input = input_file.clone();
-    let mut program = executor::Program::new();
-    let mut repl_paused = false;
+    let repl = editor.is_some();
+    let mut program = executor::Program::with_options(options);
+    // how the last thing run went
+    let mut outcome = Ok(());
+    let running = Arc::new(AtomicBool::new(false));
+    catch_break(&program.ctx.interrupt, &running);
     // some "unparsed tokens" data structure here
     loop {
-        // get text
-        if repl {
-            input = getline();
+        // get text, the REPL hands back whole blocks at a time
+        if let Some(editor) = &mut editor {
+            input = match editor.read() {
+                Some(input) => input,
+                None => break,
+            };
+            if repl::is_meta(&input) {
+                repl::meta(&input, &mut program, options, &mut io::stdout()).unwrap();
+                editor.set_variables(&program.ctx);
+                continue;
+            }
         }
         let tokens: Vec<lexer::Token>;
+        let lines: Vec<usize>;
         // lex the input
-        match lexer::lex(&input) {
-            Ok(tk) => {
+        match lexer::lex_lines(&input) {
+            Ok((tk, ln)) => {
                 tokens = tk;
+                lines = ln;
             }
             Err(err) => {
                 println!("Lexing Error: {:?}", err);
-                continue;
-            }
-        }
-
-        // todo: functionalize this
-        if repl {
-            // an empty line is a signal to break out of "paused" interpreter state
-            if repl_paused && tokens == [Token::EndOfLine, Token::EndOfInput] {
-                repl_paused = false;
-            } else if !repl_paused {
-                for pausable in &[Token::If, Token::For, Token::While, Token::Repeat] {
-                    if tokens.contains(pausable) {
-                        repl_paused = true;
-                    }
+                if !repl {
+                    // a calculator won't run a program it can't read either
+                    outcome = Err(executor::ExecError::SyntaxError);
+                    break;
                 }
+                continue;
             }
         }
 
         // println!("{:?}", tokens);
         // parse the line. If we can't parse, add to the "unparsed tokens" and continue
         //                 If we can parse, generate the AST and continue
-        match parser::parse(&tokens, &mut program) {
+        let before = program.statements.len();
+        // lines typed into the REPL carry on from the ones before, like one
+        // long program
+        let lines: Vec<usize> = lines
+            .iter()
+            .map(|line| line + program.source.len())
+            .collect();
+        program.source.extend(input.lines().map(String::from));
+        let parsed = parser::parse_lines(&tokens, &lines, &mut program);
+        match parsed {
             Err(err) => {
                 println!("Parse Error: {:?}", err);
+                // the lines before the mistake don't run either
+                let keep: Vec<bool> = (0..program.statements.len()).map(|i| i < before).collect();
+                program.retain_statements(&keep);
+                if !repl {
+                    outcome = Err(executor::ExecError::SyntaxError);
+                    break;
+                }
                 continue;
             }
             _ => (),
@@ -66,50 +118,97 @@ fn interpret(repl: bool, input_file: &String) {
 
         // println!("{:#?}", program);
 
-        if repl_paused {
-            continue;
+        // a file is parsed all at once, so it can be optimized all at once
+        if !repl && options.optimize {
+            optimize::optimize(&mut program);
         }
 
         // calculate the AST
         // print the result
         // save the program counter before we run, in case we need to rewind it
         let pc_backup = program.pc;
-        match executor::execute(&mut program) {
-            Err(err) => {
-                match err {
-                    executor::ExecError::UnexpectedEof => {
-                        // swallow this
-                        // todo: remove gross code duplication
-                        if repl {
-                            println!("{}", program.ctx.ans);
-                        }
-                    }
-                    _ => {
-                        println!("Execution Error: {:?}", err);
-                        program.pc = pc_backup;
-                    }
-                }
+        // a Ctrl+C from just after the last run finished doesn't count
+        program.ctx.interrupt.store(false, Ordering::Relaxed);
+        running.store(true, Ordering::Relaxed);
+        // --trace and --profile hook into the tree-walker
+        let result = if options.vm && !options.trace && !options.profile {
+            vm::execute(&mut program)
+        } else {
+            executor::execute(&mut program)
+        };
+        running.store(false, Ordering::Relaxed);
+        match result {
+            // an unclosed block just means there's more to come in the REPL
+            Ok(()) | Err(executor::ExecError::UnexpectedEof) => {
+                // like the home screen after a program, Ans or Done
+                println!("{}", program.result());
             }
-            _ => {
-                // No errors
-                if repl {
-                    println!("{}", program.ctx.ans);
+            Err(executor::ExecError::Break) if repl => {
+                if let Some(editor) = &mut editor {
+                    on_break(&mut program, editor);
                 }
             }
+            Err(err) => {
+                println!("Execution Error: {}", err.describe());
+                program.pc = pc_backup;
+                outcome = Err(err);
+            }
         };
 
-        if !repl {
-            break;
+        match &mut editor {
+            Some(editor) => editor.set_variables(&program.ctx),
+            None => break,
         }
     }
+    if let Some(profile) = &program.profile {
+        eprint!("{}", profile.report(&program));
+    }
+    (program.ctx, outcome)
+}
+
+pub fn interpret_repl(
+    options: &executor::Options,
+) -> (executor::Context, Result<(), executor::ExecError>) {
+    // hands back the variables for --state, and Break if Ctrl+C was how the
+    // REPL was left
+    println!("Ctrl+C to stop a program or exit, Tab to complete. Blocks run once they're");
+    println!("closed, or on an empty line. :help for the REPL's own commands.\r\n");
+    let mut editor = Repl::new();
+    // errors were already printed, the REPL carries on after them
+    let (ctx, _) = interpret(Some(&mut editor), &String::new(), options);
+    editor.save();
+    if editor.interrupted {
+        (ctx, Err(executor::ExecError::Break))
+    } else {
+        (ctx, Ok(()))
+    }
 }
 
-pub fn interpret_repl() {
-    // todo: we will need to trap this to break out of loops eventually
-    println!("Ctrl+C to exit, enter twice to execute block of code.\r\n");
-    interpret(true, &String::new())
+pub fn interpret_file(
+    file: &String,
+    options: &executor::Options,
+) -> (executor::Context, Result<(), executor::ExecError>) {
+    // hands back the state the program left behind, for --dump, and how it ended
+    interpret(None, file, options)
 }
 
-pub fn interpret_file(file: &String) {
-    interpret(false, file)
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::executor::{ExecError, Options};
+
+    #[test]
+    fn test_file_outcome() {
+        let options = Options::new();
+        let (ctx, outcome) = interpret_file(&String::from("1->A\n"), &options);
+        assert_eq!(outcome, Ok(()));
+        assert_eq!(ctx.ans, 1.0);
+        // programs that don't lex or parse fail, and none of them runs
+        let (_, outcome) = interpret_file(&String::from("1->A\n@\n"), &options);
+        assert_eq!(outcome, Err(ExecError::SyntaxError));
+        let (ctx, outcome) = interpret_file(&String::from("1->A\n)\n"), &options);
+        assert_eq!(outcome, Err(ExecError::SyntaxError));
+        assert!(!ctx.reals.contains_key(&'A'));
+        assert_eq!(ExecError::SyntaxError.exit_code(), 1);
+    }
 }
@@ -13,13 +13,72 @@ fn number_var(lex: &mut Lexer<Token>) -> Option<char> {
     let slice = lex.slice();
 
     // we only want to represent this as the single character, not the string
-    if slice == "Theta" {
+    if slice == "Theta" || slice == "theta" {
         Some('θ')
     } else {
         Some(slice.chars().nth(0)?)
     }
 }
 
+fn string(lex: &mut Lexer<Token>) -> Option<String> {
+    // Strings end at a closing quote, the end of the line, or a store arrow,
+    // since the calculator lets you leave off the closing quote
+    let rest = lex.remainder();
+    let mut end = rest.find(['"', '\n', '\r']).unwrap_or(rest.len());
+    for arrow in &["->", "→"] {
+        if let Some(i) = rest[..end].find(arrow) {
+            end = end.min(i);
+        }
+    }
+    let contents = String::from(&rest[..end]);
+    let closed = rest[end..].starts_with('"');
+
+    lex.bump(end);
+    if closed {
+        lex.bump(1);
+    }
+    Some(contents)
+}
+
+fn numbered_var(lex: &mut Lexer<Token>) -> Option<u8> {
+    // Str1, Y0, etc
+    let slice = lex.slice();
+    slice[slice.len() - 1..].parse::<u8>().ok()
+}
+
+fn list_var(lex: &mut Lexer<Token>) -> Option<String> {
+    // L₁ is L1
+    let slice = lex.slice();
+    let index = slice.chars().last()?;
+    let digit = match index {
+        '₁'..='₆' => (b'1' + (index as u32 - '₁' as u32) as u8) as char,
+        _ => index,
+    };
+    Some(format!("L{}", digit))
+}
+
+fn custom_list(lex: &mut Lexer<Token>) -> Option<String> {
+    // ʟNAME or |LNAME, the name is all that is kept
+    let slice = lex.slice();
+    let prefix = if slice.starts_with('ʟ') {
+        'ʟ'.len_utf8()
+    } else {
+        2
+    };
+    Some(String::from(&slice[prefix..]))
+}
+
+fn subscript_var(lex: &mut Lexer<Token>) -> Option<u8> {
+    // Y₁ and friends
+    let index = lex.slice().chars().last()?;
+    Some((index as u32 - '₀' as u32) as u8)
+}
+
+fn matrix_var(lex: &mut Lexer<Token>) -> Option<char> {
+    // [A] is just A
+    lex.slice().chars().nth(1)
+}
+
 fn parse_label(lex: &mut Lexer<Token>) -> Option<String> {
     let slice = &lex.slice()[1..];
     let re = Regex::new(r"[A-Z|0-9|θ]{1,2}").unwrap();
@@ -27,11 +86,10 @@ fn parse_label(lex: &mut Lexer<Token>) -> Option<String> {
 }
 
 fn scientific_parser(lex: &mut Lexer<Token>) -> Option<i32> {
-    let slice = lex.slice();
-    match String::from(slice)[1..].parse::<i32>() {
-        Ok(num) => Some(num),
-        Err(_err) => None,
-    }
+    // e5, ᴇ⁻5, |E~5, etc
+    let exponent = lex.slice().trim_start_matches(&['e', 'ᴇ', '|', 'E'][..]);
+    let exponent = exponent.replace(&['⁻', '~'][..], "-");
+    exponent.parse::<i32>().ok()
 }
 
 #[derive(Debug)]
@@ -62,20 +120,25 @@ pub enum Token {
     #[token("=")]
     Equal,
     #[token("!=")]
+    #[token("≠")]
     NotEqual,
     #[token(">")]
     Greater,
     #[token(">=")]
+    #[token("≥")]
     GreaterEqual,
     #[token("<")]
     Less,
     #[token("<=")]
+    #[token("≤")]
     LessEqual,
     #[token("+")]
     Plus,
     #[token("-")]
     Minus,
     #[token("--")]
+    #[token("⁻")]
+    #[token("~")]
     Negate,
     #[token("*")]
     Mult,
@@ -84,17 +147,89 @@ pub enum Token {
     #[token("^")]
     Power,
     #[token("->")]
+    #[token("→")]
     Store,
+    #[token("²")]
+    Square,
+    #[token("⁻¹")]
+    Inverse,
 
-    #[regex("e[-]?[0-9][0-9]?", scientific_parser)]
+    #[regex("(e|ᴇ|[|]E)[-⁻~]?[0-9][0-9]?", scientific_parser)]
     Scientific(i32),
 
     #[token("Theta", number_var)]
+    #[token("theta", number_var)]
     #[regex(r"[A-Z|θ]", number_var)]
     RealVar(char),
 
+    #[token("\"", string)]
+    Str(String),
+    #[regex("Str[0-9]", numbered_var)]
+    StrVar(u8),
+    #[regex("Y[0-9]", numbered_var)]
+    #[regex("Y[₀-₉]", subscript_var)]
+    EquVar(u8),
+    #[regex("L[1-6₁-₆]", list_var)]
+    #[regex("(ʟ|[|]L)[A-Zθ][A-Z0-9θ]?[A-Z0-9θ]?[A-Z0-9θ]?[A-Z0-9θ]?", custom_list)]
+    ListVar(String),
+    #[regex(r"\[[A-J]\]", matrix_var)]
+    MatrixVar(char),
+
     #[token("Ans")]
     Ans,
+    #[token("rand")]
+    Rand,
+    #[token("getKey")]
+    GetKey,
+    #[token("startTmr")]
+    StartTmr,
+    #[token("checkTmr(")]
+    CheckTmr,
+    #[token("i")]
+    #[token("𝑖")]
+    #[token("[i]")]
+    ImaginaryUnit,
+
+    // Functions that precede their arguments
+    #[token("sqrt(")]
+    #[token("√(")]
+    Sqrt,
+    #[token("abs(")]
+    Abs,
+    #[token("real(")]
+    RealPart,
+    #[token("imag(")]
+    ImagPart,
+    #[token("conj(")]
+    Conj,
+    #[token("angle(")]
+    Angle,
+    #[token("solve(")]
+    Solve,
+    #[token("nDeriv(")]
+    NDeriv,
+    #[token("fnInt(")]
+    FnInt,
+    #[token("fMin(")]
+    FMin,
+    #[token("fMax(")]
+    FMax,
+    #[token("Σ(")]
+    #[token("Sigma(")]
+    Sigma,
+    #[token("sub(")]
+    Sub,
+    #[token("inStr(")]
+    #[token("inString(")]
+    InStr,
+    #[token("length(")]
+    Length,
+    #[token("expr(")]
+    Expr,
+    #[token("toString(")]
+    ToString,
+    #[token("eval(")]
+    EvalFunc,
 
     // This is where I would bifrucate this enum into "statements" and "expressions"
     // things after this are "keywords" that aren't eval'd, instead they are executed
@@ -112,6 +247,10 @@ pub enum Token {
     Repeat,
     #[token("End")]
     End,
+    #[token("Stop")]
+    Stop,
+    #[token("IS>(")]
+    // tib used to spell it backwards
     #[token("IS<(")]
     IncrementSkip,
     #[token("DS<(")]
@@ -119,6 +258,36 @@ pub enum Token {
 
     #[token("Disp")]
     Disp,
+    #[token("Output(")]
+    Output,
+    #[token("Input")]
+    Input,
+    #[token("Prompt")]
+    Prompt,
+    #[token("Equ>String(")]
+    #[token("Equ►String(")]
+    EquToString,
+    #[token("String>Equ(")]
+    #[token("String►Equ(")]
+    StringToEqu,
+    #[token("DelVar")]
+    DelVar,
+    #[token("ClrList")]
+    ClrList,
+    #[token("ClrAllLists")]
+    ClrAllLists,
+    #[token("Archive")]
+    Archive,
+    #[token("UnArchive")]
+    UnArchive,
+
+    #[token("Real")]
+    RealMode,
+    #[token("a+bi")]
+    RectMode,
+    #[token("re^θi")]
+    #[token("re^thetai")]
+    PolarMode,
 
     #[regex(r"Lbl\s*[A-Z|0-9|θ][A-Z|0-9|θ]?", parse_label)]
     Lbl(String),
@@ -139,22 +308,59 @@ pub enum Token {
     UnknownToken,
 }
 
+pub fn ends_value(token: &Token) -> bool {
+    // Whether a value can end with this token, which makes a - right after it
+    // subtraction. Anywhere else the parser reads a - as negation, and the
+    // pretty printer and --export write it as ⁻ to match
+    matches!(
+        token,
+        Token::Number(_)
+            | Token::Scientific(_)
+            | Token::RealVar(_)
+            | Token::Str(_)
+            | Token::StrVar(_)
+            | Token::EquVar(_)
+            | Token::ListVar(_)
+            | Token::MatrixVar(_)
+            | Token::Ans
+            | Token::Rand
+            | Token::GetKey
+            | Token::StartTmr
+            | Token::ImaginaryUnit
+            | Token::Rparen
+            | Token::Rcurly
+            | Token::Square
+            | Token::Inverse
+    )
+}
+
 pub fn lex(input: &String) -> Result<Vec<Token>, LexError> {
-    // Do magic!
+    Ok(lex_lines(input)?.0)
+}
+
+pub fn lex_lines(input: &String) -> Result<(Vec<Token>, Vec<usize>), LexError> {
+    // Do magic! Also hands back the source line every token started on, since
+    // a : and a newline are the same token
     let lex = Token::lexer(input);
     let mut all: Vec<Token> = Vec::new();
+    let mut lines: Vec<usize> = Vec::new();
+    let mut line = 1;
+    let mut counted = 0;
 
     for (token, span) in lex.spanned() {
         if token == Token::UnknownToken {
             // todo: better error handling (this function should return Result, etc)
-            // todo: store the line number in the EndofLine token
             return Err(LexError::UnknownToken(String::from(&input[span])));
         }
 
+        line += input[counted..span.start].matches('\n').count();
+        counted = span.start;
         all.push(token);
+        lines.push(line);
     }
     all.push(Token::EndOfInput);
-    Ok(all)
+    lines.push(line);
+    Ok((all, lines))
 }
 
 pub fn lex_str(input: &str) -> Vec<Token> {
@@ -273,4 +479,128 @@ mod tests {
         );
         assert_eq!(lex_str("e-50"), [Token::Scientific(-50), Token::EndOfInput]);
     }
+
+    #[test]
+    fn test_complex_tokens() {
+        assert_eq!(
+            lex_str("2+3i"),
+            [
+                Token::Number(2.0),
+                Token::Plus,
+                Token::Number(3.0),
+                Token::ImaginaryUnit,
+                Token::EndOfInput
+            ]
+        );
+        assert_eq!(
+            lex_str("Real:a+bi:re^θi"),
+            [
+                Token::RealMode,
+                Token::EndOfLine,
+                Token::RectMode,
+                Token::EndOfLine,
+                Token::PolarMode,
+                Token::EndOfInput
+            ]
+        );
+        assert_eq!(lex_str("√("), [Token::Sqrt, Token::EndOfInput]);
+    }
+
+    #[test]
+    fn test_list_and_matrix_vars() {
+        assert_eq!(
+            lex_str("L1->[A]"),
+            [
+                Token::ListVar(String::from("L1")),
+                Token::Store,
+                Token::MatrixVar('A'),
+                Token::EndOfInput
+            ]
+        );
+        // only L1 through L6 exist
+        assert_eq!(
+            lex_str("L7"),
+            [Token::RealVar('L'), Token::Number(7.0), Token::EndOfInput]
+        );
+    }
+
+    #[test]
+    fn test_strings() {
+        assert_eq!(
+            lex_str("\"HELLO WORLD\""),
+            [Token::Str(String::from("HELLO WORLD")), Token::EndOfInput]
+        );
+        // the closing quote is optional
+        assert_eq!(
+            lex_str("\"A:B\n\"C"),
+            [
+                Token::Str(String::from("A:B")),
+                Token::EndOfLine,
+                Token::Str(String::from("C")),
+                Token::EndOfInput
+            ]
+        );
+        assert_eq!(
+            lex_str("\"AB->Str1"),
+            [
+                Token::Str(String::from("AB")),
+                Token::Store,
+                Token::StrVar(1),
+                Token::EndOfInput
+            ]
+        );
+        assert_eq!(
+            lex_str("\"\"+Y0"),
+            [
+                Token::Str(String::new()),
+                Token::Plus,
+                Token::EquVar(0),
+                Token::EndOfInput
+            ]
+        );
+    }
+
+    #[test]
+    fn test_lines() {
+        let (tokens, lines) = lex_lines(&String::from("1:2\n\n3\r\n4")).unwrap();
+        assert_eq!(tokens.len(), lines.len());
+        assert_eq!(lines, [1, 1, 1, 1, 2, 3, 3, 4, 4]);
+    }
+
+    #[test]
+    fn test_negation_tokens() {
+        // ⁻ and its stand-ins are negation, - is left for the parser to work out
+        assert_eq!(
+            lex_str("⁻1--2~3-4"),
+            [
+                Token::Negate,
+                Token::Number(1.0),
+                Token::Negate,
+                Token::Number(2.0),
+                Token::Negate,
+                Token::Number(3.0),
+                Token::Minus,
+                Token::Number(4.0),
+                Token::EndOfInput
+            ]
+        );
+        assert_eq!(
+            lex_str("A⁻¹"),
+            [Token::RealVar('A'), Token::Inverse, Token::EndOfInput]
+        );
+    }
+
+    #[test]
+    fn test_nondeterministic_tokens() {
+        assert_eq!(
+            lex_str("rand getKey startTmr checkTmr("),
+            [
+                Token::Rand,
+                Token::GetKey,
+                Token::StartTmr,
+                Token::CheckTmr,
+                Token::EndOfInput
+            ]
+        );
+    }
 }
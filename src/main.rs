@@ -1,29 +1,452 @@
+mod calculus;
+mod complex;
+mod debugger;
 mod executor;
+mod functions;
 mod interpreter;
 mod lexer;
+mod optimize;
 mod parser;
+mod pretty;
+mod profiler;
+mod repl;
+mod sources;
+mod tifile;
+#[cfg(feature = "ti-float")]
+mod tifloat;
+mod tivars;
+mod tokens;
+mod vm;
 
 use std::env;
 use std::fs;
+use std::path::Path;
+use std::process;
+use std::time::Duration;
+
+fn usage() {
+    println!("tib {} (c) 2020 Jayden Milne", env!("CARGO_PKG_VERSION"));
+    println!("Usage: tib [options] [filename, optional]");
+    println!("If no filename is provided, you will enter a REPL");
+    println!();
+    println!("Options:");
+    println!("  -d, --deterministic  Take rand, the clock, getKey and uninitialized");
+    println!("                       variables from a fixed seed instead of the system");
+    println!("  --seed N             Seed for --deterministic (implies it, default 0)");
+    println!("  --keys K1,K2,...     Key codes getKey returns, in order");
+    println!("  --input LINE         A line for Input or Prompt to read before stdin, can be");
+    println!("                       given more than once");
+    println!("  --ram-clear          Uninitialized variables read as 0, like after a RAM clear");
+    println!("  --ti-float           Use the calculator's 14 digit decimal numbers instead of");
+    println!("                       f64 (needs tib built with the ti-float feature)");
+    println!("  --export OUT.8xp     Tokenize the program into a calculator file instead of");
+    println!("                       running it. The file name is used as the program name");
+    println!("  --format STYLE       Print the program with every token spelled in one style,");
+    println!("                       unicode (→, ≠), ascii (->, !=) or tib, instead of running");
+    println!("  --load FILE          Set variables from a calculator file (.8xn, .8xl, .8xm,");
+    println!("                       .8xs or .8xg) before running");
+    println!("  --dump VARS=FILE     Save variables to a calculator file after running, eg");
+    println!("                       --dump L1=out.8xl or --dump A,Str1,[A]=out.8xg");
+    println!(
+        "  --block-limit N      Raise ERR:MEMORY once N blocks are open, default {},",
+        executor::DEFAULT_BLOCK_LIMIT
+    );
+    println!("                       0 for no limit. Leaving blocks with Goto leaks them");
+    println!("  --warn-leaks         Warn about every Goto that jumps out of a block");
+    println!("  -O, --optimize       Fold constants, drop code after Stop that can't run and");
+    println!("                       find every Goto's Lbl before running");
+    println!("  --dump-ast           Print the parsed program instead of running it, and");
+    println!("                       with --optimize what the optimizer changed");
+    println!("  --debug              Step through the program, with breakpoints and watches.");
+    println!("                       Type help at the (tib) prompt for the commands");
+    println!("  --trace              Print every statement to stderr as it runs, with its");
+    println!("                       line and the Ans it leaves behind");
+    println!("  --profile            Count and time every line and Lbl, and print the");
+    println!("                       slowest ones to stderr at the end");
+    println!("  --vm                 Compile the program to bytecode and run that instead of");
+    println!("                       walking the parsed program, faster for long loops");
+    println!("  --state FILE         Keep variables between runs, like a calculator does.");
+    println!("                       Loads FILE (an .8xg) if it exists and saves every");
+    println!("                       variable back to it after running, or on leaving the");
+    println!("                       REPL or --debug");
+    println!("  --max-steps N        Stop with an error after running N statements");
+    println!("  --timeout SECS       Stop with an error after running for SECS seconds");
+    println!("  --max-output BYTES   Stop with an error once Disp has printed BYTES bytes");
+    println!("  --max-size N         Stop with an error when a list, matrix or string gets");
+    println!("                       more than N elements or characters");
+    println!();
+    println!("Exit codes: 0 when the program finishes, 1 for a calculator error (ERR:...),");
+    println!("including a program that doesn't lex or parse, 2 for bad arguments, 3, 4,");
+    println!("5 and 6 for going over --max-steps, --timeout, --max-output and --max-size,");
+    println!("and 7 for ERR:MEMORY (blocks, or strings evaluating strings, nested too deep)");
+    println!();
+    println!("Programs ending in .8xp are read as calculator files, anything else as text");
+    println!();
+    println!("Special thanks to TI-BASIC Developer (tibasicdev.wikidot.com)");
+}
+
+fn bad_args(message: &str) -> ! {
+    eprintln!("{}, see tib --help", message);
+    process::exit(2);
+}
+
 fn main() {
     let args: Vec<String> = env::args().collect();
+    let mut options = executor::Options::new();
+    let mut filename: Option<&String> = None;
+    let mut export: Option<&String> = None;
+    let mut format: Option<pretty::Style> = None;
+    let mut dumps: Vec<(Vec<parser::Variable>, &str)> = Vec::new();
+    let mut state: Option<&String> = None;
+    let mut dump_ast = false;
+    let mut debug = false;
 
-    if args.len() == 1 {
-        println!("tib {} (c) 2020 Jayden Milne", env!("CARGO_PKG_VERSION"));
-        interpreter::interpret_repl();
-    } else {
-        if args[1] == "--help" || args[1] == "-h" {
+    let mut i = 1;
+    while i < args.len() {
+        match args[i].as_str() {
+            "--help" | "-h" => {
+                usage();
+                return;
+            }
+            "--deterministic" | "-d" => options.deterministic = true,
+            "--seed" => {
+                i += 1;
+                match args.get(i).map(|seed| seed.parse::<u64>()) {
+                    Some(Ok(seed)) => options.seed = seed,
+                    _ => bad_args("--seed needs a non-negative integer"),
+                }
+                options.deterministic = true;
+            }
+            "--keys" => {
+                i += 1;
+                let keys = args.get(i).map(|keys| {
+                    keys.split(',')
+                        .map(|key| key.trim().parse::<f64>())
+                        .collect::<Result<Vec<f64>, _>>()
+                });
+                match keys {
+                    Some(Ok(keys)) => options.keys = keys,
+                    _ => bad_args("--keys needs a comma separated list of key codes"),
+                }
+            }
+            "--input" => {
+                i += 1;
+                match args.get(i) {
+                    Some(line) => options.inputs.push(line.clone()),
+                    None => bad_args("--input needs a line of text"),
+                }
+            }
+            "--export" => {
+                i += 1;
+                match args.get(i) {
+                    Some(out) => export = Some(out),
+                    None => bad_args("--export needs a file name"),
+                }
+            }
+            "--format" => {
+                i += 1;
+                match args
+                    .get(i)
+                    .and_then(|style| pretty::Style::from_name(style))
+                {
+                    Some(style) => format = Some(style),
+                    None => bad_args("--format needs unicode, ascii or tib"),
+                }
+            }
+            "--load" => {
+                i += 1;
+                match args.get(i) {
+                    Some(file) => options.preload.extend(load_vars(file)),
+                    None => bad_args("--load needs a file name"),
+                }
+            }
+            "--dump" => {
+                i += 1;
+                match args.get(i).and_then(|dump| parse_dump(dump)) {
+                    Some(dump) => dumps.push(dump),
+                    None => bad_args("--dump needs variables and a file, like A,L1=out.8xg"),
+                }
+            }
+            "--block-limit" => {
+                i += 1;
+                match args.get(i).map(|limit| limit.parse::<usize>()) {
+                    Some(Ok(0)) => options.block_limit = None,
+                    Some(Ok(limit)) => options.block_limit = Some(limit),
+                    _ => bad_args("--block-limit needs a non-negative integer"),
+                }
+            }
+            "--warn-leaks" => options.warn_leaks = true,
+            "--vm" => options.vm = true,
+            "--optimize" | "-O" => options.optimize = true,
+            "--dump-ast" => dump_ast = true,
+            "--debug" => debug = true,
+            "--trace" => options.trace = true,
+            "--max-steps" => {
+                i += 1;
+                match args.get(i).map(|max| max.parse::<u64>()) {
+                    Some(Ok(max)) => options.limits.steps = Some(max),
+                    _ => bad_args("--max-steps needs a non-negative integer"),
+                }
+            }
+            "--timeout" => {
+                i += 1;
+                match args.get(i).map(|secs| secs.parse::<f64>()) {
+                    Some(Ok(secs)) if secs.is_finite() && secs >= 0.0 => {
+                        options.limits.time = Some(Duration::from_secs_f64(secs))
+                    }
+                    _ => bad_args("--timeout needs a number of seconds"),
+                }
+            }
+            "--max-output" => {
+                i += 1;
+                match args.get(i).map(|max| max.parse::<usize>()) {
+                    Some(Ok(max)) => options.limits.output = Some(max),
+                    _ => bad_args("--max-output needs a non-negative integer"),
+                }
+            }
+            "--max-size" => {
+                i += 1;
+                match args.get(i).map(|max| max.parse::<usize>()) {
+                    Some(Ok(max)) => options.limits.size = Some(max),
+                    _ => bad_args("--max-size needs a non-negative integer"),
+                }
+            }
+            "--profile" => options.profile = true,
+            "--state" => {
+                i += 1;
+                match args.get(i) {
+                    Some(file) => state = Some(file),
+                    None => bad_args("--state needs a file name"),
+                }
+            }
+            "--ram-clear" => options.uninit = sources::Uninit::Zero,
+            #[cfg(feature = "ti-float")]
+            "--ti-float" => options.backend = executor::Backend::TiFloat,
+            #[cfg(not(feature = "ti-float"))]
+            "--ti-float" => bad_args("tib was built without the ti-float feature"),
+            arg => {
+                if arg.starts_with('-') || filename.is_some() {
+                    bad_args(&format!("Unexpected argument {}", arg));
+                }
+                filename = Some(&args[i]);
+            }
+        }
+        i += 1;
+    }
+
+    if let Some(file) = state {
+        if Path::new(file).exists() {
+            load_state(file, &mut options);
+        }
+    }
+
+    // the rest don't run the program, so there'd be nothing new to save
+    if state.is_some() && (export.is_some() || format.is_some() || dump_ast) {
+        bad_args("--state only works when running a program");
+    }
+
+    match (filename, export) {
+        (None, Some(_)) => bad_args("--export needs a program to export"),
+        (None, None) => {
             println!("tib {} (c) 2020 Jayden Milne", env!("CARGO_PKG_VERSION"));
-            println!("Usage: tib [filename, optional]");
-            println!("If no filename is provided, you will enter a REPL");
-            println!("Special thanks to TI-BASIC Developer (tibasicdev.wikidot.com)");
-            return;
+            let (ctx, result) = interpreter::interpret_repl(&options);
+            if let Some(file) = state {
+                save_state(&ctx, file);
+            }
+            if let Err(err) = result {
+                process::exit(err.exit_code());
+            }
+        }
+        (Some(filename), None) if format.is_some() => {
+            print_formatted(&read_source(filename), format.unwrap())
         }
+        (Some(filename), None) if debug => {
+            let source = read_source(filename);
+            let ctx = debugger::debug(parse_program(&source, &options), &source);
+            if let Some(file) = state {
+                save_state(&ctx, file);
+            }
+        }
+        (Some(filename), None) if dump_ast => print_ast(&read_source(filename), &options),
+        (Some(filename), None) => {
+            let (mut ctx, result) = interpreter::interpret_file(&read_source(filename), &options);
+            for (vars, out) in dumps.iter() {
+                dump_vars(&mut ctx, vars, out);
+            }
+            if let Some(file) = state {
+                save_state(&ctx, file);
+            }
+            if let Err(err) = result {
+                process::exit(err.exit_code());
+            }
+        }
+        (Some(filename), Some(out)) => export_program(&read_source(filename), out),
+    }
+}
 
-        let filename = &args[1];
-        match fs::read_to_string(filename) {
-            Ok(file) => interpreter::interpret_file(&file),
+fn is_8xp(filename: &str) -> bool {
+    Path::new(filename)
+        .extension()
+        .is_some_and(|ext| ext.eq_ignore_ascii_case("8xp"))
+}
+
+fn read_source(filename: &str) -> String {
+    if !is_8xp(filename) {
+        return match fs::read_to_string(filename) {
+            Ok(file) => file,
             Err(error) => panic!("Could not open the file {}, error is {:?}", filename, error),
         };
     }
+    let bytes = match fs::read(filename) {
+        Ok(bytes) => bytes,
+        Err(error) => panic!("Could not open the file {}, error is {:?}", filename, error),
+    };
+    match tifile::read_program(&bytes) {
+        Ok(source) => source,
+        Err(error) => {
+            eprintln!("Could not read {} as a program: {:?}", filename, error);
+            process::exit(1);
+        }
+    }
+}
+
+fn print_formatted(source: &str, style: pretty::Style) {
+    match lexer::lex(&String::from(source)) {
+        Ok(tokens) => println!("{}", pretty::pretty(&tokens, style)),
+        Err(error) => {
+            eprintln!("Could not lex the program: {:?}", error);
+            process::exit(1);
+        }
+    }
+}
+
+fn parse_program(source: &str, options: &executor::Options) -> executor::Program {
+    let (tokens, lines) = match lexer::lex_lines(&String::from(source)) {
+        Ok(lexed) => lexed,
+        Err(error) => {
+            eprintln!("Could not lex the program: {:?}", error);
+            process::exit(1);
+        }
+    };
+    let mut program = executor::Program::with_options(options);
+    if let Err(error) = parser::parse_lines(&tokens, &lines, &mut program) {
+        eprintln!("Could not parse the program: {:?}", error);
+        process::exit(1);
+    }
+    program
+}
+
+fn print_ast(source: &str, options: &executor::Options) {
+    let mut program = parse_program(source, options);
+    let changes = if options.optimize {
+        optimize::optimize(&mut program)
+    } else {
+        Vec::new()
+    };
+    // statement number, the line it came from, then the statement
+    for (i, statement) in program.statements.iter().enumerate() {
+        let line = program.lines.get(i).copied().unwrap_or(0);
+        println!("{:>4} {:>4}  {:?}", i, line, statement);
+    }
+    if !changes.is_empty() {
+        println!();
+        for change in changes.iter() {
+            println!("{}", change);
+        }
+    }
+}
+
+fn load_vars(filename: &str) -> Vec<(parser::Variable, parser::Value)> {
+    let bytes = match fs::read(filename) {
+        Ok(bytes) => bytes,
+        Err(error) => panic!("Could not open the file {}, error is {:?}", filename, error),
+    };
+    match tivars::read_vars(&bytes) {
+        Ok(vars) => vars,
+        Err(error) => {
+            eprintln!("Could not read variables from {}: {:?}", filename, error);
+            process::exit(1);
+        }
+    }
+}
+
+fn load_state(filename: &str, options: &mut executor::Options) {
+    let bytes = match fs::read(filename) {
+        Ok(bytes) => bytes,
+        Err(error) => panic!("Could not open the file {}, error is {:?}", filename, error),
+    };
+    match tivars::read_state(&bytes) {
+        Ok(vars) => {
+            for (var, val, archived) in vars {
+                if archived {
+                    options.archived.push(var.clone());
+                }
+                options.preload.push((var, val));
+            }
+        }
+        Err(error) => {
+            eprintln!("Could not read the state file {}: {:?}", filename, error);
+            process::exit(1);
+        }
+    }
+}
+
+fn save_state(ctx: &executor::Context, filename: &str) {
+    let vars: Vec<(parser::Variable, parser::Value, bool)> = ctx
+        .variables()
+        .into_iter()
+        .map(|(var, val)| {
+            let archived = ctx.archived.contains(&var);
+            (var, val, archived)
+        })
+        .collect();
+    let result = tivars::write_state(&vars).map_err(|error| format!("{:?}", error));
+    if let Err(error) =
+        result.and_then(|bytes| fs::write(filename, bytes).map_err(|e| e.to_string()))
+    {
+        eprintln!("Could not write the state file {}: {}", filename, error);
+        process::exit(1);
+    }
+}
+
+fn parse_dump(dump: &str) -> Option<(Vec<parser::Variable>, &str)> {
+    // A,L1=out.8xg
+    let (names, out) = dump.split_once('=')?;
+    let vars: Option<Vec<parser::Variable>> = names.split(',').map(tivars::parse_name).collect();
+    Some((vars?, out))
+}
+
+fn dump_vars(ctx: &mut executor::Context, vars: &[parser::Variable], out: &str) {
+    let mut values: Vec<(parser::Variable, parser::Value)> = Vec::new();
+    for var in vars.iter() {
+        match ctx.get(var) {
+            Ok(val) => values.push((var.clone(), val)),
+            Err(error) => {
+                eprintln!("Could not dump {:?}: {:?}", var, error);
+                process::exit(1);
+            }
+        }
+    }
+    let result = tivars::write_vars(&values).map_err(|error| format!("{:?}", error));
+    if let Err(error) = result.and_then(|bytes| fs::write(out, bytes).map_err(|e| e.to_string())) {
+        eprintln!("Could not write {}: {}", out, error);
+        process::exit(1);
+    }
+}
+
+fn export_program(source: &str, out: &str) {
+    let name = Path::new(out)
+        .file_stem()
+        .map_or(String::new(), |stem| stem.to_string_lossy().to_uppercase());
+    let bytes = match tifile::write_program(&name, source) {
+        Ok(bytes) => bytes,
+        Err(error) => {
+            eprintln!("Could not export {}: {:?}", out, error);
+            process::exit(1);
+        }
+    };
+    if let Err(error) = fs::write(out, bytes) {
+        eprintln!("Could not write {}: {:?}", out, error);
+        process::exit(1);
+    }
 }
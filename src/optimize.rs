@@ -0,0 +1,244 @@
+use crate::executor::*;
+use crate::parser::*;
+
+// An optional pass over a parsed program, run before it starts (--optimize).
+// It folds constant expressions like 2*3+4 into a single value, drops
+// statements after a Stop that nothing can reach, and looks up where every
+// Goto lands so it doesn't have to happen each time the Goto runs.
+//
+// None of this can change what a program does. Anything whose answer depends
+// on the mode (eg (-8)^(1/3), an error in Real and complex in a+bi) or on
+// errors is left for the program to work out when it runs.
+
+pub fn constant(result: EvalResult) -> Option<Value> {
+    // Whether an answer is the same whatever mode we end up in
+    match result {
+        Ok(Value::NumValue(n)) if !n.is_nan() => Some(Value::NumValue(n)),
+        Ok(Value::StringValue(s)) => Some(Value::StringValue(s)),
+        _ => None,
+    }
+}
+
+pub fn fold(expr: &mut ValRef, ctx: &Context) -> Option<Value> {
+    // Fold an expression, replacing it with its value if it is constant
+    let val = expr.fold(ctx);
+    if let Some(val) = &val {
+        *expr = Box::new(val.clone());
+    }
+    val
+}
+
+fn fold_statement(statement: &mut Statement, ctx: &Context) {
+    match statement {
+        Statement::Expression(expr) => {
+            fold(expr, ctx);
+        }
+        Statement::Command(cmd) => match cmd {
+            Command::If(expr)
+            | Command::While(expr)
+            | Command::Repeat(expr)
+            | Command::Disp(expr)
+            | Command::IncrementSkip(_, expr)
+            | Command::DecrementSkip(_, expr) => {
+                fold(expr, ctx);
+            }
+            Command::For(cmd) => {
+                fold(&mut cmd.start, ctx);
+                fold(&mut cmd.stop, ctx);
+                fold(&mut cmd.inc, ctx);
+            }
+            _ => (),
+        },
+    }
+}
+
+pub fn guards_next(statement: &Statement) -> bool {
+    // If without a Then, IS>( and DS<( decide whether the next statement runs
+    matches!(
+        statement,
+        Statement::Command(Command::If(_))
+            | Statement::Command(Command::IncrementSkip(_, _))
+            | Statement::Command(Command::DecrementSkip(_, _))
+    )
+}
+
+fn unreachable(statements: &[Statement]) -> Vec<bool> {
+    // Statements after a Stop can only be reached by a Goto to a Lbl, or by
+    // skipping to an Else or End. Blocks are kept whole so scanning for Else
+    // and End still finds the same ones
+    let mut dead = vec![false; statements.len()];
+    let mut stopped = false;
+    for (i, statement) in statements.iter().enumerate() {
+        let guarded = i > 0 && guards_next(&statements[i - 1]);
+        match statement {
+            Statement::Command(Command::Lbl(_))
+            | Statement::Command(Command::Else)
+            | Statement::Command(Command::End) => stopped = false,
+            Statement::Command(Command::If(_))
+            | Statement::Command(Command::Then)
+            | Statement::Command(Command::For(_))
+            | Statement::Command(Command::While(_))
+            | Statement::Command(Command::Repeat(_)) => (),
+            _ if stopped && !guarded => dead[i] = true,
+            Statement::Command(Command::Stop) if !guarded => stopped = true,
+            _ => (),
+        }
+    }
+    dead
+}
+
+fn line(program: &Program, i: usize) -> usize {
+    program.lines.get(i).copied().unwrap_or(0)
+}
+
+pub fn optimize(program: &mut Program) -> Vec<String> {
+    // Returns what was changed, one line each, for --dump-ast
+    let mut changes: Vec<String> = Vec::new();
+
+    for i in 0..program.statements.len() {
+        let before = format!("{:?}", program.statements[i]);
+        fold_statement(&mut program.statements[i], &program.ctx);
+        let after = format!("{:?}", program.statements[i]);
+        if before != after {
+            changes.push(format!(
+                "line {}: folded {} into {}",
+                line(program, i),
+                before,
+                after
+            ));
+        }
+    }
+
+    let dead = unreachable(&program.statements);
+    for (i, _) in dead.iter().enumerate().filter(|(_, dead)| **dead) {
+        changes.push(format!(
+            "line {}: removed unreachable {:?}",
+            line(program, i),
+            program.statements[i]
+        ));
+    }
+    if dead.contains(&true) {
+        let keep: Vec<bool> = dead.iter().map(|dead| !dead).collect();
+        program.retain_statements(&keep);
+    }
+
+    for i in 0..program.statements.len() {
+        let label = match &program.statements[i] {
+            Statement::Command(Command::Goto(label)) => label.clone(),
+            _ => continue,
+        };
+        match program.label_cache.get(&label) {
+            Some(loc) => {
+                changes.push(format!(
+                    "line {}: Goto {} jumps to statement {}",
+                    line(program, i),
+                    label,
+                    loc
+                ));
+                program.statements[i] = Statement::Command(Command::Jump(label, *loc));
+            }
+            // still ERR:LABEL, but only if it runs
+            None => changes.push(format!(
+                "line {}: Goto {} has no Lbl",
+                line(program, i),
+                label
+            )),
+        }
+    }
+
+    changes
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::*;
+
+    fn optimized(input: &str) -> (Program, Vec<String>) {
+        let mut program = Program::with_options(&Options::new());
+        parse(&lex_str(input), &mut program).unwrap();
+        let changes = optimize(&mut program);
+        (program, changes)
+    }
+
+    fn run(mut program: Program) -> (Result<(), ExecError>, String) {
+        let result = execute(&mut program);
+        (result, format!("{:?}", program.ctx.variables()))
+    }
+
+    // optimizing must never change what a program does
+    fn same(input: &str) {
+        let mut program = Program::with_options(&Options::new());
+        parse(&lex_str(input), &mut program).unwrap();
+        assert_eq!(run(optimized(input).0), run(program), "{}", input);
+    }
+
+    #[test]
+    fn test_fold() {
+        let (program, changes) = optimized("2*3+4->A\nA+1*2\n\"A\"+\"B\"\n");
+        assert_eq!(
+            format!("{:?}", program.statements[0]),
+            "Expression(Store(10.0->Var(RealVar('A'))))"
+        );
+        assert_eq!(
+            format!("{:?}", program.statements[1]),
+            "Expression(Plus(Var(RealVar('A')), 2.0))"
+        );
+        assert_eq!(format!("{:?}", program.statements[2]), "Expression(\"AB\")");
+        assert_eq!(changes.len(), 3);
+
+        // left alone, they depend on the mode or are errors
+        let (_, changes) = optimized("(--8)^(1/3)\n1/0\nsqrt(4)\n");
+        assert_eq!(changes.len(), 1);
+        same("(--8)^(1/3)->A\n");
+        same("a+bi\n(--8)^(1/3)->A\n");
+        same("1/0->A\n");
+        same("For(I,1,2*5,1+1)\nI->A\nEnd\n");
+        same("not(2-2)->A\n--(1+1)->B\n{1+1,2}->L1\n");
+    }
+
+    #[test]
+    fn test_unreachable() {
+        let (program, changes) = optimized("1->A\nStop\n2->A\nDisp A\nLbl B\n3->A\n");
+        assert_eq!(program.statements.len(), 4);
+        assert_eq!(changes.len(), 2);
+        assert_eq!(program.label_cache.get("B"), Some(&2));
+
+        // a Stop that might not run doesn't make anything unreachable
+        let (_, changes) = optimized("If A\nStop\n2->A\nIS>(A,5)\nStop\n3->A\n");
+        assert!(changes.is_empty());
+        // Else and End can be skipped to
+        same("0->A\nIf A\nThen\nStop\n1->B\nElse\n2->B\nEnd\n3->C\n");
+        same("1->A\nWhile A<3\nA+1->A\nIf A=2\nThen\nStop\nIf A\n4->B\nEnd\nEnd\n");
+        same("0->A\nGoto B\nStop\n1->A\nLbl B\nA+2->A\nStop\n5->A\n");
+    }
+
+    #[test]
+    fn test_goto() {
+        let (program, changes) = optimized("0->A\nLbl A\nA+1->A\nIf A<5\nGoto A\nGoto C\n");
+        match &program.statements[4] {
+            Statement::Command(Command::Jump(label, 1)) => assert_eq!(label, "A"),
+            statement => panic!("{:?}", statement),
+        }
+        assert_eq!(
+            changes,
+            vec![
+                "line 0: Goto A jumps to statement 1",
+                "line 0: Goto C has no Lbl"
+            ]
+        );
+        same("0->A\nLbl A\nA+1->A\nIf A<5\nGoto A\n");
+        // after statements are removed
+        same("0->A\nGoto B\nStop\n1->A\nLbl B\nA+1->A\nIf A<5\nGoto B\n");
+    }
+
+    #[test]
+    fn test_stop() {
+        same("1->A\nStop\n2->A\n");
+        same("For(I,1,10)\nI->A\nIf I=3\nStop\nEnd\n");
+        let mut program = Program::with_options(&Options::new());
+        parse(&lex_str("While 1\nStop\nEnd\n"), &mut program).unwrap();
+        execute(&mut program).unwrap();
+        assert!(program.blockstack.is_empty());
+    }
+}
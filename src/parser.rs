@@ -1,18 +1,31 @@
+use crate::calculus::{self, Calculus};
+use crate::complex::Complex;
 use crate::executor::*;
+use crate::functions::{self, Func};
 use crate::lexer::Token;
+use crate::vm::Op;
 use std::fmt;
 
-#[derive(Clone, Debug)]
+#[derive(Clone, Debug, PartialEq, Eq, Hash)]
 pub enum Variable {
     RealVar(char),
+    StrVar(u8),
+    EquVar(u8),
+    // L1-L6, and eventually custom lists
+    ListVar(String),
+    MatrixVar(char),
+    Rand,
     Ans,
 }
 
 #[derive(Clone, Debug)]
 pub enum Value {
     NumValue(f64),
+    ComplexValue(Complex),
     StringValue(String),
     ValueList(Vec<Value>),
+    // rows of columns
+    MatrixValue(Vec<Vec<f64>>),
 }
 
 impl Value {
@@ -23,6 +36,71 @@ impl Value {
             false => 0.0,
         })
     }
+
+    // Complex results that land on the real line go back to being reals
+    pub fn complex(c: Complex) -> Value {
+        let c = c.clean();
+        if c.is_real() {
+            Value::NumValue(c.re)
+        } else {
+            Value::ComplexValue(c)
+        }
+    }
+
+    pub fn as_complex(&self) -> Option<Complex> {
+        match self {
+            Value::NumValue(n) => Some(Complex::new(*n, 0.0)),
+            Value::ComplexValue(c) => Some(*c),
+            _ => None,
+        }
+    }
+}
+
+impl Variable {
+    // The variable a token names, if it is one that can be stored to
+    pub fn from_token(token: &Token) -> Option<Variable> {
+        match token {
+            Token::RealVar(name) => Some(Variable::RealVar(*name)),
+            Token::StrVar(n) => Some(Variable::StrVar(*n)),
+            Token::EquVar(n) => Some(Variable::EquVar(*n)),
+            Token::ListVar(name) => Some(Variable::ListVar(name.clone())),
+            Token::MatrixVar(name) => Some(Variable::MatrixVar(*name)),
+            Token::Rand => Some(Variable::Rand),
+            _ => None,
+        }
+    }
+}
+
+// Somewhere a value can be stored and read back: a whole variable, or one
+// element of a list or matrix like L1(2) and [A](1,2)
+#[derive(Clone, Debug)]
+pub enum Target {
+    Var(Variable),
+    ListElement(String, ValRef),
+    MatrixElement(char, ValRef, ValRef),
+}
+
+impl fmt::Display for Variable {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        // spelled the way a program would
+        match self {
+            Variable::RealVar(name) => write!(f, "{}", name),
+            Variable::StrVar(n) => write!(f, "Str{}", n),
+            Variable::EquVar(n) => write!(f, "Y{}", n),
+            // L1-L6 are stored by name, custom lists without their ʟ
+            Variable::ListVar(name)
+                if name.len() == 2
+                    && name.starts_with('L')
+                    && name.as_bytes()[1].is_ascii_digit() =>
+            {
+                write!(f, "{}", name)
+            }
+            Variable::ListVar(name) => write!(f, "ʟ{}", name),
+            Variable::MatrixVar(name) => write!(f, "[{}]", name),
+            Variable::Rand => write!(f, "rand"),
+            Variable::Ans => write!(f, "Ans"),
+        }
+    }
 }
 
 impl fmt::Display for Value {
@@ -31,6 +109,9 @@ impl fmt::Display for Value {
             Value::NumValue(number) => {
                 write!(f, "{}", number)
             }
+            Value::ComplexValue(c) => {
+                write!(f, "{}", c)
+            }
             Value::StringValue(string) => {
                 write!(f, "{}", string)
             }
@@ -43,6 +124,15 @@ impl fmt::Display for Value {
                 out.push_str("}");
                 write!(f, "{}", out)
             }
+            Value::MatrixValue(rows) => {
+                let mut out = String::from("[");
+                for row in rows.iter() {
+                    let row: Vec<String> = row.iter().map(|n| n.to_string()).collect();
+                    out.push_str(format!("[{}]", row.join(" ")).as_str());
+                }
+                out.push(']');
+                write!(f, "{}", out)
+            }
         }
     }
 }
@@ -52,9 +142,26 @@ impl PartialEq for Value {
         match self {
             Value::NumValue(n1) => match other {
                 Value::NumValue(n2) => n1 == n2,
+                Value::ComplexValue(_) => false,
                 _ => panic!("Not implemented!"),
             },
-            _ => panic!("Not implemented!"),
+            Value::ComplexValue(c1) => match other {
+                Value::ComplexValue(c2) => c1 == c2,
+                Value::NumValue(_) => false,
+                _ => panic!("Not implemented!"),
+            },
+            Value::StringValue(s1) => match other {
+                Value::StringValue(s2) => s1 == s2,
+                _ => false,
+            },
+            Value::MatrixValue(m1) => match other {
+                Value::MatrixValue(m2) => m1 == m2,
+                _ => false,
+            },
+            Value::ValueList(l1) => match other {
+                Value::ValueList(l2) => l1 == l2,
+                _ => false,
+            },
         }
     }
 }
@@ -85,18 +192,30 @@ impl Eval for Value {
     fn fmt(&self, f: &mut core::fmt::Formatter<'_>) -> core::fmt::Result {
         match self {
             Value::NumValue(n) => write!(f, "{:?}", n),
+            Value::ComplexValue(c) => write!(f, "{:?}", c),
             Value::StringValue(s) => write!(f, "{:?}", s),
             Value::ValueList(v) => write!(f, "{:?}", v),
+            Value::MatrixValue(m) => write!(f, "{:?}", m),
         }
     }
 
     fn clone_expr(&self) -> Box<dyn Eval> {
         match self {
             Value::NumValue(n) => Box::new(Value::NumValue(*n)),
+            Value::ComplexValue(c) => Box::new(Value::ComplexValue(*c)),
             Value::StringValue(s) => Box::new(Value::StringValue(s.clone())),
             Value::ValueList(v) => Box::new(Value::ValueList(v.clone())),
+            Value::MatrixValue(m) => Box::new(Value::MatrixValue(m.clone())),
         }
     }
+
+    fn fold(&mut self, _ctx: &Context) -> Option<Value> {
+        Some(self.clone())
+    }
+
+    fn compile(&self, code: &mut Vec<Op>) {
+        code.push(Op::Push(self.clone()));
+    }
 }
 
 #[derive(Debug, Clone)]
@@ -108,11 +227,27 @@ pub enum Command {
     While(ValRef),
     Repeat(ValRef),
     End,
+    Stop,
     Disp(ValRef),
+    // row, column and what to show there
+    Output(ValRef, ValRef, ValRef),
+    // the prompt, "?" if there isn't one
+    Input(Option<ValRef>, Variable),
+    Prompt(Vec<Variable>),
     Lbl(String),
     Goto(String),
-    DecrementSkip(Variable, ValRef),
-    IncrementSkip(Variable, ValRef),
+    // a Goto whose Lbl was looked up ahead of time, see optimize.rs
+    Jump(String, usize),
+    DecrementSkip(Target, ValRef),
+    IncrementSkip(Target, ValRef),
+    Mode(NumberMode),
+    EquToString(Variable, Variable),
+    StringToEqu(Variable, Variable),
+    DelVar(Variable),
+    ClrList(Vec<String>),
+    ClrAllLists,
+    Archive(Variable),
+    UnArchive(Variable),
 }
 
 #[derive(Debug, Clone)]
@@ -123,6 +258,8 @@ pub enum Statement {
 
 struct Parser<'a> {
     tokens: &'a Vec<Token>,
+    // source line of each token, empty if we don't know them
+    lines: &'a [usize],
     prog: &'a mut Program,
     i: usize,
     in_list: bool,
@@ -171,16 +308,8 @@ impl<'a> Parser<'a> {
         // Storing Variables
         let lhs = self.pl_11()?;
         if self.match_if_is(Token::Store) {
-            match self.token().clone() {
-                Token::RealVar(name) => {
-                    self.advance();
-                    Ok(Box::new(StoreNode {
-                        val: lhs,
-                        var: Variable::RealVar(name.clone()),
-                    }))
-                }
-                _ => Err(ParserError::SyntaxError),
-            }
+            let target = self.target()?;
+            Ok(Box::new(StoreNode { val: lhs, target }))
         } else {
             Ok(lhs)
         }
@@ -192,111 +321,129 @@ impl<'a> Parser<'a> {
 
     fn pl_10(&mut self) -> PlRes {
         // Logical or, xor
-        let lhs = self.pl_9()?;
-
-        if self.match_if_is(Token::Or) {
-            let rhs = self.pl_10()?;
-            Ok(Box::new(BinaryOp::or(lhs, rhs)))
-        } else if self.match_if_is(Token::Xor) {
-            let rhs = self.pl_10()?;
-            Ok(Box::new(BinaryOp::xor(lhs, rhs)))
-        } else {
-            Ok(lhs)
+        let mut lhs = self.pl_9()?;
+        loop {
+            if self.match_if_is(Token::Or) {
+                let rhs = self.pl_9()?;
+                lhs = Box::new(BinaryOp::or(lhs, rhs));
+            } else if self.match_if_is(Token::Xor) {
+                let rhs = self.pl_9()?;
+                lhs = Box::new(BinaryOp::xor(lhs, rhs));
+            } else {
+                return Ok(lhs);
+            }
         }
     }
 
     fn pl_9(&mut self) -> PlRes {
         // Logical and
-        let lhs = self.pl_8()?;
-
-        if self.match_if_is(Token::And) {
-            let rhs = self.pl_9()?;
-            Ok(Box::new(BinaryOp::and(lhs, rhs)))
-        } else {
-            Ok(lhs)
+        let mut lhs = self.pl_8()?;
+        while self.match_if_is(Token::And) {
+            let rhs = self.pl_8()?;
+            lhs = Box::new(BinaryOp::and(lhs, rhs));
         }
+        Ok(lhs)
     }
 
     fn pl_8(&mut self) -> PlRes {
-        // Relational Operators
-        let lhs = self.pl_7()?;
-
-        if self.match_if_is(Token::Equal) {
-            let rhs = self.pl_8()?;
-            Ok(Box::new(BinaryOp::equal(lhs, rhs)))
-        } else if self.match_if_is(Token::NotEqual) {
-            let rhs = self.pl_8()?;
-            Ok(Box::new(BinaryOp::not_equal(lhs, rhs)))
-        } else if self.match_if_is(Token::Greater) {
-            let rhs = self.pl_8()?;
-            Ok(Box::new(BinaryOp::greater(lhs, rhs)))
-        } else if self.match_if_is(Token::GreaterEqual) {
-            let rhs = self.pl_8()?;
-            Ok(Box::new(BinaryOp::greater_equal(lhs, rhs)))
-        } else if self.match_if_is(Token::Less) {
-            let rhs = self.pl_8()?;
-            Ok(Box::new(BinaryOp::less(lhs, rhs)))
-        } else if self.match_if_is(Token::LessEqual) {
-            let rhs = self.pl_8()?;
-            Ok(Box::new(BinaryOp::less_equal(lhs, rhs)))
-        } else {
-            Ok(lhs)
+        // Relational Operators. 1<2<3 is (1<2)<3, like everything else
+        let mut lhs = self.pl_7()?;
+        loop {
+            let op: fn(ValRef, ValRef) -> BinaryOp = match self.token() {
+                Token::Equal => BinaryOp::equal,
+                Token::NotEqual => BinaryOp::not_equal,
+                Token::Greater => BinaryOp::greater,
+                Token::GreaterEqual => BinaryOp::greater_equal,
+                Token::Less => BinaryOp::less,
+                Token::LessEqual => BinaryOp::less_equal,
+                _ => return Ok(lhs),
+            };
+            self.advance();
+            let rhs = self.pl_7()?;
+            lhs = Box::new(op(lhs, rhs));
         }
     }
 
     fn pl_7(&mut self) -> PlRes {
-        let lhs = self.pl_6()?;
-        if self.match_if_is(Token::Plus) {
-            let rhs = self.pl_7()?;
-            Ok(Box::new(BinaryOp::add(lhs, rhs)))
-        } else if self.match_if_is(Token::Minus) {
-            let rhs = self.pl_7()?;
-            Ok(Box::new(BinaryOp::minus(lhs, rhs)))
-        } else {
-            Ok(lhs)
+        // Addition and subtraction, left to right so 10-3-2 is 5
+        let mut lhs = self.pl_6()?;
+        loop {
+            if self.match_if_is(Token::Plus) {
+                let rhs = self.pl_6()?;
+                lhs = Box::new(BinaryOp::add(lhs, rhs));
+            } else if self.match_if_is(Token::Minus) {
+                let rhs = self.pl_6()?;
+                lhs = Box::new(BinaryOp::minus(lhs, rhs));
+            } else {
+                return Ok(lhs);
+            }
         }
     }
 
     fn pl_6(&mut self) -> PlRes {
-        // Multiplication, division, implied multiplication
-        let lhs = self.pl_5()?;
-
-        if self.match_if_is(Token::Mult) {
-            let rhs = self.pl_6()?;
-            Ok(Box::new(BinaryOp::mult(lhs, rhs)))
-        } else if self.match_if_is(Token::Divide) {
-            let rhs = self.pl_6()?;
-            Ok(Box::new(BinaryOp::divide(lhs, rhs)))
-        // TODO: Adjacent Multiplication Here??
-        } else {
-            // Super ghetto: since this still may be adjacent multiplication,
-            // recurse and try and grab something. If it works, hooray, otherwise,
-            // swallow the error and move on
-            let rhs = self.pl_6();
-            let i_bak = self.i;
-            match rhs {
-                Ok(val) => {
-                    // hooray, we did it
-                    Ok(Box::new(BinaryOp::mult(lhs, val)))
-                }
-                Err(_err) => {
-                    // well, we tried
-                    self.i = i_bak;
-                    Ok(lhs)
-                }
+        // Multiplication, division, implied multiplication. They are all the
+        // same priority and go left to right, so 6/2(3) is (6/2)*3
+        let mut lhs = self.pl_5()?;
+        loop {
+            if self.match_if_is(Token::Mult) {
+                let rhs = self.pl_5()?;
+                lhs = Box::new(BinaryOp::mult(lhs, rhs));
+            } else if self.match_if_is(Token::Divide) {
+                let rhs = self.pl_5()?;
+                lhs = Box::new(BinaryOp::divide(lhs, rhs));
+            } else if self.implied() {
+                let rhs = self.pl_5()?;
+                lhs = Box::new(BinaryOp::mult(lhs, rhs));
+            } else {
+                return Ok(lhs);
             }
         }
     }
 
+    fn implied(&self) -> bool {
+        // Whether the next token starts another value right after one, which
+        // means they are multiplied: 2A, 3L1, 2sin(X), (1)(2), 2ᴇ3. Negation
+        // starts a value too, 2⁻3 is 2*⁻3
+        match self.token() {
+            Token::Number(_)
+            | Token::Scientific(_)
+            | Token::RealVar(_)
+            | Token::Ans
+            | Token::Str(_)
+            | Token::StrVar(_)
+            | Token::EquVar(_)
+            | Token::ListVar(_)
+            | Token::MatrixVar(_)
+            | Token::Rand
+            | Token::ImaginaryUnit
+            | Token::GetKey
+            | Token::StartTmr
+            | Token::Lparen
+            | Token::Lcurly
+            | Token::Negate
+            | Token::Not
+            | Token::CheckTmr => true,
+            token => functions::lookup(token).is_some() || calculus::arity(token).is_some(),
+        }
+    }
+
     fn pl_5(&mut self) -> PlRes {
         // nPr, nCr
         self.pl_4_5()
     }
 
+    fn negation(&mut self) -> bool {
+        // ⁻ (or -- and ~) is always negation. A plain - only gets this far
+        // where a value has to start, at the start of an expression or after
+        // an operator, ( , or ->, so it is negation too: -5, A*-2, {1,-2}.
+        // Anywhere else pl_7 has already taken it as subtraction
+        self.match_if_is(Token::Negate) || self.match_if_is(Token::Minus)
+    }
+
     fn pl_4_5(&mut self) -> PlRes {
-        // Negation
-        if self.match_if_is(Token::Negate) {
-            let val = self.pl_4()?;
+        // Negation, below ^ so -2^2 is -4
+        if self.negation() {
+            let val = self.pl_4_5()?;
             Ok(Box::new(Negate { val }))
         } else {
             Ok(self.pl_4()?)
@@ -304,20 +451,32 @@ impl<'a> Parser<'a> {
     }
 
     fn pl_4(&mut self) -> PlRes {
-        // Power, xroot
-        let lhs = self.pl_3()?;
-
-        if self.match_if_is(Token::Power) {
-            let rhs = self.pl_3()?;
-            Ok(Box::new(BinaryOp::power(lhs, rhs)))
-        } else {
-            Ok(lhs)
+        // Power, xroot. Unlike most languages, 2^3^2 is (2^3)^2
+        let mut lhs = self.pl_3()?;
+        while self.match_if_is(Token::Power) {
+            // an exponent can be negative without parens, 2^-1
+            let rhs = if self.negation() {
+                Box::new(Negate { val: self.pl_3()? })
+            } else {
+                self.pl_3()?
+            };
+            lhs = Box::new(BinaryOp::power(lhs, rhs));
         }
+        Ok(lhs)
     }
 
     fn pl_3(&mut self) -> PlRes {
-        // Functions that follow their argument (eg !)
-        self.pl_2()
+        // Functions that follow their argument (eg !, ², ⁻¹)
+        let mut val = self.pl_2()?;
+        loop {
+            let power = match self.token() {
+                Token::Square => 2.0,
+                Token::Inverse => -1.0,
+                _ => return Ok(val),
+            };
+            self.advance();
+            val = Box::new(BinaryOp::power(val, Box::new(Value::NumValue(power))));
+        }
     }
 
     fn pl_2(&mut self) -> PlRes {
@@ -326,6 +485,47 @@ impl<'a> Parser<'a> {
             let val = self.pl_10()?; // todo: should this be "expression" or something?
             self.match_if_is(Token::Rparen);
             Ok(Box::new(Not { val }))
+        } else if self.match_if_is(Token::CheckTmr) {
+            let val = self.pl_10()?;
+            self.match_if_is(Token::Rparen);
+            Ok(Box::new(CheckTmr { val }))
+        } else if let Some(arity) = calculus::arity(self.token()) {
+            // these take an unevaluated expression and the variable it is in
+            let token = self.token().clone();
+            self.advance();
+            let expr = self.pl_10()?;
+            self.match_token(Token::Comma)?;
+            let var = match self.token().clone() {
+                Token::RealVar(name) => Variable::RealVar(name),
+                _ => return Err(ParserError::SyntaxError),
+            };
+            self.advance();
+            let mut args: Vec<ValRef> = Vec::new();
+            while self.match_if_is(Token::Comma) {
+                args.push(self.pl_10()?);
+            }
+            self.match_if_is(Token::Rparen);
+            if !arity.contains(&args.len()) {
+                return Err(ParserError::WrongArgumentCount(token));
+            }
+            Ok(Box::new(Calculus {
+                token,
+                expr,
+                var,
+                args,
+            }))
+        } else if let Some((func, arity)) = functions::lookup(self.token()) {
+            let token = self.token().clone();
+            self.advance();
+            let mut args: Vec<ValRef> = vec![self.pl_10()?];
+            while self.match_if_is(Token::Comma) {
+                args.push(self.pl_10()?);
+            }
+            self.match_if_is(Token::Rparen);
+            if !arity.contains(&args.len()) {
+                return Err(ParserError::WrongArgumentCount(token));
+            }
+            Ok(Box::new(Func { token, args, func }))
         } else {
             self.pl_1()
         }
@@ -366,6 +566,35 @@ impl<'a> Parser<'a> {
                 self.advance();
                 return Ok(Box::new(VarRef { var: Variable::Ans }));
             }
+            Token::Str(string) => {
+                self.advance();
+                Ok(Box::new(Value::StringValue(string)))
+            }
+            Token::StrVar(n) => {
+                self.advance();
+                Ok(Box::new(VarRef {
+                    var: Variable::StrVar(n),
+                }))
+            }
+            Token::EquVar(_) | Token::ListVar(_) | Token::MatrixVar(_) => match self.target()? {
+                Target::Var(var) => Ok(Box::new(VarRef { var })),
+                target => Ok(Box::new(ElementRef { target })),
+            },
+            Token::Rand => {
+                self.advance();
+                Ok(Box::new(VarRef {
+                    var: Variable::Rand,
+                }))
+            }
+            Token::ImaginaryUnit => {
+                self.advance();
+                Ok(Box::new(Value::ComplexValue(Complex::i())))
+            }
+            Token::GetKey | Token::StartTmr => {
+                let token = self.token().clone();
+                self.advance();
+                Ok(Box::new(SourceRead { token }))
+            }
             Token::Lcurly => {
                 // begin literal list
                 // empty literal lists are invalid
@@ -431,12 +660,64 @@ impl<'a> Parser<'a> {
                 self.match_token(Token::EndOfLine)?;
                 Ok(Statement::Command(Command::End))
             }
+            Token::Stop => {
+                self.advance();
+                self.match_token(Token::EndOfLine)?;
+                Ok(Statement::Command(Command::Stop))
+            }
             Token::Disp => {
                 self.advance();
                 let val = self.pl_10()?;
                 self.match_token(Token::EndOfLine)?;
                 Ok(Statement::Command(Command::Disp(val)))
             }
+            Token::Output => {
+                // Output(row,column,value)
+                self.advance();
+                let row = self.pl_10()?;
+                self.match_token(Token::Comma)?;
+                let col = self.pl_10()?;
+                self.match_token(Token::Comma)?;
+                let val = self.pl_10()?;
+                self.match_if_is(Token::Rparen);
+                self.match_token(Token::EndOfLine)?;
+                Ok(Statement::Command(Command::Output(row, col, val)))
+            }
+            Token::Input => {
+                // Input A or Input "TEXT",A. A bare Input is for the graph
+                // screen, which tib doesn't have
+                self.advance();
+                if self.token() == &Token::EndOfLine || !self.more_tokens() {
+                    return Err(ParserError::NotYetImplemented(Token::Input));
+                }
+                let next_is_end = matches!(
+                    self.tokens.get(self.i + 1),
+                    Some(Token::EndOfLine) | Some(Token::EndOfInput)
+                );
+                let prompt = if next_is_end {
+                    None
+                } else {
+                    let prompt = self.pl_10()?;
+                    self.match_token(Token::Comma)?;
+                    Some(prompt)
+                };
+                let var = self.input_variable()?;
+                self.match_token(Token::EndOfLine)?;
+                Ok(Statement::Command(Command::Input(prompt, var)))
+            }
+            Token::Prompt => {
+                // Prompt A,B,...
+                self.advance();
+                let mut vars: Vec<Variable> = Vec::new();
+                loop {
+                    vars.push(self.input_variable()?);
+                    if !self.match_if_is(Token::Comma) {
+                        break;
+                    }
+                }
+                self.match_token(Token::EndOfLine)?;
+                Ok(Statement::Command(Command::Prompt(vars)))
+            }
             Token::For => {
                 self.advance();
                 // syntax is a variable, start, stop [inc]
@@ -487,42 +768,154 @@ impl<'a> Parser<'a> {
                 self.advance();
                 Ok(Statement::Command(Command::Goto(name.clone())))
             }
-            Token::IncrementSkip => {
+            Token::EquToString | Token::StringToEqu => {
+                // Equ>String(Y1,Str1) and String>Equ(Str1,Y1)
+                let to_string = self.token() == &Token::EquToString;
                 self.advance();
-                if let Token::RealVar(name) = self.token().clone() {
-                    self.advance();
-                    self.match_token(Token::Comma)?;
-                    let value = self.pl_10()?;
-                    self.match_if_is(Token::Rparen);
-
-                    Ok(Statement::Command(Command::IncrementSkip(
-                        Variable::RealVar(name.clone()),
-                        value,
-                    )))
+                let from = self.equation_or_string(!to_string)?;
+                self.match_token(Token::Comma)?;
+                let to = self.equation_or_string(to_string)?;
+                self.match_if_is(Token::Rparen);
+                self.match_token(Token::EndOfLine)?;
+                Ok(Statement::Command(if to_string {
+                    Command::EquToString(from, to)
                 } else {
-                    Err(ParserError::SyntaxError)
+                    Command::StringToEqu(to, from)
+                }))
+            }
+            Token::RealMode | Token::RectMode | Token::PolarMode => {
+                let mode = match self.token() {
+                    Token::RealMode => NumberMode::Real,
+                    Token::RectMode => NumberMode::Rect,
+                    _ => NumberMode::Polar,
+                };
+                self.advance();
+                self.match_token(Token::EndOfLine)?;
+                Ok(Statement::Command(Command::Mode(mode)))
+            }
+            Token::DelVar | Token::Archive | Token::UnArchive => {
+                let command = self.token().clone();
+                self.advance();
+                let var = self.stored_variable()?;
+                if command == Token::DelVar {
+                    // DelVar doesn't need a colon after it, DelVar ADisp A is
+                    // two statements
+                    self.match_if_is(Token::EndOfLine);
+                } else {
+                    self.match_token(Token::EndOfLine)?;
                 }
+                Ok(Statement::Command(match command {
+                    Token::DelVar => Command::DelVar(var),
+                    Token::Archive => Command::Archive(var),
+                    _ => Command::UnArchive(var),
+                }))
             }
-            Token::DecrementSkip => {
+            Token::ClrList => {
+                // ClrList L1,L2,...
                 self.advance();
-                if let Token::RealVar(name) = self.token().clone() {
+                let mut lists: Vec<String> = Vec::new();
+                loop {
+                    match self.token().clone() {
+                        Token::ListVar(name) => lists.push(name),
+                        _ => return Err(ParserError::SyntaxError),
+                    }
                     self.advance();
-                    self.match_token(Token::Comma)?;
-                    let value = self.pl_10()?;
-                    self.match_if_is(Token::Rparen);
+                    if !self.match_if_is(Token::Comma) {
+                        break;
+                    }
+                }
+                self.match_token(Token::EndOfLine)?;
+                Ok(Statement::Command(Command::ClrList(lists)))
+            }
+            Token::ClrAllLists => {
+                self.advance();
+                self.match_token(Token::EndOfLine)?;
+                Ok(Statement::Command(Command::ClrAllLists))
+            }
+            Token::IncrementSkip | Token::DecrementSkip => {
+                // IS>(A,5) and DS<(L1(2),0). Only reals and elements of lists
+                // and matrices can be counted
+                let increment = self.token() == &Token::IncrementSkip;
+                self.advance();
+                let target = self.target()?;
+                match target {
+                    Target::Var(Variable::RealVar(_))
+                    | Target::ListElement(..)
+                    | Target::MatrixElement(..) => (),
+                    _ => return Err(ParserError::SyntaxError),
+                }
+                self.match_token(Token::Comma)?;
+                let value = self.pl_10()?;
+                self.match_if_is(Token::Rparen);
 
-                    Ok(Statement::Command(Command::DecrementSkip(
-                        Variable::RealVar(name.clone()),
-                        value,
-                    )))
+                Ok(Statement::Command(if increment {
+                    Command::IncrementSkip(target, value)
                 } else {
-                    Err(ParserError::SyntaxError)
-                }
+                    Command::DecrementSkip(target, value)
+                }))
             }
             _ => Err(ParserError::NotYetImplemented(self.token().clone())),
         }
     }
 
+    fn target(&mut self) -> Result<Target, ParserError> {
+        // A variable, and for lists and matrices an optional element after it.
+        // The closing paren is optional like everywhere else
+        let var = match Variable::from_token(self.token()) {
+            Some(var) => var,
+            None => return Err(ParserError::SyntaxError),
+        };
+        self.advance();
+        let indexed = self.token() == &Token::Lparen;
+        match var {
+            Variable::ListVar(name) if indexed => {
+                self.advance();
+                let i = self.pl_10()?;
+                self.match_if_is(Token::Rparen);
+                Ok(Target::ListElement(name, i))
+            }
+            Variable::MatrixVar(name) if indexed => {
+                self.advance();
+                let row = self.pl_10()?;
+                self.match_token(Token::Comma)?;
+                let col = self.pl_10()?;
+                self.match_if_is(Token::Rparen);
+                Ok(Target::MatrixElement(name, row, col))
+            }
+            var => Ok(Target::Var(var)),
+        }
+    }
+
+    fn stored_variable(&mut self) -> Result<Variable, ParserError> {
+        // a variable that lives in memory, for DelVar and Archive. rand and
+        // Ans don't count
+        match Variable::from_token(self.token()) {
+            Some(Variable::Rand) | None => Err(ParserError::SyntaxError),
+            Some(var) => {
+                self.advance();
+                Ok(var)
+            }
+        }
+    }
+
+    fn input_variable(&mut self) -> Result<Variable, ParserError> {
+        // what Input and Prompt can store to, which isn't Ans either
+        match self.stored_variable()? {
+            Variable::Ans => Err(ParserError::SyntaxError),
+            var => Ok(var),
+        }
+    }
+
+    fn equation_or_string(&mut self, string: bool) -> Result<Variable, ParserError> {
+        let var = match (self.token(), string) {
+            (Token::StrVar(n), true) => Variable::StrVar(*n),
+            (Token::EquVar(n), false) => Variable::EquVar(*n),
+            _ => return Err(ParserError::SyntaxError),
+        };
+        self.advance();
+        Ok(var)
+    }
+
     fn is_command(&mut self) -> bool {
         // Check if the next statement is a command or an expression
         match self.token() {
@@ -532,11 +925,25 @@ impl<'a> Parser<'a> {
             | Token::While
             | Token::Repeat
             | Token::End
+            | Token::Stop
             | Token::Then
             | Token::Lbl(_)
             | Token::Goto(_)
             | Token::IncrementSkip
             | Token::DecrementSkip
+            | Token::RealMode
+            | Token::RectMode
+            | Token::PolarMode
+            | Token::EquToString
+            | Token::StringToEqu
+            | Token::DelVar
+            | Token::ClrList
+            | Token::ClrAllLists
+            | Token::Archive
+            | Token::UnArchive
+            | Token::Output
+            | Token::Input
+            | Token::Prompt
             | Token::Disp => true,
             _ => false,
         }
@@ -556,8 +963,10 @@ impl<'a> Parser<'a> {
                 self.advance();
                 continue;
             }
+            let line = self.lines.get(self.i).copied().unwrap_or(0);
             let state = self.statement()?;
             self.prog.statements.push(state);
+            self.prog.lines.push(line);
         }
 
         Ok(())
@@ -572,15 +981,25 @@ pub enum ParserError {
     RecursiveList,
     EmptyList,
     SyntaxError,
+    WrongArgumentCount(Token),
 }
 
 pub fn parse(tokens: &Vec<Token>, program: &mut Program) -> Result<(), ParserError> {
+    parse_lines(tokens, &[], program)
+}
+
+pub fn parse_lines(
+    tokens: &Vec<Token>,
+    lines: &[usize],
+    program: &mut Program,
+) -> Result<(), ParserError> {
     // Will modify program, that is this functions output
     // The basic idea of this function is that we parse tokens and add the resulting statements
     // into program. If
 
     let mut parser = Parser {
         tokens,
+        lines,
         prog: program,
         i: 0,
         in_list: false,
@@ -589,15 +1008,140 @@ pub fn parse(tokens: &Vec<Token>, program: &mut Program) -> Result<(), ParserErr
     parser.tib_program()
 }
 
+pub fn parse_expression(tokens: &Vec<Token>) -> Result<ValRef, ParserError> {
+    // Parse a lone expression, for things like expr( that turn strings into
+    // code at runtime. Anything after the expression is an error
+    let mut scratch = Program::with_options(&Options::new());
+    let mut parser = Parser {
+        tokens,
+        lines: &[],
+        prog: &mut scratch,
+        i: 0,
+        in_list: false,
+    };
+
+    let expr = parser.pl_10()?;
+    match parser.token() {
+        Token::EndOfInput => Ok(expr),
+        token => Err(ParserError::UnexpectedToken(token.clone())),
+    }
+}
+
 #[cfg(test)]
 mod tests {
     use super::*;
     use crate::lexer::*;
     #[test]
     fn test_simple_addition() {
-        let mut program = Program::new();
+        let mut program = Program::with_options(&Options::new());
 
         assert!(parse(&lex_str("2+2\n"), &mut program).is_ok());
         // todo: validate the statements in program
     }
+
+    fn ast(input: &str) -> String {
+        let mut program = Program::with_options(&Options::new());
+        parse(&lex_str(input), &mut program).unwrap();
+        format!("{:?}", program.statements[0])
+    }
+
+    #[test]
+    fn test_associativity() {
+        // every level goes left to right, like on the calculator
+        assert_eq!(ast("10-3-2\n"), "Expression(Minus(Minus(10.0, 3.0), 2.0))");
+        assert_eq!(ast("10-3+2\n"), "Expression(Plus(Minus(10.0, 3.0), 2.0))");
+        assert_eq!(ast("8/4/2\n"), "Expression(Divide(Divide(8.0, 4.0), 2.0))");
+        assert_eq!(ast("2^3^2\n"), "Expression(Power(Power(2.0, 3.0), 2.0))");
+        assert_eq!(ast("1<2<3\n"), "Expression(Less(Less(1.0, 2.0), 3.0))");
+        assert_eq!(
+            ast("1=2!=3\n"),
+            "Expression(NotEqual(Equal(1.0, 2.0), 3.0))"
+        );
+        assert_eq!(
+            ast("1 and 0 and 1\n"),
+            "Expression(And(And(1.0, 0.0), 1.0))"
+        );
+        assert_eq!(ast("1 or 0 xor 1\n"), "Expression(Xor(Or(1.0, 0.0), 1.0))");
+        // and still respects priority
+        assert_eq!(
+            ast("1+2*3-4\n"),
+            "Expression(Minus(Plus(1.0, Mult(2.0, 3.0)), 4.0))"
+        );
+        assert_eq!(ast("1 or 0 and 0\n"), "Expression(Or(1.0, And(0.0, 0.0)))");
+    }
+
+    #[test]
+    fn test_negation() {
+        let a = "Var(RealVar('A'))";
+        // - where a value starts is negation
+        assert_eq!(ast("-5\n"), "Expression(Neg(5.0))");
+        assert_eq!(ast("A*-2\n"), format!("Expression(Mult({}, Neg(2.0)))", a));
+        assert_eq!(ast("(-1)\n"), "Expression(Neg(1.0))");
+        assert_eq!(ast("{1,-2}\n"), "Expression([1.0, Neg(2.0)])");
+        assert_eq!(
+            ast("-5->A\n"),
+            format!("Expression(Store(Neg(5.0)->{}))", a)
+        );
+        assert_eq!(ast("2^-1\n"), "Expression(Power(2.0, Neg(1.0)))");
+        assert_eq!(ast("- -2\n"), "Expression(Neg(Neg(2.0)))");
+        // and anywhere else subtraction
+        assert_eq!(ast("10-3\n"), "Expression(Minus(10.0, 3.0))");
+        assert_eq!(ast("10--3\n"), "Expression(Mult(10.0, Neg(3.0)))");
+        assert_eq!(ast("10- -3\n"), "Expression(Minus(10.0, Neg(3.0)))");
+        assert_eq!(ast("(1)-2\n"), "Expression(Minus(1.0, 2.0))");
+        // ⁻ never is
+        assert_eq!(ast("⁻5+1\n"), "Expression(Plus(Neg(5.0), 1.0))");
+        assert_eq!(ast("2⁻3\n"), "Expression(Mult(2.0, Neg(3.0)))");
+        // below ^, above everything else
+        assert_eq!(ast("-2^2\n"), "Expression(Neg(Power(2.0, 2.0)))");
+        assert_eq!(ast("-2*3\n"), "Expression(Mult(Neg(2.0), 3.0))");
+        assert_eq!(ast("-A²\n"), format!("Expression(Neg(Power({}, 2.0)))", a));
+    }
+
+    #[test]
+    fn test_implied_multiplication() {
+        let a = "Var(RealVar('A'))";
+        assert_eq!(ast("2A\n"), format!("Expression(Mult(2.0, {}))", a));
+        assert_eq!(
+            ast("A(B+1)\n"),
+            format!("Expression(Mult({}, Plus(Var(RealVar('B')), 1.0)))", a)
+        );
+        assert_eq!(
+            ast("2sqrt(A)\n"),
+            format!("Expression(Mult(2.0, Sqrt[{}]))", a)
+        );
+        assert_eq!(ast("3L1\n"), "Expression(Mult(3.0, Var(ListVar(\"L1\"))))");
+        assert_eq!(ast("(1)(2)\n"), "Expression(Mult(1.0, 2.0))");
+        assert_eq!(
+            ast("A B\n"),
+            format!("Expression(Mult({}, Var(RealVar('B'))))", a)
+        );
+        // same priority as * and /, left to right
+        assert_eq!(ast("2(3)4\n"), "Expression(Mult(Mult(2.0, 3.0), 4.0))");
+        assert_eq!(ast("6/2(3)\n"), "Expression(Mult(Divide(6.0, 2.0), 3.0))");
+        assert_eq!(
+            ast("2A^2\n"),
+            format!("Expression(Mult(2.0, Power({}, 2.0)))", a)
+        );
+        assert_eq!(ast("2--3\n"), "Expression(Mult(2.0, Neg(3.0)))");
+        assert_eq!(
+            ast("2+3A\n"),
+            format!("Expression(Plus(2.0, Mult(3.0, {})))", a)
+        );
+        // L1( is an element, not a product
+        assert_eq!(
+            ast("L1(2)3\n"),
+            "Expression(Mult(Element(ListElement(\"L1\", 2.0)), 3.0))"
+        );
+
+        // errors in what follows aren't swallowed
+        let mut program = Program::with_options(&Options::new());
+        match parse(&lex_str("2sqrt()\n"), &mut program) {
+            Err(ParserError::UnexpectedToken(Token::Rparen)) => (),
+            result => panic!("{:?}", result),
+        }
+        // and long products don't take forever
+        let long = format!("{}\n", "A".repeat(500));
+        assert!(parse(&lex_str(&long), &mut program).is_ok());
+    }
 }
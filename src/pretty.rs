@@ -0,0 +1,349 @@
+use crate::lexer::{self, Token};
+
+// Every token can be written three ways: how TI Connect CE and tibasicdev
+// print it (→, ≠, θ), how TokenIDE spells it in plain ASCII (->, !=, theta),
+// and how tib has always spelled it. The lexer accepts all of them, this turns
+// tokens back into text in whichever one you like.
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Style {
+    Unicode,
+    Ascii,
+    Tib,
+}
+
+impl Style {
+    pub fn from_name(name: &str) -> Option<Style> {
+        match name {
+            "unicode" => Some(Style::Unicode),
+            "ascii" => Some(Style::Ascii),
+            "tib" => Some(Style::Tib),
+            _ => None,
+        }
+    }
+
+    fn pick(self, spellings: [&str; 3]) -> String {
+        let [unicode, ascii, tib] = spellings;
+        String::from(match self {
+            Style::Unicode => unicode,
+            Style::Ascii => ascii,
+            Style::Tib => tib,
+        })
+    }
+}
+
+fn same(spelling: &str) -> [&str; 3] {
+    [spelling, spelling, spelling]
+}
+
+fn spellings(token: &Token) -> Option<[&'static str; 3]> {
+    // (unicode, ascii, tib) for the tokens that don't carry any data.
+    // Keywords keep a trailing space so programs come out readable
+    Some(match token {
+        Token::Or => same(" or "),
+        Token::Xor => same(" xor "),
+        Token::And => same(" and "),
+        Token::Not => same("not("),
+        Token::Lparen => same("("),
+        Token::Rparen => same(")"),
+        Token::Lcurly => same("{"),
+        Token::Rcurly => same("}"),
+        Token::Comma => same(","),
+        Token::Equal => same("="),
+        Token::NotEqual => ["≠", "!=", "!="],
+        Token::Greater => same(">"),
+        Token::GreaterEqual => ["≥", ">=", ">="],
+        Token::Less => same("<"),
+        Token::LessEqual => ["≤", "<=", "<="],
+        Token::Plus => same("+"),
+        Token::Minus => same("-"),
+        Token::Negate => ["⁻", "~", "--"],
+        Token::Mult => same("*"),
+        Token::Divide => same("/"),
+        Token::Power => same("^"),
+        Token::Store => ["→", "->", "->"],
+        // there is no ASCII spelling of these, so they come out as the power
+        // they mean
+        Token::Square => ["²", "^2", "^2"],
+        Token::Inverse => ["⁻¹", "^~1", "^--1"],
+        Token::Ans => same("Ans"),
+        Token::Rand => same("rand"),
+        Token::GetKey => same("getKey"),
+        Token::StartTmr => same("startTmr"),
+        Token::CheckTmr => same("checkTmr("),
+        Token::ImaginaryUnit => ["𝑖", "[i]", "i"],
+        Token::Sqrt => ["√(", "sqrt(", "sqrt("],
+        Token::Abs => same("abs("),
+        Token::RealPart => same("real("),
+        Token::ImagPart => same("imag("),
+        Token::Conj => same("conj("),
+        Token::Angle => same("angle("),
+        Token::Solve => same("solve("),
+        Token::NDeriv => same("nDeriv("),
+        Token::FnInt => same("fnInt("),
+        Token::FMin => same("fMin("),
+        Token::FMax => same("fMax("),
+        Token::Sigma => ["Σ(", "Sigma(", "Σ("],
+        Token::Sub => same("sub("),
+        Token::InStr => ["inString(", "inString(", "inStr("],
+        Token::Length => same("length("),
+        Token::Expr => same("expr("),
+        Token::ToString => same("toString("),
+        Token::EvalFunc => same("eval("),
+        Token::If => same("If "),
+        Token::Then => same("Then"),
+        Token::Else => same("Else"),
+        Token::For => same("For("),
+        Token::While => same("While "),
+        Token::Repeat => same("Repeat "),
+        Token::End => same("End"),
+        Token::Stop => same("Stop"),
+        Token::IncrementSkip => same("IS>("),
+        Token::DecrementSkip => same("DS<("),
+        Token::Disp => same("Disp "),
+        Token::Output => same("Output("),
+        Token::Input => same("Input "),
+        Token::Prompt => same("Prompt "),
+        Token::EquToString => ["Equ►String(", "Equ>String(", "Equ>String("],
+        Token::StringToEqu => ["String►Equ(", "String>Equ(", "String>Equ("],
+        Token::DelVar => same("DelVar "),
+        Token::ClrList => same("ClrList "),
+        Token::ClrAllLists => same("ClrAllLists"),
+        Token::Archive => same("Archive "),
+        Token::UnArchive => same("UnArchive "),
+        Token::RealMode => same("Real"),
+        Token::RectMode => same("a+bi"),
+        Token::PolarMode => ["re^θi", "re^thetai", "re^θi"],
+        Token::EndOfLine => same("\n"),
+        _ => return None,
+    })
+}
+
+fn subscript(digit: u8) -> char {
+    std::char::from_u32('₀' as u32 + digit as u32).unwrap()
+}
+
+fn theta(style: Style, name: &str) -> String {
+    let theta = style.pick(["θ", "theta", "Theta"]);
+    name.replace('θ', &theta)
+}
+
+fn spell(token: &Token, style: Style) -> String {
+    if let Some(spellings) = spellings(token) {
+        return style.pick(spellings);
+    }
+    match token {
+        Token::Number(n) => n.to_string(),
+        Token::Scientific(n) => {
+            let e = style.pick(["ᴇ", "|E", "e"]);
+            if *n < 0 {
+                format!("{}{}{}", e, style.pick(["⁻", "~", "-"]), -n)
+            } else {
+                format!("{}{}", e, n)
+            }
+        }
+        Token::RealVar(name) => theta(style, &name.to_string()),
+        Token::Str(string) => format!("\"{}\"", string),
+        Token::StrVar(n) => format!("Str{}", n),
+        Token::EquVar(n) if style == Style::Unicode => format!("Y{}", subscript(*n)),
+        Token::EquVar(n) => format!("Y{}", n),
+        Token::ListVar(name) if lexer::lex(name).is_ok_and(|t| t.len() == 2) => {
+            // L1 through L6
+            let digit = name.as_bytes()[1] - b'0';
+            match style {
+                Style::Unicode => format!("L{}", subscript(digit)),
+                _ => name.clone(),
+            }
+        }
+        Token::ListVar(name) => {
+            // custom lists, which tib had no spelling of before. Their names
+            // keep θ in every style since the lexer reads them character by
+            // character
+            format!("{}{}", style.pick(["ʟ", "|L", "|L"]), name)
+        }
+        Token::MatrixVar(name) => format!("[{}]", name),
+        Token::Lbl(label) => format!("Lbl {}", label),
+        Token::Goto(label) => format!("Goto {}", label),
+        _ => String::new(),
+    }
+}
+
+fn runs_together(left: &str, right: &str) -> bool {
+    // whether writing two bits of source next to each other changes what
+    // they lex as
+    let lex = |text: &str| lexer::lex(&String::from(text)).ok();
+    match (lex(left), lex(right), lex(&format!("{}{}", left, right))) {
+        (Some(mut apart), Some(right), Some(joined)) => {
+            apart.pop();
+            apart.extend(right);
+            apart != joined
+        }
+        _ => true,
+    }
+}
+
+pub fn pretty(tokens: &[Token], style: Style) -> String {
+    // Write tokens out as source. Two tokens that would run together into
+    // something else (like L and 1 into L1) get a space between them
+    let mut out = String::new();
+    let mut last = String::new();
+    let mut previous: Option<&Token> = None;
+    for token in tokens.iter().filter(|t| **t != Token::EndOfInput) {
+        // a - where a value starts is negation, which the calculator only
+        // has ⁻ for. tib reads the - as negation already, so leave it be
+        let negation = *token == Token::Minus && !previous.is_some_and(lexer::ends_value);
+        let text = if negation && style != Style::Tib {
+            spell(&Token::Negate, style)
+        } else {
+            spell(token, style)
+        };
+        previous = Some(token);
+        if runs_together(&last, &text) {
+            out.push(' ');
+        }
+        out.push_str(&text);
+        last = text;
+    }
+    out
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::lex_str;
+
+    #[test]
+    fn test_every_spelling_lexes() {
+        let fixed = vec![
+            Token::Or,
+            Token::Xor,
+            Token::And,
+            Token::Not,
+            Token::Lparen,
+            Token::Rparen,
+            Token::Lcurly,
+            Token::Rcurly,
+            Token::Comma,
+            Token::Equal,
+            Token::NotEqual,
+            Token::Greater,
+            Token::GreaterEqual,
+            Token::Less,
+            Token::LessEqual,
+            Token::Plus,
+            Token::Minus,
+            Token::Negate,
+            Token::Mult,
+            Token::Divide,
+            Token::Power,
+            Token::Store,
+            Token::Ans,
+            Token::Rand,
+            Token::GetKey,
+            Token::StartTmr,
+            Token::CheckTmr,
+            Token::ImaginaryUnit,
+            Token::Sqrt,
+            Token::Abs,
+            Token::RealPart,
+            Token::ImagPart,
+            Token::Conj,
+            Token::Angle,
+            Token::Solve,
+            Token::NDeriv,
+            Token::FnInt,
+            Token::FMin,
+            Token::FMax,
+            Token::Sigma,
+            Token::Sub,
+            Token::InStr,
+            Token::Length,
+            Token::Expr,
+            Token::ToString,
+            Token::EvalFunc,
+            Token::If,
+            Token::Then,
+            Token::Else,
+            Token::For,
+            Token::While,
+            Token::Repeat,
+            Token::End,
+            Token::Stop,
+            Token::IncrementSkip,
+            Token::DecrementSkip,
+            Token::Disp,
+            Token::Output,
+            Token::Input,
+            Token::Prompt,
+            Token::EquToString,
+            Token::StringToEqu,
+            Token::DelVar,
+            Token::ClrList,
+            Token::ClrAllLists,
+            Token::Archive,
+            Token::UnArchive,
+            Token::RealMode,
+            Token::RectMode,
+            Token::PolarMode,
+        ];
+        for token in fixed.iter() {
+            for spelling in spellings(token).unwrap().iter() {
+                assert_eq!(
+                    lex_str(spelling.trim()),
+                    [token.clone(), Token::EndOfInput],
+                    "{:?}",
+                    spelling
+                );
+            }
+        }
+    }
+
+    #[test]
+    fn test_unicode_aliases() {
+        assert_eq!(lex_str("⁻1ᴇ⁻5→θ"), lex_str("--1e-5->Theta"),);
+        assert_eq!(lex_str("A≠B≤C≥D"), lex_str("A!=B<=C>=D"));
+        assert_eq!(lex_str("√(X²)"), lex_str("sqrt(X²)"));
+        assert_eq!(lex_str("L₁→ʟABC"), lex_str("L1->|LABC"));
+        assert_eq!(lex_str("Y₁"), [Token::EquVar(1), Token::EndOfInput]);
+        assert_eq!(lex_str("|E~2"), [Token::Scientific(-2), Token::EndOfInput]);
+    }
+
+    #[test]
+    fn test_pretty() {
+        let tokens = lex_str("If A!=--1\nDisp L1,sqrt(X),Theta->|LAB");
+        assert_eq!(
+            pretty(&tokens, Style::Unicode),
+            "If A≠⁻1\nDisp L₁,√(X),θ→ʟAB"
+        );
+        assert_eq!(
+            pretty(&tokens, Style::Ascii),
+            "If A!=~1\nDisp L1,sqrt(X),theta->|LAB"
+        );
+        assert_eq!(
+            pretty(&tokens, Style::Tib),
+            "If A!=--1\nDisp L1,sqrt(X),Theta->|LAB"
+        );
+        // L and 1 would be L1 if they were written together
+        assert_eq!(pretty(&lex_str("L 1"), Style::Tib), "L 1");
+    }
+
+    #[test]
+    fn test_negation() {
+        let tokens = lex_str("-5->A\nA-(-B)\nDisp {1,-2}");
+        assert_eq!(pretty(&tokens, Style::Unicode), "⁻5→A\nA-(⁻B)\nDisp {1,⁻2}");
+        assert_eq!(pretty(&tokens, Style::Ascii), "~5->A\nA-(~B)\nDisp {1,~2}");
+        assert_eq!(pretty(&tokens, Style::Tib), "-5->A\nA-(-B)\nDisp {1,-2}");
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let source = "For(I,1,5)\nIf I≥2 and I≠4\nDisp I,2ᴇ⁻3,ʟθ1\nEnd\n\"HI\"→Str1";
+        let tokens = lex_str(source);
+        for style in &[Style::Unicode, Style::Ascii, Style::Tib] {
+            assert_eq!(lex_str(&pretty(&tokens, *style)), tokens);
+        }
+        // ² only round trips in its meaning outside of unicode
+        assert_eq!(pretty(&lex_str("X²"), Style::Unicode), "X²");
+        assert_eq!(pretty(&lex_str("X²"), Style::Ascii), "X^2");
+    }
+}
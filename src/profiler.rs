@@ -0,0 +1,224 @@
+use std::collections::HashMap;
+use std::time::Duration;
+
+use crate::executor::Program;
+use crate::parser::{Command, Statement};
+
+// tib --profile: how many times each statement ran and how long it took,
+// added up per source line and per Lbl when the program is done. A statement
+// belongs to the last Lbl above it, which is usually how TI-BASIC programs
+// are split into subroutines.
+
+// how many of the hottest lines to print
+const TOP_LINES: usize = 10;
+
+#[derive(Debug, Default)]
+pub struct Profile {
+    // indexed by statement, grows as the REPL adds statements
+    counts: Vec<u64>,
+    times: Vec<Duration>,
+}
+
+#[derive(Debug, Default, Clone, PartialEq)]
+pub struct Entry {
+    pub count: u64,
+    pub time: Duration,
+}
+
+impl Profile {
+    pub fn new() -> Profile {
+        Profile::default()
+    }
+
+    pub fn record(&mut self, pc: usize, time: Duration) {
+        if pc >= self.counts.len() {
+            self.counts.resize(pc + 1, 0);
+            self.times.resize(pc + 1, Duration::default());
+        }
+        self.counts[pc] += 1;
+        self.times[pc] += time;
+    }
+
+    fn total(&self) -> Entry {
+        Entry {
+            count: self.counts.iter().sum(),
+            time: self.times.iter().sum(),
+        }
+    }
+
+    pub fn by_line(&self, program: &Program) -> Vec<(usize, Entry)> {
+        // hottest first
+        let mut lines: HashMap<usize, Entry> = HashMap::new();
+        for (pc, count) in self.counts.iter().enumerate() {
+            let line = program.lines.get(pc).copied().unwrap_or(0);
+            let entry = lines.entry(line).or_default();
+            entry.count += count;
+            entry.time += self.times[pc];
+        }
+        sorted(lines.into_iter().collect())
+    }
+
+    pub fn by_label(&self, program: &Program) -> Vec<(Option<String>, Entry)> {
+        // None is everything before the first Lbl
+        let mut labels: HashMap<Option<String>, Entry> = HashMap::new();
+        let mut label: Option<String> = None;
+        for (pc, statement) in program.statements.iter().enumerate() {
+            if let Statement::Command(Command::Lbl(name)) = statement {
+                label = Some(name.clone());
+            }
+            if pc >= self.counts.len() {
+                break;
+            }
+            if self.counts[pc] == 0 {
+                continue;
+            }
+            let entry = labels.entry(label.clone()).or_default();
+            entry.count += self.counts[pc];
+            entry.time += self.times[pc];
+        }
+        sorted(labels.into_iter().collect())
+    }
+
+    pub fn report(&self, program: &Program) -> String {
+        let total = self.total();
+        let share = |time: Duration| {
+            if total.time.as_nanos() == 0 {
+                0.0
+            } else {
+                100.0 * time.as_secs_f64() / total.time.as_secs_f64()
+            }
+        };
+        let mut out = format!(
+            "profile: {} statements in {:.3}s\n\n  line      count       time   share\n",
+            total.count,
+            total.time.as_secs_f64()
+        );
+        for (line, entry) in self.by_line(program).iter().take(TOP_LINES) {
+            out.push_str(&format!(
+                "{:>6} {:>10} {:>9.3}s {:>6.1}%\n",
+                line,
+                entry.count,
+                entry.time.as_secs_f64(),
+                share(entry.time)
+            ));
+        }
+        out.push_str("\n  label     count       time   share\n");
+        for (label, entry) in self.by_label(program).iter() {
+            let label = match label {
+                Some(name) => format!("Lbl {}", name),
+                None => String::from("start"),
+            };
+            out.push_str(&format!(
+                "{:>7} {:>9} {:>9.3}s {:>6.1}%\n",
+                label,
+                entry.count,
+                entry.time.as_secs_f64(),
+                share(entry.time)
+            ));
+        }
+        out
+    }
+}
+
+fn sorted<T: Ord>(mut entries: Vec<(T, Entry)>) -> Vec<(T, Entry)> {
+    // most time first, ties by how often they ran and then by name so the
+    // report doesn't shuffle around
+    entries.sort_by(|(a, ea), (b, eb)| {
+        eb.time
+            .cmp(&ea.time)
+            .then(eb.count.cmp(&ea.count))
+            .then(a.cmp(b))
+    });
+    entries
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::executor::*;
+    use crate::lexer::*;
+    use crate::parser::parse_lines;
+
+    fn profiled(input: &str) -> Program {
+        let (tokens, lines) = lex_lines(&String::from(input)).unwrap();
+        let mut options = Options::new();
+        options.profile = true;
+        let mut program = Program::with_options(&options);
+        parse_lines(&tokens, &lines, &mut program).unwrap();
+        execute(&mut program).unwrap();
+        program
+    }
+
+    fn counts<T: Clone>(entries: Vec<(T, Entry)>) -> Vec<(T, u64)> {
+        entries
+            .iter()
+            .map(|(key, e)| (key.clone(), e.count))
+            .collect()
+    }
+
+    #[test]
+    fn test_counts() {
+        let program = profiled("0->A\nFor(I,1,5)\nA+I->A\nEnd\nDisp A\n");
+        let profile = program.profile.as_ref().unwrap();
+        let mut lines = counts(profile.by_line(&program));
+        lines.sort();
+        assert_eq!(lines, vec![(1, 1), (2, 1), (3, 5), (4, 5), (5, 1)]);
+        assert_eq!(profile.total().count, 13);
+    }
+
+    #[test]
+    fn test_labels() {
+        let program = profiled("0->A\nLbl A\nA+1->A\nIf A<3\nGoto A\nLbl B\nDisp A\n");
+        let profile = program.profile.as_ref().unwrap();
+        let mut labels = counts(profile.by_label(&program));
+        labels.sort();
+        // Lbl A itself only runs when it's fallen into, Goto skips it
+        assert_eq!(
+            labels,
+            vec![
+                (None, 1),
+                (Some(String::from("A")), 9),
+                (Some(String::from("B")), 2)
+            ]
+        );
+
+        let report = profile.report(&program);
+        assert!(report.starts_with("profile: 12 statements in "));
+        assert!(report.contains("  Lbl A         9 "));
+    }
+
+    #[test]
+    fn test_sorted() {
+        let ms = Duration::from_millis;
+        let entries = vec![
+            (
+                1,
+                Entry {
+                    count: 5,
+                    time: ms(1),
+                },
+            ),
+            (
+                2,
+                Entry {
+                    count: 1,
+                    time: ms(3),
+                },
+            ),
+            (
+                3,
+                Entry {
+                    count: 9,
+                    time: ms(1),
+                },
+            ),
+        ];
+        assert_eq!(
+            sorted(entries)
+                .iter()
+                .map(|(line, _)| *line)
+                .collect::<Vec<usize>>(),
+            vec![2, 3, 1]
+        );
+    }
+}
@@ -0,0 +1,582 @@
+use std::env;
+use std::fs;
+use std::io;
+use std::io::Write;
+use std::path::PathBuf;
+use std::process;
+
+use rustyline::completion::Completer;
+use rustyline::error::ReadlineError;
+use rustyline::highlight::Highlighter;
+use rustyline::hint::Hinter;
+use rustyline::history::DefaultHistory;
+use rustyline::validate::Validator;
+use rustyline::{Editor, Helper};
+
+use crate::debugger;
+use crate::executor::{Context, NumberMode, Options, Program};
+use crate::lexer;
+use crate::lexer::Token;
+use crate::optimize;
+use crate::parser;
+use crate::parser::{ParserError, Value, Variable};
+use crate::tivars;
+
+// The REPL's line editor. Arrow keys move around and bring back earlier lines,
+// which are kept in ~/.tib_history between sessions, Tab completes commands
+// and variables, and a block or an expression that isn't finished yet asks
+// for another line instead of running.
+//
+// Lines like :vars are for tib rather than the program, see META_HELP.
+
+const HISTORY_FILE: &str = ".tib_history";
+
+// every command and function tib knows, the way they're typed
+const COMMANDS: &[&str] = &[
+    "Ans",
+    "Archive ",
+    "ClrAllLists",
+    "ClrList ",
+    "DS<(",
+    "DelVar ",
+    "Disp ",
+    "Else",
+    "End",
+    "Equ>String(",
+    "For(",
+    "Goto ",
+    "IS>(",
+    "If ",
+    "Input ",
+    "Lbl ",
+    "Output(",
+    "Prompt ",
+    "Real",
+    "Repeat ",
+    "Sigma(",
+    "Stop",
+    "String>Equ(",
+    "Then",
+    "UnArchive ",
+    "While ",
+    "a+bi",
+    "abs(",
+    "and",
+    "angle(",
+    "checkTmr(",
+    "conj(",
+    "eval(",
+    "expr(",
+    "fMax(",
+    "fMin(",
+    "fnInt(",
+    "getKey",
+    "imag(",
+    "inString(",
+    "length(",
+    "nDeriv(",
+    "not(",
+    "or",
+    "rand",
+    "re^thetai",
+    "real(",
+    "solve(",
+    "sqrt(",
+    "startTmr",
+    "sub(",
+    "toString(",
+    "xor",
+];
+
+const META_HELP: &str = "\
+:vars           every variable that has been set
+:reset          forget every variable and statement, like starting tib again
+:load FILE      set variables from a calculator file (.8xn, .8xl, .8xm, .8xs
+                or .8xg)
+:save FILE      save every variable to a group file (.8xg)
+:ast CODE       how CODE parses, without running it
+:tokens CODE    what CODE lexes to
+:mode [MODE]    show the number mode, or change it to Real, a+bi or re^θi
+:help           this list";
+
+const META: &[&str] = &[
+    "vars", "reset", "load", "save", "ast", "tokens", "mode", "help",
+];
+
+#[derive(Debug, PartialEq)]
+pub enum Pending {
+    // ready to run
+    Done,
+    // this many blocks are still open, or a line needs the one after it
+    Block(usize),
+    // the last line stopped partway through an expression, eg {1,2,
+    Expression,
+}
+
+pub fn pending(input: &str) -> Pending {
+    // Parse what has been typed so far on its own to see if it is finished.
+    // Anything that doesn't lex or parse is done too, running it shows the
+    // error
+    let tokens = match lexer::lex(&format!("{}\n", input)) {
+        Ok(tokens) => tokens,
+        Err(_) => return Pending::Done,
+    };
+    let mut scratch = Program::with_options(&Options::new());
+    match parser::parse(&tokens, &mut scratch) {
+        Ok(()) => (),
+        Err(ParserError::UnexpectedToken(Token::EndOfLine))
+        | Err(ParserError::UnexpectedToken(Token::EndOfInput)) => return Pending::Expression,
+        Err(_) => return Pending::Done,
+    }
+    let mut depth = scratch.open_blocks();
+    // If without a Then, IS>( and DS<( need the statement they guard
+    if scratch.statements.last().is_some_and(optimize::guards_next) {
+        depth += 1;
+    }
+    match depth {
+        0 => Pending::Done,
+        depth => Pending::Block(depth),
+    }
+}
+
+fn is_word(c: char) -> bool {
+    c.is_alphanumeric() || "θʟ|[".contains(c)
+}
+
+pub fn complete(line: &str, pos: usize, variables: &[String]) -> (usize, Vec<String>) {
+    // Everything that starts with the word before the cursor, and where that
+    // word starts
+    let start = line[..pos]
+        .char_indices()
+        .rev()
+        .take_while(|(_, c)| is_word(*c))
+        .last()
+        .map_or(pos, |(i, _)| i);
+    let word = line[start..pos].replace("|L", "ʟ");
+    if word.is_empty() {
+        return (pos, Vec::new());
+    }
+    // :vars and friends only come first
+    if line[..start].trim() == ":" {
+        let found = META
+            .iter()
+            .filter(|name| name.starts_with(&word))
+            .map(|name| name.to_string())
+            .collect();
+        return (start, found);
+    }
+    let mut found: Vec<String> = variables
+        .iter()
+        .map(|name| name.as_str())
+        .chain(COMMANDS.iter().copied())
+        .filter(|name| name.starts_with(&word))
+        .map(String::from)
+        .collect();
+    found.sort();
+    found.dedup();
+    (start, found)
+}
+
+pub fn is_meta(line: &str) -> bool {
+    // A : in front of anything else is just TI-BASIC's statement separator
+    match line.trim().strip_prefix(':') {
+        Some(rest) => META.contains(&rest.split(' ').next().unwrap_or("")),
+        None => false,
+    }
+}
+
+fn mode_name(mode: &NumberMode) -> &'static str {
+    match mode {
+        NumberMode::Real => "Real",
+        NumberMode::Rect => "a+bi",
+        NumberMode::Polar => "re^θi",
+    }
+}
+
+pub fn meta(
+    line: &str,
+    program: &mut Program,
+    options: &Options,
+    out: &mut dyn Write,
+) -> io::Result<()> {
+    // Run one of the commands in META_HELP. None of them touch Ans
+    let line = line.trim().trim_start_matches(':');
+    let (cmd, arg) = match line.split_once(' ') {
+        Some((cmd, arg)) => (cmd, arg.trim()),
+        None => (line, ""),
+    };
+    match cmd {
+        "vars" => debugger::show_vars(&program.ctx, out)?,
+        "reset" => {
+            // Ctrl+C is wired up to this flag
+            let interrupt = program.ctx.interrupt.clone();
+            *program = Program::with_options(options);
+            program.ctx.interrupt = interrupt;
+            writeln!(out, "Done")?;
+        }
+        "load" => {
+            let vars = fs::read(arg)
+                .map_err(|err| err.to_string())
+                .and_then(|bytes| tivars::read_vars(&bytes).map_err(|err| format!("{:?}", err)));
+            match vars {
+                Ok(vars) => {
+                    for (var, val) in vars.iter() {
+                        if let Err(err) = program.ctx.set(var, val.clone()) {
+                            writeln!(out, "can't set {}: {}", var, err.describe())?;
+                        }
+                    }
+                    writeln!(out, "loaded {} variables", vars.len())?;
+                }
+                Err(err) => writeln!(out, "can't load {}: {}", arg, err)?,
+            }
+        }
+        "save" => {
+            let ctx = &program.ctx;
+            let vars: Vec<(Variable, Value, bool)> = ctx
+                .variables()
+                .into_iter()
+                .map(|(var, val)| {
+                    let archived = ctx.archived.contains(&var);
+                    (var, val, archived)
+                })
+                .collect();
+            let saved = tivars::write_state(&vars)
+                .map_err(|err| format!("{:?}", err))
+                .and_then(|bytes| fs::write(arg, bytes).map_err(|err| err.to_string()));
+            match saved {
+                Ok(()) => writeln!(out, "saved {} variables", vars.len())?,
+                Err(err) => writeln!(out, "can't save {}: {}", arg, err)?,
+            }
+        }
+        "ast" => {
+            let mut scratch = Program::with_options(&Options::new());
+            let parsed = lexer::lex(&format!("{}\n", arg))
+                .map_err(|err| format!("{:?}", err))
+                .and_then(|tokens| {
+                    parser::parse(&tokens, &mut scratch).map_err(|err| format!("{:?}", err))
+                });
+            match parsed {
+                Ok(()) => {
+                    for statement in scratch.statements.iter() {
+                        writeln!(out, "{:?}", statement)?;
+                    }
+                }
+                Err(err) => writeln!(out, "can't parse {}: {}", arg, err)?,
+            }
+        }
+        "tokens" => match lexer::lex(&String::from(arg)) {
+            Ok(tokens) => writeln!(out, "{:?}", tokens)?,
+            Err(err) => writeln!(out, "can't lex {}: {:?}", arg, err)?,
+        },
+        "mode" => {
+            let mode = match arg {
+                "" => None,
+                "Real" => Some(NumberMode::Real),
+                "a+bi" => Some(NumberMode::Rect),
+                "re^θi" | "re^thetai" => Some(NumberMode::Polar),
+                _ => {
+                    writeln!(out, "no mode {}, try Real, a+bi or re^θi", arg)?;
+                    return Ok(());
+                }
+            };
+            if let Some(mode) = mode {
+                program.ctx.mode = mode;
+            }
+            writeln!(out, "{}", mode_name(&program.ctx.mode))?;
+        }
+        _ => writeln!(out, "{}", META_HELP)?,
+    }
+    Ok(())
+}
+
+struct TibHelper {
+    // what the program has set so far
+    variables: Vec<String>,
+}
+
+impl Completer for TibHelper {
+    type Candidate = String;
+
+    fn complete(
+        &self,
+        line: &str,
+        pos: usize,
+        _ctx: &rustyline::Context<'_>,
+    ) -> rustyline::Result<(usize, Vec<String>)> {
+        Ok(complete(line, pos, &self.variables))
+    }
+}
+
+impl Hinter for TibHelper {
+    type Hint = String;
+}
+
+impl Highlighter for TibHelper {}
+
+impl Validator for TibHelper {}
+
+impl Helper for TibHelper {}
+
+pub struct Repl {
+    editor: Editor<TibHelper, DefaultHistory>,
+    history: Option<PathBuf>,
+    // Ctrl+C was pressed at the prompt, which quits
+    pub interrupted: bool,
+}
+
+impl Repl {
+    pub fn new() -> Repl {
+        let mut editor = match Editor::new() {
+            Ok(editor) => editor,
+            Err(err) => {
+                eprintln!("Couldn't start the REPL: {}", err);
+                process::exit(1);
+            }
+        };
+        editor.set_helper(Some(TibHelper {
+            variables: Vec::new(),
+        }));
+        let history = env::var_os("HOME").map(|home| PathBuf::from(home).join(HISTORY_FILE));
+        if let Some(path) = &history {
+            // there isn't one the first time
+            let _ = editor.load_history(path);
+        }
+        Repl {
+            editor,
+            history,
+            interrupted: false,
+        }
+    }
+
+    pub fn set_variables(&mut self, ctx: &Context) {
+        if let Some(helper) = self.editor.helper_mut() {
+            helper.variables = ctx
+                .variables()
+                .iter()
+                .map(|(var, _)| var.to_string())
+                .collect();
+        }
+    }
+
+    pub fn line(&mut self, prompt: &str) -> Option<String> {
+        // None once there is nothing more to read. Ctrl+C at the prompt
+        // quits, the same as it did before there was an editor
+        if self.interrupted {
+            return None;
+        }
+        match self.editor.readline(prompt) {
+            Ok(line) => {
+                if !line.trim().is_empty() {
+                    let _ = self.editor.add_history_entry(line.as_str());
+                }
+                Some(line)
+            }
+            Err(ReadlineError::Interrupted) => {
+                self.interrupted = true;
+                None
+            }
+            Err(ReadlineError::Eof) => None,
+            Err(err) => {
+                eprintln!("Couldn't read a line: {}", err);
+                None
+            }
+        }
+    }
+
+    pub fn read(&mut self) -> Option<String> {
+        // Read lines until they make something that can run. An empty line
+        // runs an unfinished block anyway, the rest of it can come later
+        let mut input = String::new();
+        let mut prompt = String::from(":");
+        loop {
+            let line = match self.line(&prompt) {
+                Some(line) => line,
+                // whatever was typed before Ctrl+D still runs
+                None if !input.is_empty() && !self.interrupted => return Some(input),
+                None => return None,
+            };
+            if line.trim().is_empty() && !input.is_empty() {
+                return Some(input);
+            }
+            if input.is_empty() && is_meta(&line) {
+                return Some(line);
+            }
+            input.push_str(&line);
+            match pending(&input) {
+                Pending::Done => {
+                    input.push('\n');
+                    return Some(input);
+                }
+                Pending::Block(depth) => {
+                    input.push('\n');
+                    prompt = format!("{}:", "  ".repeat(depth));
+                }
+                // the next line carries on this one
+                Pending::Expression => prompt = String::from("..."),
+            }
+        }
+    }
+
+    pub fn save(&mut self) {
+        if let Some(path) = &self.history {
+            if let Err(err) = self.editor.save_history(path) {
+                eprintln!("warning: couldn't save history to {:?}: {}", path, err);
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_pending() {
+        assert_eq!(pending("2+2"), Pending::Done);
+        assert_eq!(pending("While A<5"), Pending::Block(1));
+        assert_eq!(pending("While A<5\nFor(I,1,3)"), Pending::Block(2));
+        assert_eq!(pending("While A<5\nA+1->A\nEnd"), Pending::Done);
+        assert_eq!(pending("If A"), Pending::Block(1));
+        assert_eq!(pending("If A\nDisp 1"), Pending::Done);
+        assert_eq!(pending("If A\nThen\nDisp 1"), Pending::Block(1));
+        assert_eq!(pending("If A\nThen\nElse\nEnd"), Pending::Done);
+        assert_eq!(pending("Repeat 1\nIS>(A,5)"), Pending::Block(2));
+        // parentheses close themselves at the end of a line
+        assert_eq!(pending("(1+2"), Pending::Done);
+        assert_eq!(pending("{1,2,"), Pending::Expression);
+        assert_eq!(pending("{1,2,3}"), Pending::Done);
+        assert_eq!(pending("For(I,1,"), Pending::Expression);
+        assert_eq!(pending("2+"), Pending::Expression);
+        // an error runs, so it can be shown
+        assert_eq!(pending("End"), Pending::Done);
+        assert_eq!(pending("Then"), Pending::Done);
+        assert_eq!(pending("Disp 1,"), Pending::Done);
+    }
+
+    #[test]
+    fn test_complete() {
+        let vars = vec![
+            String::from("A"),
+            String::from("Str1"),
+            String::from("ʟABC"),
+        ];
+        assert_eq!(complete("Dis", 3, &vars), (0, vec![String::from("Disp ")]));
+        assert_eq!(
+            complete("1->A:Wh", 7, &vars),
+            (5, vec![String::from("While ")])
+        );
+        assert_eq!(
+            complete("Disp St", 7, &vars),
+            (
+                5,
+                vec![
+                    String::from("Stop"),
+                    String::from("Str1"),
+                    String::from("String>Equ(")
+                ]
+            )
+        );
+        assert_eq!(
+            complete("A", 1, &vars),
+            (
+                0,
+                vec![
+                    String::from("A"),
+                    String::from("Ans"),
+                    String::from("Archive ")
+                ]
+            )
+        );
+        assert_eq!(complete("2+|LA", 5, &vars), (2, vec![String::from("ʟABC")]));
+        // only up to the cursor
+        assert_eq!(
+            complete("Disp 1", 2, &vars),
+            (0, vec![String::from("Disp ")])
+        );
+        assert_eq!(complete("2+", 2, &vars), (2, Vec::new()));
+        assert_eq!(complete(":va", 3, &vars), (1, vec![String::from("vars")]));
+        assert_eq!(complete("1:Di", 4, &vars), (2, vec![String::from("Disp ")]));
+    }
+
+    fn run(program: &mut Program, line: &str) -> String {
+        assert!(is_meta(line), "{}", line);
+        let mut out: Vec<u8> = Vec::new();
+        meta(line, program, &Options::new(), &mut out).unwrap();
+        String::from_utf8(out).unwrap()
+    }
+
+    #[test]
+    fn test_meta() {
+        let mut program = Program::with_options(&Options::new());
+        parser::parse(
+            &lexer::lex_str("5->A\n{1,2}->L1\n\"HI\"->Str1\n"),
+            &mut program,
+        )
+        .unwrap();
+        crate::executor::execute(&mut program).unwrap();
+
+        assert_eq!(
+            run(&mut program, ":vars"),
+            "Ans = HI\nL1 = {1 2}\nA = 5\nStr1 = HI\n"
+        );
+        assert_eq!(
+            run(&mut program, ":tokens 1->A"),
+            "[Number(1.0), Store, RealVar('A'), EndOfInput]\n"
+        );
+        assert_eq!(
+            run(&mut program, ":ast 1+2"),
+            "Expression(Plus(1.0, 2.0))\n"
+        );
+        assert_eq!(
+            run(&mut program, ":ast 1+"),
+            "can't parse 1+: UnexpectedToken(EndOfLine)\n"
+        );
+        assert_eq!(run(&mut program, ":mode"), "Real\n");
+        assert_eq!(run(&mut program, ":mode a+bi"), "a+bi\n");
+        assert_eq!(program.ctx.mode, NumberMode::Rect);
+        assert_eq!(
+            run(&mut program, ":mode Imaginary"),
+            "no mode Imaginary, try Real, a+bi or re^θi\n"
+        );
+
+        let file = env::temp_dir().join(format!("tib-meta-{}.8xg", process::id()));
+        let file = file.to_str().unwrap();
+        assert_eq!(
+            run(&mut program, &format!(":save {}", file)),
+            "saved 3 variables\n"
+        );
+        assert_eq!(run(&mut program, " :reset"), "Done\n");
+        assert_eq!(run(&mut program, ":vars"), "Ans = 0\n");
+        assert_eq!(
+            run(&mut program, &format!(":load {}", file)),
+            "loaded 3 variables\n"
+        );
+        fs::remove_file(file).unwrap();
+        assert_eq!(
+            run(&mut program, ":vars"),
+            "Ans = 0\nL1 = {1 2}\nA = 5\nStr1 = HI\n"
+        );
+        assert!(run(&mut program, &format!(":load {}", file)).starts_with("can't load "));
+        assert!(run(&mut program, ":help").starts_with(":vars "));
+
+        // these are TI-BASIC
+        assert!(!is_meta(":A+1"));
+        assert!(!is_meta(":a+bi"));
+        assert!(!is_meta("vars"));
+    }
+
+    #[test]
+    fn test_commands() {
+        // every command lexes to something that isn't a variable, Lbl and
+        // Goto need a name
+        for command in COMMANDS {
+            let tokens = lexer::lex(&format!("{}A", command)).unwrap();
+            assert!(
+                !matches!(tokens[0], Token::RealVar(_)),
+                "{} {:?}",
+                command,
+                tokens
+            );
+        }
+    }
+}
@@ -0,0 +1,141 @@
+use crate::executor::Options;
+use rand::{Rng, SeedableRng};
+use rand_chacha::ChaCha8Rng;
+use std::collections::VecDeque;
+use std::io;
+use std::time::Instant;
+
+// Every source of nondeterminism a TI-BASIC program can observe lives here, so
+// that a deterministic run only has to swap this struct out. On a calculator
+// these are uninitialized variables, rand, the clock and the keypad
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub enum Uninit {
+    // what an uninitialized real reads back as
+    Random, // garbage, to punish people using uninitialized variables
+    Zero,   // what a calculator does after a RAM clear
+}
+
+#[derive(Debug)]
+enum Clock {
+    System(Instant),
+    // a fake clock that ticks one second every time it is read, so that
+    // `While checkTmr(T)<5` still terminates
+    Fixed(f64),
+}
+
+#[derive(Debug)]
+pub struct Sources {
+    rng: ChaCha8Rng,
+    clock: Clock,
+    keys: VecDeque<f64>,
+    inputs: VecDeque<String>,
+    pub uninit: Uninit,
+}
+
+impl Sources {
+    pub fn new(options: &Options) -> Sources {
+        if options.deterministic {
+            Sources {
+                rng: ChaCha8Rng::seed_from_u64(options.seed),
+                clock: Clock::Fixed(0.0),
+                keys: options.keys.iter().cloned().collect(),
+                inputs: options.inputs.iter().cloned().collect(),
+                uninit: options.uninit,
+            }
+        } else {
+            Sources {
+                rng: ChaCha8Rng::from_entropy(),
+                clock: Clock::System(Instant::now()),
+                keys: options.keys.iter().cloned().collect(),
+                inputs: options.inputs.iter().cloned().collect(),
+                uninit: options.uninit,
+            }
+        }
+    }
+
+    pub fn uninit_real(&mut self) -> f64 {
+        match self.uninit {
+            Uninit::Random => self.rng.gen_range(-10e20..10e20),
+            Uninit::Zero => 0.0,
+        }
+    }
+
+    pub fn seed(&mut self, seed: f64) {
+        // `5->rand` reseeds the generator, like on the calculator
+        self.rng = ChaCha8Rng::seed_from_u64(seed.abs().trunc() as u64);
+    }
+
+    pub fn rand(&mut self) -> f64 {
+        self.rng.gen_range(0.0..1.0)
+    }
+
+    pub fn seconds(&mut self) -> f64 {
+        match &mut self.clock {
+            Clock::System(start) => start.elapsed().as_secs() as f64,
+            Clock::Fixed(now) => {
+                *now += 1.0;
+                *now
+            }
+        }
+    }
+
+    pub fn get_key(&mut self) -> f64 {
+        // tib has no keypad to poll, so without a script nothing is ever pressed
+        self.keys.pop_front().unwrap_or(0.0)
+    }
+
+    pub fn input(&mut self) -> Option<(String, bool)> {
+        // A line for Input or Prompt, and whether it was scripted rather than
+        // typed. None once stdin runs out too
+        if let Some(line) = self.inputs.pop_front() {
+            return Some((line, true));
+        }
+        let mut line = String::new();
+        match io::stdin().read_line(&mut line) {
+            Ok(0) | Err(_) => None,
+            Ok(_) => Some((line.trim_end_matches(&['\r', '\n'][..]).to_string(), false)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn deterministic() -> Options {
+        let mut options = Options::new();
+        options.deterministic = true;
+        options.seed = 42;
+        options
+    }
+
+    #[test]
+    fn test_seeded_sources_repeat() {
+        let mut a = Sources::new(&deterministic());
+        let mut b = Sources::new(&deterministic());
+        for _ in 0..10 {
+            assert_eq!(a.rand(), b.rand());
+            assert_eq!(a.uninit_real(), b.uninit_real());
+        }
+    }
+
+    #[test]
+    fn test_zero_uninit() {
+        let mut options = Options::new();
+        options.uninit = Uninit::Zero;
+        assert_eq!(Sources::new(&options).uninit_real(), 0.0);
+    }
+
+    #[test]
+    fn test_fixed_clock_and_keys() {
+        let mut options = deterministic();
+        options.keys = vec![105.0, 21.0];
+        let mut sources = Sources::new(&options);
+        assert_eq!(sources.seconds(), 1.0);
+        assert_eq!(sources.seconds(), 2.0);
+        assert_eq!(sources.get_key(), 105.0);
+        assert_eq!(sources.get_key(), 21.0);
+        assert_eq!(sources.get_key(), 0.0);
+    }
+}
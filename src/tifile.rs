@@ -0,0 +1,317 @@
+use crate::tokens::{self, TokenError};
+use std::convert::TryFrom;
+
+// The file format TI Connect uses to send variables to and from a calculator
+// (.8xp and friends). Every file is the same container:
+//
+//   11 bytes  signature, **TI83F*\x1A\x0A\x00
+//   42 bytes  comment, padded with zeros
+//    2 bytes  length of the data section
+//      data   one or more variable entries
+//    2 bytes  checksum, the low 16 bits of the sum of the data section
+//
+// and each variable entry is
+//
+//    2 bytes  0x0D, the length of the entry header
+//    2 bytes  length of the variable data
+//    1 byte   variable type
+//    8 bytes  name, padded with zeros
+//    1 byte   version
+//    1 byte   0x80 if the variable is archived
+//    2 bytes  length of the variable data again
+//      data   the variable itself
+//
+// All numbers are little endian.
+
+const SIGNATURE: &[u8] = b"**TI83F*\x1A\x0A\x00";
+const COMMENT_LENGTH: usize = 42;
+const HEADER_LENGTH: usize = SIGNATURE.len() + COMMENT_LENGTH + 2;
+const ENTRY_HEADER_LENGTH: u16 = 0x0D;
+// entries written by older software leave out the version and archived bytes
+const SHORT_ENTRY_HEADER_LENGTH: u16 = 0x0B;
+
+// variable types
+pub const REAL: u8 = 0x00;
+pub const LIST: u8 = 0x01;
+pub const MATRIX: u8 = 0x02;
+pub const EQUATION: u8 = 0x03;
+pub const STRING: u8 = 0x04;
+pub const PROGRAM: u8 = 0x05;
+pub const PROTECTED_PROGRAM: u8 = 0x06;
+pub const COMPLEX: u8 = 0x0C;
+pub const COMPLEX_LIST: u8 = 0x0D;
+
+#[derive(Debug, PartialEq)]
+pub enum FileError {
+    BadSignature,
+    Truncated,
+    BadChecksum,
+    BadEntry,
+    WrongType(u8),
+    BadName(String),
+    BadValue,
+    Token(TokenError),
+    // more than the 16 bit lengths in the file can hold
+    TooBig,
+    // a matrix with more rows or columns than a byte can count
+    InvalidDim,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+pub struct VarEntry {
+    pub var_type: u8,
+    pub name: [u8; 8],
+    pub version: u8,
+    pub archived: bool,
+    pub data: Vec<u8>,
+}
+
+impl VarEntry {
+    pub fn new(var_type: u8, name: &[u8], data: Vec<u8>) -> VarEntry {
+        let mut padded = [0u8; 8];
+        padded[..name.len()].copy_from_slice(name);
+        VarEntry {
+            var_type,
+            name: padded,
+            version: 0,
+            archived: false,
+            data,
+        }
+    }
+}
+
+pub struct Reader<'a> {
+    bytes: &'a [u8],
+    i: usize,
+}
+
+impl<'a> Reader<'a> {
+    pub fn new(bytes: &'a [u8]) -> Reader<'a> {
+        Reader { bytes, i: 0 }
+    }
+
+    pub fn take(&mut self, n: usize) -> Result<&'a [u8], FileError> {
+        if self.i + n > self.bytes.len() {
+            return Err(FileError::Truncated);
+        }
+        self.i += n;
+        Ok(&self.bytes[self.i - n..self.i])
+    }
+
+    pub fn byte(&mut self) -> Result<u8, FileError> {
+        Ok(self.take(1)?[0])
+    }
+
+    pub fn word(&mut self) -> Result<u16, FileError> {
+        let bytes = self.take(2)?;
+        Ok(u16::from_le_bytes([bytes[0], bytes[1]]))
+    }
+
+    fn done(&self) -> bool {
+        self.i >= self.bytes.len()
+    }
+}
+
+fn checksum(data: &[u8]) -> u16 {
+    data.iter().fold(0u16, |sum, b| sum.wrapping_add(*b as u16))
+}
+
+pub fn read(bytes: &[u8]) -> Result<Vec<VarEntry>, FileError> {
+    // Check the container and pull out every variable in it
+    if !bytes.starts_with(SIGNATURE) {
+        return Err(FileError::BadSignature);
+    }
+    let mut header = Reader::new(bytes);
+    header.take(SIGNATURE.len() + COMMENT_LENGTH)?;
+    let length = header.word()? as usize;
+    let data = header.take(length)?;
+    let expected = header.word()?;
+    if checksum(data) != expected {
+        return Err(FileError::BadChecksum);
+    }
+
+    let mut entries: Vec<VarEntry> = Vec::new();
+    let mut reader = Reader::new(data);
+    while !reader.done() {
+        let header_length = reader.word()?;
+        if header_length != ENTRY_HEADER_LENGTH && header_length != SHORT_ENTRY_HEADER_LENGTH {
+            return Err(FileError::BadEntry);
+        }
+        let length = reader.word()? as usize;
+        let var_type = reader.byte()?;
+        let mut name = [0u8; 8];
+        name.copy_from_slice(reader.take(8)?);
+        let (version, archived) = if header_length == ENTRY_HEADER_LENGTH {
+            (reader.byte()?, reader.byte()? & 0x80 != 0)
+        } else {
+            (0, false)
+        };
+        if reader.word()? as usize != length {
+            return Err(FileError::BadEntry);
+        }
+        entries.push(VarEntry {
+            var_type,
+            name,
+            version,
+            archived,
+            data: reader.take(length)?.to_vec(),
+        });
+    }
+    Ok(entries)
+}
+
+fn length(data: &[u8]) -> Result<[u8; 2], FileError> {
+    // lengths are 16 bits, so a calculator can't hold anything over 64K
+    let length = u16::try_from(data.len()).map_err(|_| FileError::TooBig)?;
+    Ok(length.to_le_bytes())
+}
+
+pub fn write(entries: &[VarEntry], comment: &str) -> Result<Vec<u8>, FileError> {
+    let mut data: Vec<u8> = Vec::new();
+    for entry in entries.iter() {
+        let length = length(&entry.data)?;
+        data.extend_from_slice(&ENTRY_HEADER_LENGTH.to_le_bytes());
+        data.extend_from_slice(&length);
+        data.push(entry.var_type);
+        data.extend_from_slice(&entry.name);
+        data.push(entry.version);
+        data.push(if entry.archived { 0x80 } else { 0x00 });
+        data.extend_from_slice(&length);
+        data.extend_from_slice(&entry.data);
+    }
+
+    let mut bytes: Vec<u8> = Vec::with_capacity(HEADER_LENGTH + data.len() + 2);
+    bytes.extend_from_slice(SIGNATURE);
+    let mut padded = [0u8; COMMENT_LENGTH];
+    let comment = comment.as_bytes();
+    let comment = &comment[..comment.len().min(COMMENT_LENGTH)];
+    padded[..comment.len()].copy_from_slice(comment);
+    bytes.extend_from_slice(&padded);
+    bytes.extend_from_slice(&length(&data)?);
+    bytes.extend_from_slice(&data);
+    bytes.extend_from_slice(&checksum(&data).to_le_bytes());
+    Ok(bytes)
+}
+
+pub fn read_program(bytes: &[u8]) -> Result<String, FileError> {
+    // The source of the first program in an .8xp. Program data is the length
+    // of the tokens followed by the tokens
+    let entry = read(bytes)?
+        .into_iter()
+        .next()
+        .ok_or(FileError::Truncated)?;
+    if entry.var_type != PROGRAM && entry.var_type != PROTECTED_PROGRAM {
+        return Err(FileError::WrongType(entry.var_type));
+    }
+    let mut reader = Reader::new(&entry.data);
+    let length = reader.word()? as usize;
+    let tokens = reader.take(length)?;
+    tokens::detokenize(tokens).map_err(FileError::Token)
+}
+
+pub fn write_program(name: &str, source: &str) -> Result<Vec<u8>, FileError> {
+    // Program names are 1 to 8 uppercase letters, digits or θ, starting with a
+    // letter. Tokenizing the name is what gets θ right
+    let name_bytes = tokens::tokenize(name).map_err(|_| FileError::BadName(String::from(name)))?;
+    let valid = |b: &u8| (0x41..=0x5B).contains(b) || b.is_ascii_digit();
+    if name_bytes.is_empty()
+        || name_bytes.len() > 8
+        || !name_bytes.iter().all(valid)
+        || name_bytes[0].is_ascii_digit()
+    {
+        return Err(FileError::BadName(String::from(name)));
+    }
+
+    let tokens = tokens::tokenize(source).map_err(FileError::Token)?;
+    let mut data = length(&tokens)?.to_vec();
+    data.extend_from_slice(&tokens);
+    let entry = VarEntry::new(PROGRAM, &name_bytes, data);
+    write(&[entry], "Exported by tib")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // PROGRAM:HI
+    // :Disp "HI"
+    const HI: &[u8] = &[
+        0x2A, 0x2A, 0x54, 0x49, 0x38, 0x33, 0x46, 0x2A, 0x1A, 0x0A, 0x00, // signature
+        0x45, 0x78, 0x70, 0x6F, 0x72, 0x74, 0x65, 0x64, 0x20, 0x62, 0x79, 0x20, 0x74, 0x69, 0x62,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, // comment
+        0x18, 0x00, // data length
+        0x0D, 0x00, 0x07, 0x00, 0x05, 0x48, 0x49, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00, 0x00,
+        0x07, 0x00, // entry header
+        0x05, 0x00, 0xDE, 0x2A, 0x48, 0x49, 0x2A, // program
+        0x79, 0x02, // checksum
+    ];
+
+    #[test]
+    fn test_read_program() {
+        assert_eq!(read_program(HI).unwrap(), "Disp \"HI\"");
+        let entries = read(HI).unwrap();
+        assert_eq!(entries.len(), 1);
+        assert_eq!(&entries[0].name, b"HI\0\0\0\0\0\0");
+        assert_eq!(entries[0].var_type, PROGRAM);
+    }
+
+    #[test]
+    fn test_write_program() {
+        assert_eq!(write_program("HI", "Disp \"HI\"").unwrap(), HI);
+        assert_eq!(
+            write_program("1ABC", "1"),
+            Err(FileError::BadName(String::from("1ABC")))
+        );
+        assert_eq!(
+            write_program("TOOLONGNAME", "1"),
+            Err(FileError::BadName(String::from("TOOLONGNAME")))
+        );
+    }
+
+    #[test]
+    fn test_too_big() {
+        // rand is one token, so this is the program's length in bytes
+        assert!(write_program("BIG", &"rand".repeat(60000)).is_ok());
+        assert_eq!(
+            write_program("BIG", &"rand".repeat(70000)),
+            Err(FileError::TooBig)
+        );
+        // the tokens fit in 16 bits but the file's data section around them
+        // doesn't
+        assert_eq!(
+            write_program("BIG", &"rand".repeat(65530)),
+            Err(FileError::TooBig)
+        );
+    }
+
+    #[test]
+    fn test_bad_files() {
+        assert_eq!(read(b"hello"), Err(FileError::BadSignature));
+        let mut corrupt = HI.to_vec();
+        corrupt[HEADER_LENGTH + 20] = 0xDF;
+        assert_eq!(read(&corrupt), Err(FileError::BadChecksum));
+        assert_eq!(read(&HI[..HI.len() - 5]), Err(FileError::Truncated));
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let source = "For(I,1,10)\nIf I>=5\nDisp \"BIG\",I\nEnd";
+        let file = write_program("LOOPθ", source).unwrap();
+        assert_eq!(read(&file).unwrap()[0].name, *b"LOOP\x5B\0\0\0");
+        assert_eq!(read_program(&file).unwrap(), source);
+    }
+
+    #[test]
+    fn test_custom_list() {
+        let file = write_program("LISTS", "{1,2}→ʟABC\nDisp ʟABC").unwrap();
+        assert_eq!(read_program(&file).unwrap(), "{1,2}->|LABC\nDisp |LABC");
+    }
+
+    #[test]
+    fn test_negation_round_trip() {
+        // tib's - for negation comes back as --, which means the same
+        let file = write_program("NEG", "-5->A\nA-1").unwrap();
+        assert_eq!(read_program(&file).unwrap(), "--5->A\nA-1");
+    }
+}
@@ -0,0 +1,282 @@
+use crate::executor::{EvalResult, ExecError};
+use crate::lexer::Token;
+use crate::parser::Value;
+use std::cmp::Ordering;
+
+// An emulation of the TI-84's floating point numbers: a sign, 14 decimal digits
+// of mantissa and an exponent from -99 to 99. Values still travel through tib
+// as f64, but every arithmetic result is computed in decimal and rounded to
+// what the calculator could hold, so 0.1+0.2=0.3 like it does on a TI.
+
+const DIGITS: u32 = 14;
+const MIN_MANTISSA: i128 = 10_000_000_000_000; // 10^13
+const MAX_EXPONENT: i32 = 99;
+// digits kept when comparing, the rest are assumed to be rounding noise
+const COMPARE_DIGITS: usize = 10;
+
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct TiFloat {
+    // value is mantissa * 10^(exponent - 13), with the mantissa either 0 or
+    // exactly 14 digits long
+    mantissa: i128,
+    exponent: i32,
+}
+
+impl TiFloat {
+    fn zero() -> TiFloat {
+        TiFloat {
+            mantissa: 0,
+            exponent: 0,
+        }
+    }
+
+    fn normalize(mantissa: i128, mut exponent: i32) -> Result<TiFloat, ExecError> {
+        // round an arbitrarily long mantissa to 14 digits
+        if mantissa == 0 {
+            return Ok(TiFloat::zero());
+        }
+        let mut magnitude = mantissa.abs();
+        let excess = digit_count(magnitude) as i32 - DIGITS as i32;
+        if excess > 0 {
+            magnitude = round_off(magnitude, excess as u32);
+            exponent += excess;
+            if magnitude >= MIN_MANTISSA * 10 {
+                // rounding carried into a new digit, eg 99999999999999.5
+                magnitude /= 10;
+                exponent += 1;
+            }
+        } else {
+            magnitude *= 10i128.pow((-excess) as u32);
+            exponent += excess;
+        }
+
+        if exponent > MAX_EXPONENT {
+            Err(ExecError::Overflow)
+        } else if exponent < -MAX_EXPONENT {
+            // too small to represent, the calculator just gives up and says 0
+            Ok(TiFloat::zero())
+        } else {
+            Ok(TiFloat {
+                mantissa: magnitude * mantissa.signum(),
+                exponent,
+            })
+        }
+    }
+
+    pub fn from_f64(n: f64) -> Result<TiFloat, ExecError> {
+        if n.is_nan() {
+            return Err(ExecError::NonRealAnswer);
+        }
+        if !n.is_finite() {
+            return Err(ExecError::Overflow);
+        }
+        // the shortest decimal that round trips is what the user typed
+        let text = format!("{:e}", n.abs());
+        let (digits, exponent) = text.split_once('e').unwrap();
+        let exponent: i32 = exponent.parse().unwrap();
+        let digits: String = digits.chars().filter(|c| c.is_ascii_digit()).collect();
+        let mantissa: i128 = digits.parse().unwrap();
+
+        TiFloat::normalize(
+            if n < 0.0 { -mantissa } else { mantissa },
+            exponent - digits.len() as i32 + DIGITS as i32,
+        )
+    }
+
+    pub fn to_f64(self) -> f64 {
+        format!("{}e{}", self.mantissa, self.exponent - (DIGITS as i32 - 1))
+            .parse()
+            .unwrap()
+    }
+
+    fn scaled(self, exponent: i32) -> i128 {
+        // the mantissa as if it had `exponent`, which must be <= our own
+        self.mantissa * 10i128.pow((self.exponent - exponent) as u32)
+    }
+
+    pub fn add(self, other: TiFloat) -> Result<TiFloat, ExecError> {
+        if self.mantissa == 0 {
+            return Ok(other);
+        }
+        if other.mantissa == 0 {
+            return Ok(self);
+        }
+        let (big, small) = if self.exponent >= other.exponent {
+            (self, other)
+        } else {
+            (other, self)
+        };
+        if big.exponent - small.exponent > DIGITS as i32 + 1 {
+            // small is entirely below big's last digit
+            return Ok(big);
+        }
+        TiFloat::normalize(big.scaled(small.exponent) + small.mantissa, small.exponent)
+    }
+
+    pub fn negate(self) -> TiFloat {
+        TiFloat {
+            mantissa: -self.mantissa,
+            exponent: self.exponent,
+        }
+    }
+
+    pub fn mul(self, other: TiFloat) -> Result<TiFloat, ExecError> {
+        // the product has 27 or 28 digits, and needs 13 of them shifted out
+        TiFloat::normalize(
+            self.mantissa * other.mantissa,
+            self.exponent + other.exponent - (DIGITS as i32 - 1),
+        )
+    }
+
+    pub fn div(self, other: TiFloat) -> Result<TiFloat, ExecError> {
+        if other.mantissa == 0 {
+            return Err(ExecError::DivideByZero);
+        }
+        // 10^20 of headroom leaves plenty of digits after the division to round
+        let numerator = self.mantissa * 10i128.pow(20);
+        TiFloat::normalize(
+            numerator / other.mantissa,
+            self.exponent - other.exponent + (DIGITS as i32 - 1) - 20,
+        )
+    }
+
+    fn compare_key(self) -> TiFloat {
+        // round to COMPARE_DIGITS before comparing
+        let dropped = (DIGITS as usize - COMPARE_DIGITS) as u32;
+        let magnitude = round_off(self.mantissa.abs(), dropped) * 10i128.pow(dropped);
+        TiFloat::normalize(magnitude * self.mantissa.signum(), self.exponent).unwrap_or(self)
+    }
+
+    pub fn compare(self, other: TiFloat) -> Ordering {
+        let lhs = self.compare_key();
+        let rhs = other.compare_key();
+        if lhs == rhs {
+            return Ordering::Equal;
+        }
+        lhs.to_f64().partial_cmp(&rhs.to_f64()).unwrap()
+    }
+}
+
+fn digit_count(n: i128) -> u32 {
+    let mut count = 1;
+    while n >= 10i128.pow(count) {
+        count += 1;
+    }
+    count
+}
+
+fn round_off(n: i128, digits: u32) -> i128 {
+    // drop the last `digits` digits of a non-negative number, rounding half up
+    let divisor = 10i128.pow(digits);
+    let rounded = n / divisor;
+    if (n % divisor) * 2 >= divisor {
+        rounded + 1
+    } else {
+        rounded
+    }
+}
+
+pub fn round(n: f64) -> Result<f64, ExecError> {
+    // round an f64 to the nearest thing the calculator could hold
+    Ok(TiFloat::from_f64(n)?.to_f64())
+}
+
+pub fn binary(token: &Token, lhs: f64, rhs: f64) -> Option<EvalResult> {
+    // Apply a binary operator in decimal, or None if there is no decimal version
+    // of it and the f64 implementation should be used instead
+    let l = match TiFloat::from_f64(lhs) {
+        Ok(l) => l,
+        Err(err) => return Some(Err(err)),
+    };
+    let r = match TiFloat::from_f64(rhs) {
+        Ok(r) => r,
+        Err(err) => return Some(Err(err)),
+    };
+
+    let number =
+        |result: Result<TiFloat, ExecError>| Some(result.map(|n| Value::NumValue(n.to_f64())));
+    let ordering = l.compare(r);
+
+    match token {
+        Token::Plus => number(l.add(r)),
+        Token::Minus => number(l.add(r.negate())),
+        Token::Mult => number(l.mul(r)),
+        Token::Divide => number(l.div(r)),
+        Token::Power => match lhs.powf(rhs) {
+            // a negative number to a fraction, hand the NaN back like the f64
+            // version does so it goes complex or becomes ERR:NONREAL ANS
+            n if n.is_nan() => Some(Ok(Value::NumValue(n))),
+            n => Some(round(n).map(Value::NumValue)),
+        },
+        Token::Equal => Some(Ok(Value::bool(ordering == Ordering::Equal))),
+        Token::NotEqual => Some(Ok(Value::bool(ordering != Ordering::Equal))),
+        Token::Greater => Some(Ok(Value::bool(ordering == Ordering::Greater))),
+        Token::GreaterEqual => Some(Ok(Value::bool(ordering != Ordering::Less))),
+        Token::Less => Some(Ok(Value::bool(ordering == Ordering::Less))),
+        Token::LessEqual => Some(Ok(Value::bool(ordering != Ordering::Greater))),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn ti(n: f64) -> TiFloat {
+        TiFloat::from_f64(n).unwrap()
+    }
+
+    #[test]
+    fn test_round_trip() {
+        for n in &[0.0, 1.0, -1.0, 0.1, 123.456, -9.87654321e-50, 1e99] {
+            assert_eq!(ti(*n).to_f64(), *n);
+        }
+        // only 14 digits survive
+        assert_eq!(ti(1.234567890123456).to_f64(), 1.2345678901235);
+        assert_eq!(ti(99999999999999.5).to_f64(), 1e14);
+    }
+
+    #[test]
+    fn test_arithmetic() {
+        assert_eq!(ti(0.1).add(ti(0.2)).unwrap().to_f64(), 0.3);
+        assert_eq!(ti(0.3).add(ti(0.1).negate()).unwrap().to_f64(), 0.2);
+        assert_eq!(ti(1.1).mul(ti(1.1)).unwrap().to_f64(), 1.21);
+        assert_eq!(ti(1.0).div(ti(3.0)).unwrap().to_f64(), 0.33333333333333);
+        assert_eq!(ti(1.0).div(ti(0.0)), Err(ExecError::DivideByZero));
+        assert_eq!(ti(1e20).add(ti(1.0)).unwrap().to_f64(), 1e20);
+    }
+
+    #[test]
+    fn test_exponent_range() {
+        assert_eq!(ti(1e99).mul(ti(10.0)), Err(ExecError::Overflow));
+        assert_eq!(ti(1e-99).div(ti(10.0)).unwrap().to_f64(), 0.0);
+        assert_eq!(TiFloat::from_f64(1e100), Err(ExecError::Overflow));
+    }
+
+    #[test]
+    fn test_compare() {
+        let third = ti(1.0).div(ti(3.0)).unwrap();
+        assert_eq!(
+            third.mul(ti(3.0)).unwrap().compare(ti(1.0)),
+            Ordering::Equal
+        );
+        assert_eq!(ti(1.0).compare(ti(2.0)), Ordering::Less);
+        assert_eq!(ti(-1.0).compare(ti(-2.0)), Ordering::Greater);
+    }
+
+    #[test]
+    fn test_binary() {
+        assert_eq!(binary(&Token::Plus, 0.1, 0.2).unwrap().unwrap(), 0.3);
+        assert_eq!(
+            binary(&Token::Equal, 0.1 + 0.2, 0.3).unwrap().unwrap(),
+            true
+        );
+        assert_eq!(binary(&Token::Power, 10.0, 99.0).unwrap().unwrap(), 1e99);
+        match binary(&Token::Power, -1.0, 0.5).unwrap().unwrap() {
+            Value::NumValue(n) => assert!(n.is_nan()),
+            val => panic!("{:?} isn't NaN", val),
+        }
+        assert_eq!(TiFloat::from_f64(f64::NAN), Err(ExecError::NonRealAnswer));
+        assert!(binary(&Token::And, 1.0, 1.0).is_none());
+    }
+}
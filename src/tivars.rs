@@ -0,0 +1,426 @@
+use crate::complex::Complex;
+use crate::lexer;
+use crate::parser::{Value, Variable};
+use crate::tifile::{self, FileError, Reader, VarEntry};
+use crate::tokens;
+use std::convert::TryFrom;
+
+// Converting between calculator variable files (.8xn, .8xl, .8xm, .8xs, and
+// .8xg groups of them) and tib's variables.
+//
+// Numbers are stored in 9 bytes:
+//
+//   1 byte   flags, 0x80 if negative, 0x0C if part of a complex number
+//   1 byte   exponent, plus 0x80
+//   7 bytes  14 digits of mantissa in BCD, with an implied point after the
+//            first digit
+//
+// Lists are a 2 byte length and their elements, matrices are a byte each for
+// the number of columns and rows and then their elements row by row, and
+// strings are a 2 byte length and then tokens.
+
+const NEGATIVE: u8 = 0x80;
+const COMPLEX_PART: u8 = 0x0C;
+const REAL_LENGTH: usize = 9;
+
+fn read_real(bytes: &[u8]) -> Result<f64, FileError> {
+    if bytes.len() < REAL_LENGTH {
+        return Err(FileError::Truncated);
+    }
+    let exponent = bytes[1] as i32 - 0x80;
+    let mut digits = String::new();
+    for byte in bytes[2..REAL_LENGTH].iter() {
+        for digit in &[byte >> 4, byte & 0x0F] {
+            if *digit > 9 {
+                return Err(FileError::BadValue);
+            }
+            digits.push((b'0' + digit) as char);
+        }
+    }
+    let n: f64 = format!("{}e{}", digits, exponent - 13).parse().unwrap();
+    Ok(if bytes[0] & NEGATIVE != 0 { -n } else { n })
+}
+
+fn write_real(n: f64, flags: u8) -> Result<[u8; REAL_LENGTH], FileError> {
+    let mut bytes = [0u8; REAL_LENGTH];
+    bytes[0] = flags;
+    bytes[1] = 0x80;
+    if n == 0.0 {
+        return Ok(bytes);
+    }
+    if !n.is_finite() {
+        return Err(FileError::BadValue);
+    }
+
+    // 1.2345678901234e5, rounded to the 14 digits the calculator keeps
+    let text = format!("{:.13e}", n.abs());
+    let (mantissa, exponent) = text.split_once('e').unwrap();
+    let exponent: i32 = exponent.parse().unwrap();
+    if exponent > 99 {
+        return Err(FileError::BadValue);
+    }
+    if exponent < -99 {
+        // too small for the calculator, which would call it 0 too
+        return Ok(bytes);
+    }
+    let digits: Vec<u8> = mantissa
+        .bytes()
+        .filter(|c| c.is_ascii_digit())
+        .map(|c| c - b'0')
+        .collect();
+
+    if n < 0.0 {
+        bytes[0] |= NEGATIVE;
+    }
+    bytes[1] = (0x80 + exponent) as u8;
+    for (i, pair) in digits.chunks(2).enumerate() {
+        bytes[2 + i] = pair[0] << 4 | pair[1];
+    }
+    Ok(bytes)
+}
+
+fn read_number(reader: &mut Reader, complex: bool) -> Result<Value, FileError> {
+    let re = read_real(reader.take(REAL_LENGTH)?)?;
+    if !complex {
+        return Ok(Value::NumValue(re));
+    }
+    let im = read_real(reader.take(REAL_LENGTH)?)?;
+    Ok(Value::complex(Complex::new(re, im)))
+}
+
+fn write_number(data: &mut Vec<u8>, val: &Value, complex: bool) -> Result<(), FileError> {
+    match (val, complex) {
+        (Value::NumValue(n), false) => data.extend_from_slice(&write_real(*n, 0)?),
+        (val, true) => {
+            let c = val.as_complex().ok_or(FileError::BadValue)?;
+            data.extend_from_slice(&write_real(c.re, COMPLEX_PART)?);
+            data.extend_from_slice(&write_real(c.im, COMPLEX_PART)?);
+        }
+        _ => return Err(FileError::BadValue),
+    }
+    Ok(())
+}
+
+fn is_complex(val: &Value) -> bool {
+    matches!(val, Value::ComplexValue(_))
+}
+
+pub fn parse_name(name: &str) -> Option<Variable> {
+    // A variable as it would be written in a program, like A, L1, [A] or Str1
+    let tokens = lexer::lex(&String::from(name)).ok()?;
+    match tokens.as_slice() {
+        [token, lexer::Token::EndOfInput] => match Variable::from_token(token)? {
+            Variable::Rand => None,
+            var => Some(var),
+        },
+        _ => None,
+    }
+}
+
+fn entry_name(var: &Variable) -> Result<Vec<u8>, FileError> {
+    let text = match var {
+        Variable::RealVar(name) => name.to_string(),
+        Variable::StrVar(n) => format!("Str{}", n),
+        Variable::EquVar(n) => format!("Y{}", n),
+        Variable::MatrixVar(name) => format!("[{}]", name),
+        Variable::ListVar(name) => {
+            if parse_name(name).is_some() {
+                name.clone()
+            } else {
+                // custom lists are the list prefix and then their name
+                let mut bytes = vec![0x5D];
+                bytes.extend(tokens::tokenize(name).map_err(FileError::Token)?);
+                return Ok(bytes);
+            }
+        }
+        _ => return Err(FileError::BadName(format!("{:?}", var))),
+    };
+    tokens::tokenize(&text).map_err(FileError::Token)
+}
+
+fn variable(entry: &VarEntry) -> Result<Variable, FileError> {
+    // Names are zero padded, but L1 and friends have a zero in their name.
+    // Those have a one byte prefix and a one byte index
+    let prefixed = [0x5C, 0x5D, 0x5E, 0xAA].contains(&entry.name[0]);
+    let start = if prefixed { 2 } else { 1 };
+    let end = entry.name[start..]
+        .iter()
+        .position(|b| *b == 0)
+        .map_or(8, |i| i + start);
+    let name = &entry.name[..end];
+
+    let bad_name = || FileError::BadName(format!("{:02X?}", name));
+    if name[0] == 0x5D && name[1] >= 0x41 {
+        let custom = tokens::detokenize(&name[1..]).map_err(|_| bad_name())?;
+        return Ok(Variable::ListVar(custom));
+    }
+    let text = tokens::detokenize(name).map_err(|_| bad_name())?;
+    parse_name(&text).ok_or_else(bad_name)
+}
+
+fn read_tokens(reader: &mut Reader) -> Result<String, FileError> {
+    let length = reader.word()? as usize;
+    tokens::detokenize(reader.take(length)?).map_err(FileError::Token)
+}
+
+fn read_value(entry: &VarEntry) -> Result<Value, FileError> {
+    let mut reader = Reader::new(&entry.data);
+    match entry.var_type {
+        tifile::REAL | tifile::COMPLEX => {
+            read_number(&mut reader, entry.var_type == tifile::COMPLEX)
+        }
+        tifile::LIST | tifile::COMPLEX_LIST => {
+            let length = reader.word()?;
+            let mut list: Vec<Value> = Vec::new();
+            for _ in 0..length {
+                list.push(read_number(
+                    &mut reader,
+                    entry.var_type == tifile::COMPLEX_LIST,
+                )?);
+            }
+            Ok(Value::ValueList(list))
+        }
+        tifile::MATRIX => {
+            let columns = reader.byte()?;
+            let rows = reader.byte()?;
+            let mut matrix: Vec<Vec<f64>> = Vec::new();
+            for _ in 0..rows {
+                let mut row: Vec<f64> = Vec::new();
+                for _ in 0..columns {
+                    row.push(read_real(reader.take(REAL_LENGTH)?)?);
+                }
+                matrix.push(row);
+            }
+            Ok(Value::MatrixValue(matrix))
+        }
+        tifile::STRING | tifile::EQUATION => Ok(Value::StringValue(read_tokens(&mut reader)?)),
+        var_type => Err(FileError::WrongType(var_type)),
+    }
+}
+
+pub fn read_vars(bytes: &[u8]) -> Result<Vec<(Variable, Value)>, FileError> {
+    // Every variable in a file. Programs are skipped since tib has nowhere to
+    // put them, which lets a whole group file be loaded at once
+    Ok(read_state(bytes)?
+        .into_iter()
+        .map(|(var, val, _)| (var, val))
+        .collect())
+}
+
+pub fn read_state(bytes: &[u8]) -> Result<Vec<(Variable, Value, bool)>, FileError> {
+    // Like read_vars, but also says whether each variable was archived
+    let mut vars: Vec<(Variable, Value, bool)> = Vec::new();
+    for entry in tifile::read(bytes)?.iter() {
+        if entry.var_type == tifile::PROGRAM || entry.var_type == tifile::PROTECTED_PROGRAM {
+            continue;
+        }
+        let var = variable(entry)?;
+        let val = read_value(entry)?;
+        // make sure the value can actually be stored in the variable
+        let fits = matches!(
+            (&var, &val),
+            (Variable::RealVar(_), Value::NumValue(_))
+                | (Variable::RealVar(_), Value::ComplexValue(_))
+                | (Variable::ListVar(_), Value::ValueList(_))
+                | (Variable::MatrixVar(_), Value::MatrixValue(_))
+                | (Variable::StrVar(_), Value::StringValue(_))
+                | (Variable::EquVar(_), Value::StringValue(_))
+        );
+        if !fits {
+            return Err(FileError::WrongType(entry.var_type));
+        }
+        vars.push((var, val, entry.archived));
+    }
+    Ok(vars)
+}
+
+fn write_entry(var: &Variable, val: &Value) -> Result<VarEntry, FileError> {
+    let mut data: Vec<u8> = Vec::new();
+    let var_type = match (var, val) {
+        (Variable::RealVar(_), val) => {
+            let complex = is_complex(val);
+            write_number(&mut data, val, complex)?;
+            if complex {
+                tifile::COMPLEX
+            } else {
+                tifile::REAL
+            }
+        }
+        (Variable::ListVar(_), Value::ValueList(list)) => {
+            // a list with any complex number in it is all complex
+            let complex = list.iter().any(is_complex);
+            let length = u16::try_from(list.len()).map_err(|_| FileError::TooBig)?;
+            data.extend_from_slice(&length.to_le_bytes());
+            for val in list.iter() {
+                write_number(&mut data, val, complex)?;
+            }
+            if complex {
+                tifile::COMPLEX_LIST
+            } else {
+                tifile::LIST
+            }
+        }
+        (Variable::MatrixVar(_), Value::MatrixValue(rows)) => {
+            let columns = rows.first().map_or(0, |row| row.len());
+            let dim = |n: usize| u8::try_from(n).map_err(|_| FileError::InvalidDim);
+            data.push(dim(columns)?);
+            data.push(dim(rows.len())?);
+            for n in rows.iter().flatten() {
+                data.extend_from_slice(&write_real(*n, 0)?);
+            }
+            tifile::MATRIX
+        }
+        (Variable::StrVar(_), Value::StringValue(text))
+        | (Variable::EquVar(_), Value::StringValue(text)) => {
+            let (tokens, var_type) = match var {
+                Variable::StrVar(_) => (tokens::tokenize_string(text), tifile::STRING),
+                _ => (tokens::tokenize(text), tifile::EQUATION),
+            };
+            let tokens = tokens.map_err(FileError::Token)?;
+            let length = u16::try_from(tokens.len()).map_err(|_| FileError::TooBig)?;
+            data.extend_from_slice(&length.to_le_bytes());
+            data.extend_from_slice(&tokens);
+            var_type
+        }
+        _ => return Err(FileError::BadValue),
+    };
+    Ok(VarEntry::new(var_type, &entry_name(var)?, data))
+}
+
+pub fn write_vars(vars: &[(Variable, Value)]) -> Result<Vec<u8>, FileError> {
+    // One variable makes an .8xn/.8xl/etc, more than one makes an .8xg
+    let mut entries: Vec<VarEntry> = Vec::new();
+    for (var, val) in vars.iter() {
+        entries.push(write_entry(var, val)?);
+    }
+    tifile::write(&entries, "Exported by tib")
+}
+
+pub fn write_state(vars: &[(Variable, Value, bool)]) -> Result<Vec<u8>, FileError> {
+    // A group of every variable, with archived ones flagged as archived
+    let mut entries: Vec<VarEntry> = Vec::new();
+    for (var, val, archived) in vars.iter() {
+        let mut entry = write_entry(var, val)?;
+        entry.archived = *archived;
+        entries.push(entry);
+    }
+    tifile::write(&entries, "tib state")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn round_trip(var: &str, val: Value) -> Value {
+        let var = parse_name(var).unwrap();
+        let file = write_vars(&[(var, val)]).unwrap();
+        read_vars(&file).unwrap().remove(0).1
+    }
+
+    #[test]
+    fn test_bcd() {
+        // -123.45
+        let bytes = [0x80, 0x82, 0x12, 0x34, 0x50, 0x00, 0x00, 0x00, 0x00];
+        assert_eq!(read_real(&bytes).unwrap(), -123.45);
+        assert_eq!(write_real(-123.45, 0).unwrap(), bytes);
+        assert_eq!(
+            write_real(0.0, 0).unwrap(),
+            [0x00, 0x80, 0, 0, 0, 0, 0, 0, 0]
+        );
+        assert_eq!(write_real(1e-5, 0).unwrap()[1], 0x7B);
+        assert_eq!(
+            read_real(&write_real(1.0 / 3.0, 0).unwrap()).unwrap(),
+            0.33333333333333
+        );
+        assert_eq!(write_real(1e100, 0), Err(FileError::BadValue));
+        assert_eq!(
+            read_real(&[0x00, 0x80, 0xA0, 0, 0, 0, 0, 0, 0]),
+            Err(FileError::BadValue)
+        );
+    }
+
+    #[test]
+    fn test_names() {
+        assert!(matches!(parse_name("A"), Some(Variable::RealVar('A'))));
+        assert!(matches!(parse_name("Str0"), Some(Variable::StrVar(0))));
+        assert!(matches!(parse_name("[B]"), Some(Variable::MatrixVar('B'))));
+        assert!(parse_name("rand").is_none());
+        assert!(parse_name("1+1").is_none());
+        assert_eq!(
+            entry_name(&parse_name("L1").unwrap()).unwrap(),
+            [0x5D, 0x00]
+        );
+        assert_eq!(
+            entry_name(&parse_name("Str1").unwrap()).unwrap(),
+            [0xAA, 0x00]
+        );
+        assert_eq!(
+            entry_name(&Variable::ListVar(String::from("AB"))).unwrap(),
+            [0x5D, 0x41, 0x42]
+        );
+    }
+
+    #[test]
+    fn test_round_trip() {
+        assert_eq!(round_trip("θ", Value::NumValue(2.5)), 2.5);
+        let c = Value::ComplexValue(Complex::new(1.0, -2.0));
+        assert_eq!(round_trip("Z", c.clone()), c);
+        let list = Value::ValueList(vec![Value::NumValue(1.0), Value::NumValue(-0.5)]);
+        assert_eq!(round_trip("L3", list.clone()), list);
+        let mixed = Value::ValueList(vec![Value::NumValue(1.0), c.clone()]);
+        assert_eq!(round_trip("L1", mixed.clone()), mixed);
+        let matrix = Value::MatrixValue(vec![vec![1.0, 2.0, 3.0], vec![4.0, 5.0, 6.0]]);
+        assert_eq!(round_trip("[J]", matrix.clone()), matrix);
+        let string = Value::StringValue(String::from("HELLO world"));
+        assert_eq!(round_trip("Str5", string.clone()), string);
+        let equation = Value::StringValue(String::from("X^2+1"));
+        assert_eq!(round_trip("Y1", equation.clone()), equation);
+    }
+
+    #[test]
+    fn test_too_big() {
+        let write = |name: &str, val: Value| write_vars(&[(parse_name(name).unwrap(), val)]);
+        let wide = Value::MatrixValue(vec![vec![0.0; 256]]);
+        assert_eq!(write("[A]", wide), Err(FileError::InvalidDim));
+        let tall = Value::MatrixValue(vec![vec![0.0]; 256]);
+        assert_eq!(write("[A]", tall), Err(FileError::InvalidDim));
+        let long = Value::ValueList(vec![Value::NumValue(0.0); 70000]);
+        assert_eq!(write("L1", long), Err(FileError::TooBig));
+    }
+
+    #[test]
+    fn test_group() {
+        let vars = vec![
+            (Variable::RealVar('A'), Value::NumValue(1.0)),
+            (
+                Variable::ListVar(String::from("AB")),
+                Value::ValueList(vec![]),
+            ),
+        ];
+        let group = read_vars(&write_vars(&vars).unwrap()).unwrap();
+        assert_eq!(group.len(), 2);
+        assert!(matches!(&group[1].0, Variable::ListVar(name) if name == "AB"));
+
+        // programs in a group are skipped
+        let program = tifile::write_program("HI", "Disp 1").unwrap();
+        assert!(read_vars(&program).unwrap().is_empty());
+    }
+
+    #[test]
+    fn test_state() {
+        let vars = vec![
+            (Variable::RealVar('A'), Value::NumValue(1.0), false),
+            (
+                Variable::StrVar(1),
+                Value::StringValue(String::from("HI")),
+                true,
+            ),
+        ];
+        let state = read_state(&write_state(&vars).unwrap()).unwrap();
+        assert_eq!(state.len(), 2);
+        assert_eq!(state[0].0, Variable::RealVar('A'));
+        assert!(!state[0].2);
+        assert_eq!(state[1].0, Variable::StrVar(1));
+        assert!(state[1].2);
+    }
+}
@@ -0,0 +1,483 @@
+// The calculator stores programs as one and two byte tokens rather than text.
+// This is the table between those bytes and how tib spells the same tokens, so
+// that a tokenized program can be turned into source tib can lex and back.
+
+use crate::lexer::{self, Token};
+
+// (bytes, tib spelling). Tokens tib doesn't implement yet are still here so
+// that calculator programs survive a round trip through tib
+const TOKENS: &[(&[u8], &str)] = &[
+    (&[0x04], "->"),
+    (&[0x06], "["),
+    (&[0x07], "]"),
+    (&[0x08], "{"),
+    (&[0x09], "}"),
+    (&[0x0C], "⁻¹"),
+    (&[0x0D], "²"),
+    (&[0x0E], "ᵀ"),
+    (&[0x0F], "³"),
+    (&[0x10], "("),
+    (&[0x11], ")"),
+    (&[0x22], "solve("),
+    (&[0x24], "fnInt("),
+    (&[0x25], "nDeriv("),
+    (&[0x27], "fMin("),
+    (&[0x28], "fMax("),
+    (&[0x29], " "),
+    (&[0x2A], "\""),
+    (&[0x2B], ","),
+    (&[0x2C], "i"),
+    (&[0x2D], "!"),
+    (&[0x30], "0"),
+    (&[0x31], "1"),
+    (&[0x32], "2"),
+    (&[0x33], "3"),
+    (&[0x34], "4"),
+    (&[0x35], "5"),
+    (&[0x36], "6"),
+    (&[0x37], "7"),
+    (&[0x38], "8"),
+    (&[0x39], "9"),
+    (&[0x3A], "."),
+    // ᴇ, tib spells it e. A negative exponent is ᴇ⁻ on the calculator but e-
+    // to tib, hence the two token entry
+    (&[0x3B], "e"),
+    (&[0x3B, 0xB0], "e-"),
+    (&[0x3C], " or "),
+    (&[0x3D], " xor "),
+    (&[0x3E], ":"),
+    (&[0x3F], "\n"),
+    (&[0x40], " and "),
+    (&[0x41], "A"),
+    (&[0x42], "B"),
+    (&[0x43], "C"),
+    (&[0x44], "D"),
+    (&[0x45], "E"),
+    (&[0x46], "F"),
+    (&[0x47], "G"),
+    (&[0x48], "H"),
+    (&[0x49], "I"),
+    (&[0x4A], "J"),
+    (&[0x4B], "K"),
+    (&[0x4C], "L"),
+    (&[0x4D], "M"),
+    (&[0x4E], "N"),
+    (&[0x4F], "O"),
+    (&[0x50], "P"),
+    (&[0x51], "Q"),
+    (&[0x52], "R"),
+    (&[0x53], "S"),
+    (&[0x54], "T"),
+    (&[0x55], "U"),
+    (&[0x56], "V"),
+    (&[0x57], "W"),
+    (&[0x58], "X"),
+    (&[0x59], "Y"),
+    (&[0x5A], "Z"),
+    (&[0x5B], "θ"),
+    (&[0x5C, 0x00], "[A]"),
+    (&[0x5C, 0x01], "[B]"),
+    (&[0x5C, 0x02], "[C]"),
+    (&[0x5C, 0x03], "[D]"),
+    (&[0x5C, 0x04], "[E]"),
+    (&[0x5C, 0x05], "[F]"),
+    (&[0x5C, 0x06], "[G]"),
+    (&[0x5C, 0x07], "[H]"),
+    (&[0x5C, 0x08], "[I]"),
+    (&[0x5C, 0x09], "[J]"),
+    (&[0x5D, 0x00], "L1"),
+    (&[0x5D, 0x01], "L2"),
+    (&[0x5D, 0x02], "L3"),
+    (&[0x5D, 0x03], "L4"),
+    (&[0x5D, 0x04], "L5"),
+    (&[0x5D, 0x05], "L6"),
+    (&[0x5E, 0x10], "Y1"),
+    (&[0x5E, 0x11], "Y2"),
+    (&[0x5E, 0x12], "Y3"),
+    (&[0x5E, 0x13], "Y4"),
+    (&[0x5E, 0x14], "Y5"),
+    (&[0x5E, 0x15], "Y6"),
+    (&[0x5E, 0x16], "Y7"),
+    (&[0x5E, 0x17], "Y8"),
+    (&[0x5E, 0x18], "Y9"),
+    (&[0x5E, 0x19], "Y0"),
+    (&[0x6A], "="),
+    (&[0x6B], "<"),
+    (&[0x6C], ">"),
+    (&[0x6D], "<="),
+    (&[0x6E], ">="),
+    (&[0x6F], "!="),
+    (&[0x70], "+"),
+    (&[0x71], "-"),
+    (&[0x72], "Ans"),
+    (&[0x82], "*"),
+    (&[0x83], "/"),
+    (&[0xAA, 0x00], "Str1"),
+    (&[0xAA, 0x01], "Str2"),
+    (&[0xAA, 0x02], "Str3"),
+    (&[0xAA, 0x03], "Str4"),
+    (&[0xAA, 0x04], "Str5"),
+    (&[0xAA, 0x05], "Str6"),
+    (&[0xAA, 0x06], "Str7"),
+    (&[0xAA, 0x07], "Str8"),
+    (&[0xAA, 0x08], "Str9"),
+    (&[0xAA, 0x09], "Str0"),
+    (&[0xAB], "rand"),
+    (&[0xAC], "π"),
+    (&[0xAD], "getKey"),
+    (&[0xAE], "'"),
+    (&[0xAF], "?"),
+    (&[0xB0], "--"),
+    (&[0xB1], "int("),
+    (&[0xB2], "abs("),
+    (&[0xB5], "dim("),
+    (&[0xB6], "sum("),
+    (&[0xB8], "not("),
+    (&[0xB9], "iPart("),
+    (&[0xBA], "fPart("),
+    (&[0xBC], "√("),
+    (&[0xBE], "ln("),
+    (&[0xBF], "e^("),
+    (&[0xC0], "log("),
+    (&[0xC2], "sin("),
+    (&[0xC3], "sin⁻¹("),
+    (&[0xC4], "cos("),
+    (&[0xC5], "cos⁻¹("),
+    (&[0xC6], "tan("),
+    (&[0xC7], "tan⁻¹("),
+    (&[0xCE], "If "),
+    (&[0xCF], "Then"),
+    (&[0xD0], "Else"),
+    (&[0xD1], "While "),
+    (&[0xD2], "Repeat "),
+    (&[0xD3], "For("),
+    (&[0xD4], "End"),
+    (&[0xD5], "Return"),
+    (&[0xD6], "Lbl "),
+    (&[0xD7], "Goto "),
+    (&[0xD8], "Pause "),
+    (&[0xD9], "Stop"),
+    (&[0xDA], "IS>("),
+    (&[0xDB], "DS<("),
+    (&[0xDC], "Input "),
+    (&[0xDD], "Prompt "),
+    (&[0xDE], "Disp "),
+    (&[0xE0], "Output("),
+    (&[0xE1], "ClrHome"),
+    // ʟ, the start of a custom list name like ʟABC
+    (&[0xEB], "|L"),
+    (&[0xF0], "^"),
+    (&[0xFA], "ClrList "),
+    (&[0xBB, 0x0A], "randInt("),
+    (&[0xBB, 0x0C], "sub("),
+    (&[0xBB, 0x0F], "inStr("),
+    (&[0xBB, 0x25], "conj("),
+    (&[0xBB, 0x26], "real("),
+    (&[0xBB, 0x27], "imag("),
+    (&[0xBB, 0x28], "angle("),
+    (&[0xBB, 0x2A], "expr("),
+    (&[0xBB, 0x2B], "length("),
+    (&[0xBB, 0x4D], "Real"),
+    (&[0xBB, 0x4E], "re^θi"),
+    (&[0xBB, 0x4F], "a+bi"),
+    (&[0xBB, 0x52], "ClrAllLists"),
+    (&[0xBB, 0x54], "DelVar "),
+    (&[0xBB, 0x55], "Equ>String("),
+    (&[0xBB, 0x56], "String>Equ("),
+    (&[0xBB, 0x68], "Archive "),
+    (&[0xBB, 0x69], "UnArchive "),
+    (&[0xEF, 0x02], "checkTmr("),
+    (&[0xEF, 0x0B], "startTmr"),
+    (&[0xEF, 0x33], "Σ("),
+    (&[0xEF, 0x97], "toString("),
+    (&[0xEF, 0x98], "eval("),
+];
+
+// Lowercase letters, which take two bytes each. Outside of strings tib reads
+// e and i as ᴇ and the imaginary unit, so these are only used inside strings
+const LOWERCASE: &[(&[u8], &str)] = &[
+    (&[0xBB, 0xB0], "a"),
+    (&[0xBB, 0xB1], "b"),
+    (&[0xBB, 0xB2], "c"),
+    (&[0xBB, 0xB3], "d"),
+    (&[0xBB, 0xB4], "e"),
+    (&[0xBB, 0xB5], "f"),
+    (&[0xBB, 0xB6], "g"),
+    (&[0xBB, 0xB7], "h"),
+    (&[0xBB, 0xB8], "i"),
+    (&[0xBB, 0xB9], "j"),
+    (&[0xBB, 0xBA], "k"),
+    (&[0xBB, 0xBC], "l"),
+    (&[0xBB, 0xBD], "m"),
+    (&[0xBB, 0xBE], "n"),
+    (&[0xBB, 0xBF], "o"),
+    (&[0xBB, 0xC0], "p"),
+    (&[0xBB, 0xC1], "q"),
+    (&[0xBB, 0xC2], "r"),
+    (&[0xBB, 0xC3], "s"),
+    (&[0xBB, 0xC4], "t"),
+    (&[0xBB, 0xC5], "u"),
+    (&[0xBB, 0xC6], "v"),
+    (&[0xBB, 0xC7], "w"),
+    (&[0xBB, 0xC8], "x"),
+    (&[0xBB, 0xC9], "y"),
+    (&[0xBB, 0xCA], "z"),
+];
+
+// The unicode and TokenIDE spellings the lexer also accepts, only used when
+// tokenizing
+const SYMBOLS: &[(&[u8], &str)] = &[
+    (&[0x04], "→"),
+    (&[0x6F], "≠"),
+    (&[0x6D], "≤"),
+    (&[0x6E], "≥"),
+    (&[0xB0], "⁻"),
+    (&[0xB0], "~"),
+    (&[0x3B], "ᴇ"),
+    (&[0x3B], "|E"),
+    (&[0x2C], "𝑖"),
+    (&[0x2C], "[i]"),
+    (&[0xBB, 0x0F], "inString("),
+    (&[0xBB, 0x55], "Equ►String("),
+    (&[0xBB, 0x56], "String►Equ("),
+    (&[0xEF, 0x33], "Sigma("),
+    (&[0xEB], "ʟ"),
+    (&[0x5D, 0x00], "L₁"),
+    (&[0x5D, 0x01], "L₂"),
+    (&[0x5D, 0x02], "L₃"),
+    (&[0x5D, 0x03], "L₄"),
+    (&[0x5D, 0x04], "L₅"),
+    (&[0x5D, 0x05], "L₆"),
+];
+
+// Other words for a token that tib accepts, only used when tokenizing outside
+// of strings
+const ALIASES: &[(&[u8], &str)] = &[
+    (&[0x3F], "\r\n"),
+    (&[0x5B], "Theta"),
+    (&[0x5B], "theta"),
+    (&[0xBC], "sqrt("),
+    (&[0x3C], "or"),
+    (&[0x3D], "xor"),
+    (&[0x40], "and"),
+    (&[0xCE], "If"),
+    (&[0xD1], "While"),
+    (&[0xD2], "Repeat"),
+    (&[0xD6], "Lbl"),
+    (&[0xD7], "Goto"),
+    (&[0xD8], "Pause"),
+    (&[0xDC], "Input"),
+    (&[0xDD], "Prompt"),
+    (&[0xDE], "Disp"),
+    (&[0xBB, 0x54], "DelVar"),
+    (&[0xFA], "ClrList"),
+    (&[0xBB, 0x68], "Archive"),
+    (&[0xBB, 0x69], "UnArchive"),
+];
+
+#[derive(Debug, PartialEq)]
+pub enum TokenError {
+    UnknownBytes(Vec<u8>),
+    UnknownText(String),
+}
+
+fn longest<'a, I>(
+    candidates: I,
+    len: fn(&(&[u8], &str)) -> usize,
+) -> Option<&'a (&'static [u8], &'static str)>
+where
+    I: Iterator<Item = &'a (&'static [u8], &'static str)>,
+{
+    // the longest candidate, the earliest one in the table on a tie
+    let mut best: Option<&(&[u8], &str)> = None;
+    for candidate in candidates {
+        if best.is_none_or(|best| len(candidate) > len(best)) {
+            best = Some(candidate);
+        }
+    }
+    best
+}
+
+pub fn detokenize(bytes: &[u8]) -> Result<String, TokenError> {
+    // Turn tokenized program data into tib source
+    let mut source = String::new();
+    let mut i = 0;
+    while i < bytes.len() {
+        let rest = &bytes[i..];
+        // longest match, so ᴇ⁻ wins over ᴇ
+        let found = longest(
+            TOKENS
+                .iter()
+                .chain(LOWERCASE.iter())
+                .filter(|(token, _)| rest.starts_with(token)),
+            |(token, _)| token.len(),
+        );
+        match found {
+            Some((token, text)) => {
+                source.push_str(text);
+                i += token.len();
+            }
+            None => {
+                let end = (i + 2).min(bytes.len());
+                return Err(TokenError::UnknownBytes(bytes[i..end].to_vec()));
+            }
+        }
+    }
+    Ok(source)
+}
+
+pub fn tokenize(source: &str) -> Result<Vec<u8>, TokenError> {
+    // Turn tib source into tokenized program data. Whitespace outside of
+    // strings is dropped since the calculator has no use for it
+    tokenize_from(source, false)
+}
+
+pub fn tokenize_string(text: &str) -> Result<Vec<u8>, TokenError> {
+    // The contents of a string variable, which are tokenized like the inside
+    // of a string literal
+    tokenize_from(text, true)
+}
+
+fn follows_value(text: &str) -> bool {
+    // Whether a - after the token spelled `text` is subtraction, by the same
+    // rule the parser uses. Tokens tib can't lex are guessed at from how they
+    // end, like randInt( which starts a value rather than ending one
+    if text.is_empty() {
+        return false;
+    }
+    match lexer::lex(&String::from(text)) {
+        Ok(tokens) => tokens
+            .iter()
+            .rev()
+            .find(|token| **token != Token::EndOfInput)
+            .is_some_and(lexer::ends_value),
+        Err(_) => !text.ends_with(['(', ',', ' ']),
+    }
+}
+
+fn tokenize_from(source: &str, mut in_string: bool) -> Result<Vec<u8>, TokenError> {
+    let mut bytes: Vec<u8> = Vec::new();
+    // the text of the last token, to tell a - that subtracts from one that
+    // negates. The calculator has different tokens for them
+    let mut last = "";
+    let mut rest = source;
+    while let Some(c) = rest.chars().next() {
+        if !in_string && (c == ' ' || c == '\t') {
+            rest = &rest[c.len_utf8()..];
+            continue;
+        }
+
+        // Aliases are words like `or`, which inside a string are just letters
+        let tables: &[&[_]] = if in_string && c.is_ascii_lowercase() {
+            &[LOWERCASE]
+        } else if in_string {
+            &[TOKENS, SYMBOLS]
+        } else {
+            &[TOKENS, SYMBOLS, ALIASES]
+        };
+        let found = longest(
+            tables
+                .iter()
+                .flat_map(|table| table.iter())
+                .filter(|(_, text)| rest.starts_with(text)),
+            |(_, text)| text.len(),
+        );
+        let (token, text) = match found {
+            Some(found) => found,
+            None => return Err(TokenError::UnknownText(c.to_string())),
+        };
+
+        // strings end at a closing quote, a newline or a store arrow
+        match *token {
+            [0x2A] => in_string = !in_string,
+            [0x3F] | [0x04] => in_string = false,
+            _ => (),
+        }
+        if *token == [0x71] && !in_string && !follows_value(last) {
+            bytes.push(0xB0);
+        } else {
+            bytes.extend_from_slice(token);
+        }
+        last = text;
+        rest = &rest[text.len()..];
+    }
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn test_detokenize() {
+        // :If A>1:Disp "HI"
+        let bytes = [
+            0x3E, 0xCE, 0x41, 0x6C, 0x31, 0x3E, 0xDE, 0x2A, 0x48, 0x49, 0x2A,
+        ];
+        assert_eq!(detokenize(&bytes).unwrap(), ":If A>1:Disp \"HI\"");
+        assert_eq!(detokenize(&[0x31, 0x3B, 0xB0, 0x35]).unwrap(), "1e-5");
+        assert_eq!(detokenize(&[0xBB, 0x0C]).unwrap(), "sub(");
+        assert_eq!(
+            detokenize(&[0xBB, 0xFF]),
+            Err(TokenError::UnknownBytes(vec![0xBB, 0xFF]))
+        );
+    }
+
+    #[test]
+    fn test_tokenize() {
+        assert_eq!(tokenize("3->X").unwrap(), [0x33, 0x04, 0x58]);
+        assert_eq!(tokenize("Disp X").unwrap(), [0xDE, 0x58]);
+        assert_eq!(tokenize("Disp   X").unwrap(), [0xDE, 0x58]);
+        assert_eq!(tokenize("sqrt(4)").unwrap(), [0xBC, 0x34, 0x11]);
+        // inside a string, spaces are kept and `or` is two letters
+        assert_eq!(
+            tokenize("\"A or\"").unwrap(),
+            [0x2A, 0x41, 0x29, 0xBB, 0xBF, 0xBB, 0xC2, 0x2A]
+        );
+        assert_eq!(tokenize("A or B").unwrap(), [0x41, 0x3C, 0x42]);
+        assert_eq!(
+            tokenize("@"),
+            Err(TokenError::UnknownText(String::from("@")))
+        );
+    }
+
+    #[test]
+    fn test_unicode() {
+        assert_eq!(tokenize("⁻1→θ").unwrap(), tokenize("--1->Theta").unwrap());
+        assert_eq!(
+            tokenize("\"A→Str1").unwrap(),
+            [0x2A, 0x41, 0x04, 0xAA, 0x00]
+        );
+        assert_eq!(tokenize("L₁≠L2").unwrap(), [0x5D, 0x00, 0x6F, 0x5D, 0x01]);
+        // custom lists, either spelling
+        assert_eq!(tokenize("ʟABC").unwrap(), [0xEB, 0x41, 0x42, 0x43]);
+        assert_eq!(tokenize("1->|LAB").unwrap(), [0x31, 0x04, 0xEB, 0x41, 0x42]);
+        assert_eq!(detokenize(&[0xEB, 0x41, 0x42]).unwrap(), "|LAB");
+    }
+
+    #[test]
+    fn test_negation() {
+        // a - where a value starts is ⁻, anywhere else it's subtraction
+        assert_eq!(tokenize("-5->A").unwrap(), [0xB0, 0x35, 0x04, 0x41]);
+        assert_eq!(tokenize("A-5").unwrap(), [0x41, 0x71, 0x35]);
+        assert_eq!(
+            tokenize("{1,-2}-(-A)").unwrap(),
+            [0x08, 0x31, 0x2B, 0xB0, 0x32, 0x09, 0x71, 0x10, 0xB0, 0x41, 0x11]
+        );
+        assert_eq!(
+            tokenize("Disp -A²-1").unwrap(),
+            tokenize("Disp ⁻A²-1").unwrap()
+        );
+        // even before tokens tib doesn't know
+        assert_eq!(tokenize("randInt(-1,1)").unwrap()[2], 0xB0);
+        // inside a string it is just a character
+        assert_eq!(tokenize("\"-\"").unwrap(), [0x2A, 0x71, 0x2A]);
+    }
+
+    #[test]
+    fn test_round_trip() {
+        let source = "For(I,1,10)\nIf I>=5\nDisp \"BIG\",I\nEnd\n1e-3->Str1";
+        let bytes = tokenize(source).unwrap();
+        assert_eq!(tokenize(&detokenize(&bytes).unwrap()).unwrap(), bytes);
+    }
+}
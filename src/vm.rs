@@ -0,0 +1,471 @@
+use crate::executor::*;
+use crate::functions::FuncImpl;
+use crate::parser::*;
+
+// tib's other way of running a program. The tree-walker in executor.rs is the
+// reference, this compiles every statement into one flat list of instructions
+// (with Goto already turned into the index it jumps to) and every expression
+// into stack code, so nothing gets cloned while the program runs.
+//
+// Control flow has to stay exactly as loose as on the calculator: Goto can land
+// in the middle of a loop and End closes whatever block is on top of the
+// blockstack, not the one it looks like it belongs to. So instructions are
+// numbered the same as statements and the VM shares the blockstack, the block
+// scanning and ERR:MEMORY with the tree-walker.
+
+#[derive(Debug, Clone)]
+pub enum Op {
+    Push(Value),
+    Load(Variable),
+    // pops a value, stores it and pushes it back since storing is also an
+    // expression. Element indices are evaluated by the tree-walker
+    Store(Target),
+    // pops the right hand side, then the left
+    Binary(Operator),
+    Unary(fn(Value) -> EvalResult),
+    // pops this many arguments, the first one was pushed first
+    Call(FuncImpl, usize),
+    // anything without its own op
+    Tree(ValRef),
+}
+
+pub type Code = Vec<Op>;
+
+#[derive(Debug, Clone)]
+pub enum Instr {
+    Expr(Code),
+    If(Code),
+    Then,
+    Else,
+    End,
+    Stop,
+    For {
+        var: Variable,
+        start: Code,
+        stop: Code,
+        inc: Code,
+    },
+    While(Code),
+    Repeat(Code),
+    Disp(Code),
+    // the label is kept for --warn-leaks, None if it doesn't exist
+    Goto(String, Option<usize>),
+    Skip {
+        target: Target,
+        value: Code,
+        decrement: bool,
+    },
+    // commands with no expressions and no control flow
+    Simple(Command),
+}
+
+fn code(expr: &ValRef) -> Code {
+    let mut code: Code = Vec::new();
+    expr.compile(&mut code);
+    code
+}
+
+pub fn compile(program: &Program) -> Vec<Instr> {
+    program
+        .statements
+        .iter()
+        .map(|statement| match statement {
+            Statement::Expression(expr) => Instr::Expr(code(expr)),
+            Statement::Command(cmd) => match cmd {
+                Command::If(cond) => Instr::If(code(cond)),
+                Command::Then => Instr::Then,
+                Command::Else => Instr::Else,
+                Command::End => Instr::End,
+                Command::For(cmd) => Instr::For {
+                    var: cmd.var.clone(),
+                    start: code(&cmd.start),
+                    stop: code(&cmd.stop),
+                    inc: code(&cmd.inc),
+                },
+                Command::While(cond) => Instr::While(code(cond)),
+                Command::Repeat(cond) => Instr::Repeat(code(cond)),
+                Command::Disp(val) => Instr::Disp(code(val)),
+                Command::Goto(label) => {
+                    Instr::Goto(label.clone(), program.label_cache.get(label).copied())
+                }
+                Command::Jump(label, loc) => Instr::Goto(label.clone(), Some(*loc)),
+                Command::Stop => Instr::Stop,
+                Command::IncrementSkip(target, value) => Instr::Skip {
+                    target: target.clone(),
+                    value: code(value),
+                    decrement: false,
+                },
+                Command::DecrementSkip(target, value) => Instr::Skip {
+                    target: target.clone(),
+                    value: code(value),
+                    decrement: true,
+                },
+                cmd => Instr::Simple(cmd.clone()),
+            },
+        })
+        .collect()
+}
+
+fn eval(code: &[Op], ctx: &mut Context, stack: &mut Vec<Value>) -> EvalResult {
+    // an error can leave things behind from the last expression
+    stack.clear();
+    for op in code.iter() {
+        let val = match op {
+            Op::Push(val) => val.clone(),
+            Op::Load(var) => ctx.get(var)?,
+            Op::Store(target) => {
+                let val = stack.pop().unwrap();
+                target.set(ctx, val)?
+            }
+            Op::Binary(operator) => {
+                let rhs = stack.pop().unwrap();
+                let lhs = stack.pop().unwrap();
+                operator.apply(lhs, rhs, ctx)?
+            }
+            Op::Unary(func) => func(stack.pop().unwrap())?,
+            Op::Call(func, argc) => {
+                let args = stack.split_off(stack.len() - argc);
+                func(ctx, args)?
+            }
+            Op::Tree(expr) => expr.eval(ctx)?,
+        };
+        stack.push(val);
+    }
+    Ok(stack.pop().unwrap())
+}
+
+struct Vm<'a> {
+    program: &'a mut Program,
+    code: &'a [Instr],
+    stack: Vec<Value>,
+}
+
+impl<'a> Vm<'a> {
+    fn eval(&mut self, code: &[Op]) -> EvalResult {
+        eval(code, &mut self.program.ctx, &mut self.stack)
+    }
+
+    fn run(&mut self) -> Result<(), ExecError> {
+        let code = self.code;
+        self.program.start_limits();
+        while self.program.pc < code.len() {
+            self.program.checkpoint()?;
+            self.program.done = !matches!(code[self.program.pc], Instr::Expr(_));
+            match &code[self.program.pc] {
+                Instr::Expr(expr) => {
+                    let ans = self.eval(expr)?;
+                    self.program.ctx.set_ans(ans)?;
+                }
+                Instr::If(cond) => self.exec_if(cond)?,
+                Instr::Then => return Err(ExecError::UnexpectedThen),
+                Instr::Else => self.program.exec_else()?,
+                Instr::End => self.exec_end()?,
+                Instr::For {
+                    var, start, stop, ..
+                } => self.exec_for(var, start, stop)?,
+                Instr::While(cond) => self.exec_while(cond)?,
+                Instr::Repeat(_) => {
+                    let pc = self.program.pc;
+                    self.program.push_block(Block::RepeatBlock(pc))?;
+                }
+                Instr::Disp(val) => {
+                    let result = self.eval(val)?;
+                    let text = self.program.ctx.format(&result);
+                    self.program.ctx.disp(&text)?;
+                }
+                Instr::Stop => self.program.stop(),
+                Instr::Goto(label, target) => match target {
+                    Some(loc) => self.program.jump(label, *loc),
+                    None => return Err(ExecError::UnknownLabel),
+                },
+                Instr::Skip {
+                    target,
+                    value,
+                    decrement,
+                } => self.exec_skip(target, value, *decrement)?,
+                Instr::Simple(cmd) => self.program.ctx.exec_command(cmd)?,
+            }
+            self.program.pc += 1;
+        }
+        Ok(())
+    }
+
+    fn exec_if(&mut self, cond: &[Op]) -> Result<(), ExecError> {
+        let result = self.eval(cond)?;
+        let pc = self.program.pc;
+        let then = self.program.next_then()?;
+        match (result.to_bool()?, then) {
+            (true, true) => {
+                self.program.push_block(Block::IfBlock(pc, true))?;
+                // don't run the Then
+                self.program.pc += 1;
+            }
+            (true, false) => (),
+            (false, true) => {
+                self.program.push_block(Block::IfBlock(pc, false))?;
+                self.program.scan_and_advance(true, false)?;
+                // an Else we land on isn't run either, it would skip to the End
+                if let Statement::Command(Command::Else) = self.program.peek_next()? {
+                    self.program.pc += 1;
+                }
+            }
+            // single line If, skip the next statement
+            (false, false) => self.program.pc += 1,
+        }
+        Ok(())
+    }
+
+    fn exec_while(&mut self, cond: &[Op]) -> Result<(), ExecError> {
+        let pc = self.program.pc;
+        self.program.push_block(Block::WhileBlock(pc))?;
+        if !self.eval(cond)?.to_bool()? {
+            self.program.scan_and_advance(false, false)?;
+        }
+        Ok(())
+    }
+
+    fn exec_for(&mut self, var: &Variable, start: &[Op], stop: &[Op]) -> Result<(), ExecError> {
+        let start = self.eval(start)?;
+        self.program.ctx.set(var, start)?;
+        let pc = self.program.pc;
+        self.program.push_block(Block::ForBlock(pc))?;
+        if !self.for_continues(var, stop)? {
+            self.program.scan_and_advance(false, false)?;
+        }
+        Ok(())
+    }
+
+    fn for_continues(&mut self, var: &Variable, stop: &[Op]) -> Result<bool, ExecError> {
+        let stop = self.eval(stop)?;
+        let val = self.program.ctx.get(var)?;
+        BinaryOp::less_equal(Box::new(val), Box::new(stop))
+            .eval(&mut self.program.ctx)?
+            .to_bool()
+    }
+
+    fn exec_end(&mut self) -> Result<(), ExecError> {
+        let top = self
+            .program
+            .blockstack
+            .last()
+            .ok_or(ExecError::UnexpectedEnd)?
+            .clone();
+        let start = match top {
+            Block::IfBlock(_, _) => {
+                self.program.blockstack.pop();
+                return Ok(());
+            }
+            Block::ForBlock(start) | Block::WhileBlock(start) | Block::RepeatBlock(start) => start,
+        };
+
+        let code = self.code;
+        let again = match (&top, &code[start]) {
+            (Block::ForBlock(_), Instr::For { var, stop, inc, .. }) => {
+                let val = self.program.ctx.get(var)?;
+                let inc = self.eval(inc)?;
+                let next =
+                    BinaryOp::add(Box::new(inc), Box::new(val)).eval(&mut self.program.ctx)?;
+                self.program.ctx.set(var, next)?;
+                self.for_continues(var, stop)?
+            }
+            (Block::WhileBlock(_), Instr::While(cond)) => self.eval(cond)?.to_bool()?,
+            (Block::RepeatBlock(_), Instr::Repeat(cond)) => self.eval(cond)?.to_bool()?,
+            _ => return Err(ExecError::FailedToFindForNode),
+        };
+
+        if again {
+            // back to the top of the loop, which doesn't get run again
+            self.program.pc = start;
+            Ok(())
+        } else {
+            // done, we are already on the End so just carry on
+            self.program.blockstack.pop();
+            Ok(())
+        }
+    }
+
+    fn exec_skip(
+        &mut self,
+        target: &Target,
+        value: &[Op],
+        decrement: bool,
+    ) -> Result<(), ExecError> {
+        // IS>( and DS<(, same order of evaluation as the tree-walker
+        if self.program.pc + 1 >= self.code.len() {
+            return Err(ExecError::SyntaxError);
+        }
+        let delta = if decrement { -1.0 } else { 1.0 };
+        let old = target.get(&mut self.program.ctx)?;
+        let new = BinaryOp::add(Box::new(old), Box::new(Value::NumValue(delta)))
+            .eval(&mut self.program.ctx)?;
+        target.set(&mut self.program.ctx, new.clone())?;
+
+        let value = self.eval(value)?;
+        let skip = if decrement {
+            BinaryOp::less(Box::new(new), Box::new(value))
+        } else {
+            BinaryOp::greater(Box::new(new), Box::new(value))
+        };
+        if skip.eval(&mut self.program.ctx)?.to_bool()? {
+            self.program.pc += 1;
+        }
+        Ok(())
+    }
+}
+
+pub fn run(program: &mut Program, code: &[Instr]) -> Result<(), ExecError> {
+    Vm {
+        program,
+        code,
+        stack: Vec::new(),
+    }
+    .run()
+}
+
+pub fn execute(program: &mut Program) -> Result<(), ExecError> {
+    // compile whatever has been parsed so far and run it from program.pc
+    let code = compile(program);
+    run(program, &code)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::lexer::*;
+
+    fn run_on(input: &str, options: &Options, on_vm: bool) -> String {
+        let mut program = Program::with_options(options);
+        parse(&lex_str(input), &mut program).unwrap();
+        let result = if on_vm {
+            execute(&mut program)
+        } else {
+            crate::executor::execute(&mut program)
+        };
+        // lists and matrices can't be compared directly, Debug is good enough
+        format!(
+            "{:?} {:?} {:?} {:?} {}",
+            result,
+            program.ctx.ans,
+            program.ctx.variables(),
+            program.blockstack,
+            program.result()
+        )
+    }
+
+    // the tree-walker is the reference, the vm has to end up in the same place
+    fn same_with(input: &str, options: &Options) {
+        assert_eq!(
+            run_on(input, options, true),
+            run_on(input, options, false),
+            "{}",
+            input
+        );
+    }
+
+    fn same(input: &str) {
+        same_with(input, &Options::new())
+    }
+
+    #[test]
+    fn test_expressions() {
+        same("2+3*4\n");
+        same("--2^2\n");
+        same("1->A\nA+1->A\nA*2->B\n");
+        same("{1,2,3}->L1\nL1*2+{1,1,1}\n");
+        same("sqrt(16)+abs(--2)\n");
+        same("sub(\"HELLO\",2,3)+\"!\"\n");
+        same("\"AB\"+\"C\"->Str1\n");
+        same("{1,2}->L1\n5->L1(3)\nL1(2)\n");
+        same("1->A\n2->B\nnot(A=B) and A<B\n");
+        same("sqrt(--1)\n");
+        same("1/0\n");
+        same("2\nDisp 3\n");
+        same("2\nOutput(1,1,3)\nAns\n");
+    }
+
+    #[test]
+    fn test_control_flow() {
+        same("0->S\nFor(I,1,10)\nS+I->S\nEnd\nS\n");
+        same("0->S\nFor(I,10,1,--2)\nS+I->S\nEnd\nS\n");
+        same("0->S\nFor(I,5,1)\nS+1->S\nEnd\nS\n");
+        same("0->A\nWhile A<5\nA+1->A\nEnd\nA\n");
+        same("0->A\nRepeat A>=5\nA+1->A\nEnd\nA\n");
+        same("1->A\nIf A\nThen\n2->B\nElse\n3->B\nEnd\nB\n");
+        same("0->A\nIf A\nThen\n2->B\nElse\n3->B\nEnd\nB\n");
+        same("0->A\nIf A\n5->A\nA+1\n");
+        same("5->A\nIS>(A,5)\n10->A\nA\n");
+        same("5->A\nDS<(A,5)\n10->A\nA\n");
+        same("{1,2}->L1\nIS>(L1(2),2)\n0->B\nL1\n");
+        same("End\n");
+        same("Then\n");
+        same("If 1\n");
+        same("IS>(A,5)\n");
+    }
+
+    #[test]
+    fn test_goto() {
+        same("0->A\nLbl A\nA+1->A\nIf A<5\nGoto A\nA\n");
+        same("Goto B\n");
+        // into the middle of a loop, the End belongs to whatever is open
+        same("0->S\nGoto A\nFor(I,1,3)\nLbl A\nS+1->S\nEnd\nS\n");
+        same("1->I\n0->S\nWhile I<5\nLbl A\nS+1->S\nI+1->I\nEnd\nIf S<20\nGoto A\nS\n");
+        // End turned off by a single line If
+        same("1->A\n0->B\nIf 1\nThen\nLbl A\nB+1->B\nIf A\nEnd\n0->A\nIf B<3\nGoto A\nB\n");
+        // leaking blocks runs out of memory in the same place
+        let leaky = "0->I\nLbl A\nI+1->I\nWhile 1\nIf I<50\nGoto A\nGoto B\nEnd\nLbl B\nI\n";
+        same(leaky);
+        let mut options = Options::new();
+        options.block_limit = Some(10);
+        same_with(leaky, &options);
+    }
+
+    #[test]
+    fn test_limits() {
+        let mut options = Options::new();
+        options.limits.steps = Some(25);
+        options.limits.size = Some(4);
+        same_with("0->A\nLbl A\nA+1->A\nGoto A\n", &options);
+        same_with("{1}->L1\nFor(I,2,10)\nI->L1(I)\nEnd\n", &options);
+        same_with("\"A\"\nWhile 1\nAns+\"A\"\nEnd\n", &options);
+    }
+
+    #[test]
+    fn test_break() {
+        let mut program = Program::with_options(&Options::new());
+        parse(&lex_str("5->A\nLbl A\nGoto A\n"), &mut program).unwrap();
+        let interrupt = program.ctx.interrupt.clone();
+        let presser = std::thread::spawn(move || {
+            std::thread::sleep(std::time::Duration::from_millis(20));
+            interrupt.store(true, std::sync::atomic::Ordering::Relaxed);
+        });
+        assert_eq!(execute(&mut program).unwrap_err(), ExecError::Break);
+        presser.join().unwrap();
+        assert_eq!(program.ctx.get(&Variable::RealVar('A')).unwrap(), 5.0);
+    }
+
+    #[test]
+    fn test_compile() {
+        let mut program = Program::with_options(&Options::new());
+        parse(&lex_str("1->A\nLbl B\nA+1\nGoto B\nGoto C\n"), &mut program).unwrap();
+        let code = compile(&program);
+        assert_eq!(code.len(), 5);
+        match &code[0] {
+            Instr::Expr(ops) => match ops.as_slice() {
+                [Op::Push(_), Op::Store(_)] => (),
+                ops => panic!("{:?}", ops),
+            },
+            instr => panic!("{:?}", instr),
+        }
+        match &code[2] {
+            Instr::Expr(ops) => match ops.as_slice() {
+                [Op::Load(_), Op::Push(_), Op::Binary(_)] => (),
+                ops => panic!("{:?}", ops),
+            },
+            instr => panic!("{:?}", instr),
+        }
+        match (&code[3], &code[4]) {
+            (Instr::Goto(_, Some(1)), Instr::Goto(_, None)) => (),
+            instrs => panic!("{:?}", instrs),
+        }
+    }
+}
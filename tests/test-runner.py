@@ -63,7 +63,13 @@ for test in tests:
     if len(info.args) != 0:
         args = info.args
     
-    results = subprocess.run([TIB_EXEC, test, args], stdout=subprocess.PIPE)
+    # what Input and Prompt read, if the test needs any
+    stdin = b''
+    if os.path.exists(test + '.in'):
+        with open(test + '.in', 'rb') as input_file:
+            stdin = input_file.read()
+
+    results = subprocess.run([TIB_EXEC, test, args], input=stdin, stdout=subprocess.PIPE)
 
     if results.returncode != info.retval:
         print(results.stdout)